@@ -0,0 +1,68 @@
+// Rustreexo
+
+use bitcoin_hashes::{sha256, Hash, HashEngine};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+
+use rustreexo::accumulator::{pollard::Pollard, types::Leaf};
+
+// Number of leaves in the forests that get benchmarked
+const NUM_LEAVES: u64 = 1 << 20;
+
+// Number of leaves that are added and deleted in a block
+const BLOCK_SIZE: u64 = 2000;
+
+fn hash_from_u64(num: u64) -> sha256::Hash {
+    let mut engine = sha256::Hash::engine();
+    engine.input(&num.to_le_bytes());
+    sha256::Hash::from_engine(engine)
+}
+
+fn leaves(range: std::ops::Range<u64>, remember: bool) -> Vec<Leaf> {
+    range.map(|num| Leaf { hash: hash_from_u64(num), remember }).collect()
+}
+
+fn full_pollard() -> Pollard {
+    let mut pol = Pollard::new();
+    pol.modify(leaves(0..NUM_LEAVES, true), vec![]).unwrap();
+    pol
+}
+
+fn bench_add(c: &mut Criterion) {
+    let mut group = c.benchmark_group("add 1M leaves");
+    group.sample_size(10);
+
+    for remember in [false, true].iter() {
+        let name = if *remember { "remembered" } else { "not remembered" };
+        group.bench_function(name, |b| b.iter_batched(
+            || leaves(0..NUM_LEAVES, *remember),
+            |adds| Pollard::new().modify(adds, vec![]).unwrap(),
+            BatchSize::LargeInput,
+        ));
+    }
+
+    group.finish();
+}
+
+fn bench_modify(c: &mut Criterion) {
+    let pol = full_pollard();
+
+    // spread the deletions out over the whole forest
+    let dels: Vec<u64> = (0..BLOCK_SIZE).map(|i| i * (NUM_LEAVES / BLOCK_SIZE)).collect();
+
+    let mut group = c.benchmark_group("modify 1M leaves");
+    group.sample_size(10);
+
+    group.bench_function("block", |b| b.iter_batched(
+        || (pol.clone(), leaves(NUM_LEAVES..NUM_LEAVES + BLOCK_SIZE, true)),
+        |(mut pol, adds)| pol.modify(adds, dels.clone()).unwrap(),
+        BatchSize::LargeInput,
+    ));
+
+    let hashes: Vec<_> = (0..BLOCK_SIZE).map(|i| hash_from_u64(i * (NUM_LEAVES / BLOCK_SIZE))).collect();
+    group.bench_function("prove", |b| b.iter(|| pol.prove(&hashes).unwrap()));
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_add, bench_modify);
+criterion_main!(benches);
@@ -0,0 +1,120 @@
+/* Rustreexo */
+
+/*
+ * C bindings for the rustreexo accumulator, implemented in ffi/src/lib.rs.
+ *
+ * The Pollard, the Stump and proofs are opaque handles that have to be freed
+ * with their own free function. Hashes are 32 bytes each, one after another.
+ * Calls that can fail return a rustreexo_error and don't change anything if
+ * it isn't RUSTREEXO_OK. The exception is a Pollard that panics while it's
+ * being modified: it may be half modified, so every call with it returns
+ * RUSTREEXO_POISONED after that and it can only be freed. Serialized data is
+ * CBOR.
+ */
+
+#ifndef RUSTREEXO_H
+#define RUSTREEXO_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* The Pollard, which keeps track of where its remembered leaves are. */
+typedef struct rustreexo_pollard rustreexo_pollard;
+
+/* The accumulator with only its roots. */
+typedef struct rustreexo_stump rustreexo_stump;
+
+/* A proof for some of the leaves of the accumulator. */
+typedef struct rustreexo_proof rustreexo_proof;
+
+typedef enum rustreexo_error {
+    RUSTREEXO_OK = 0,
+    RUSTREEXO_NULL_POINTER = 1,
+    RUSTREEXO_INVALID_PROOF = 2,
+    RUSTREEXO_COUNT_MISMATCH = 3,
+    RUSTREEXO_MISSING_HASH = 4,
+    RUSTREEXO_LEAF_NOT_REMEMBERED = 5,
+    RUSTREEXO_DECODE = 6,
+    RUSTREEXO_PANIC = 7,
+    RUSTREEXO_POISONED = 8,
+} rustreexo_error;
+
+/* Bytes made by the library. Freed with rustreexo_bytes_free. */
+typedef struct rustreexo_bytes {
+    uint8_t *data;
+    size_t len;
+} rustreexo_bytes;
+
+/* Returns a static description of the error. */
+const char *rustreexo_error_string(rustreexo_error err);
+
+/* Frees the bytes and sets them to empty. */
+void rustreexo_bytes_free(rustreexo_bytes *bytes);
+
+rustreexo_pollard *rustreexo_pollard_new(void);
+void rustreexo_pollard_free(rustreexo_pollard *pollard);
+
+/*
+ * Deletes the leaves with the hashes in dels and then adds the leaves with
+ * the hashes in adds. remember says for each add whether the Pollard keeps
+ * it so it can be proven and deleted later. The dels need to have been
+ * remembered. If this returns RUSTREEXO_PANIC the Pollard is poisoned.
+ */
+rustreexo_error rustreexo_pollard_modify(rustreexo_pollard *pollard,
+                                         const uint8_t *adds, const bool *remember, size_t num_adds,
+                                         const uint8_t *dels, size_t num_dels);
+
+/* Makes a proof for the remembered leaves with the given hashes. */
+rustreexo_error rustreexo_pollard_prove(const rustreexo_pollard *pollard,
+                                        const uint8_t *hashes, size_t num_hashes,
+                                        rustreexo_proof **proof);
+
+/* RUSTREEXO_OK if the proof proves the leaves, RUSTREEXO_INVALID_PROOF if not. */
+rustreexo_error rustreexo_pollard_verify(const rustreexo_pollard *pollard, const rustreexo_proof *proof,
+                                         const uint8_t *hashes, size_t num_hashes);
+
+/* Writes the hashes of the roots, biggest tree first. */
+rustreexo_error rustreexo_pollard_roots(const rustreexo_pollard *pollard, rustreexo_bytes *roots);
+uint64_t rustreexo_pollard_num_leaves(const rustreexo_pollard *pollard);
+
+rustreexo_error rustreexo_pollard_serialize(const rustreexo_pollard *pollard, rustreexo_bytes *out);
+rustreexo_error rustreexo_pollard_deserialize(const uint8_t *data, size_t len, rustreexo_pollard **pollard);
+
+rustreexo_stump *rustreexo_stump_new(void);
+void rustreexo_stump_free(rustreexo_stump *stump);
+
+/*
+ * Deletes the leaves with the hashes in dels, which the proof proves, and
+ * then adds the leaves with the hashes in adds. The proof can be NULL if
+ * there are no dels.
+ */
+rustreexo_error rustreexo_stump_modify(rustreexo_stump *stump,
+                                       const uint8_t *adds, size_t num_adds,
+                                       const uint8_t *dels, size_t num_dels,
+                                       const rustreexo_proof *proof);
+
+/* RUSTREEXO_OK if the proof proves the leaves, RUSTREEXO_INVALID_PROOF if not. */
+rustreexo_error rustreexo_stump_verify(const rustreexo_stump *stump, const rustreexo_proof *proof,
+                                       const uint8_t *hashes, size_t num_hashes);
+
+/* Writes the hashes of the roots, biggest tree first. */
+rustreexo_error rustreexo_stump_roots(const rustreexo_stump *stump, rustreexo_bytes *roots);
+uint64_t rustreexo_stump_num_leaves(const rustreexo_stump *stump);
+
+rustreexo_error rustreexo_stump_serialize(const rustreexo_stump *stump, rustreexo_bytes *out);
+rustreexo_error rustreexo_stump_deserialize(const uint8_t *data, size_t len, rustreexo_stump **stump);
+
+void rustreexo_proof_free(rustreexo_proof *proof);
+rustreexo_error rustreexo_proof_serialize(const rustreexo_proof *proof, rustreexo_bytes *out);
+rustreexo_error rustreexo_proof_deserialize(const uint8_t *data, size_t len, rustreexo_proof **proof);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* RUSTREEXO_H */
@@ -0,0 +1,600 @@
+// Rustreexo
+
+//! C bindings for the accumulator, declared in include/rustreexo.h.
+//!
+//! The Pollard, the Stump and proofs are handed out as opaque pointers that
+//! have to be freed with their own free function. Hashes go in and come out
+//! as 32 bytes each, one after another. Every call that can fail returns a
+//! rustreexo_error and a panic is turned into RUSTREEXO_PANIC instead of
+//! crossing into C. A Pollard that panics while it's being modified may be
+//! half modified, so every call with it fails with RUSTREEXO_POISONED after
+//! that. Serialized data is CBOR.
+
+#![allow(non_camel_case_types)]
+
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::{ptr, slice};
+
+use bitcoin_hashes::{sha256, Hash};
+
+use rustreexo::accumulator::{
+    error::Error,
+    pollard::Pollard,
+    proof::BatchProof,
+    stump::Stump,
+    types::Leaf,
+};
+
+/// The Pollard, which keeps track of where its remembered leaves are.
+pub struct rustreexo_pollard {
+    pollard: Pollard,
+
+    // Set while the Pollard is being modified, so it stays set if that
+    // panics
+    poisoned: bool,
+}
+
+/// The accumulator with only its roots.
+pub struct rustreexo_stump(Stump);
+
+/// A proof for some of the leaves of the accumulator.
+pub struct rustreexo_proof(BatchProof);
+
+/// What went wrong in a call. Everything but RUSTREEXO_OK means that the
+/// call didn't change anything, except for RUSTREEXO_PANIC when modifying a
+/// Pollard.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum rustreexo_error {
+    RUSTREEXO_OK = 0,
+    RUSTREEXO_NULL_POINTER = 1,
+    RUSTREEXO_INVALID_PROOF = 2,
+    RUSTREEXO_COUNT_MISMATCH = 3,
+    RUSTREEXO_MISSING_HASH = 4,
+    RUSTREEXO_LEAF_NOT_REMEMBERED = 5,
+    RUSTREEXO_DECODE = 6,
+    RUSTREEXO_PANIC = 7,
+    RUSTREEXO_POISONED = 8,
+}
+
+use rustreexo_error::*;
+
+impl From<Error> for rustreexo_error {
+    fn from(err: Error) -> rustreexo_error {
+        match err {
+            Error::TargetCountMismatch { .. } | Error::ProofCountMismatch { .. } => RUSTREEXO_COUNT_MISMATCH,
+            Error::InvalidProof | Error::InvalidTargets => RUSTREEXO_INVALID_PROOF,
+            Error::MissingHash(_) => RUSTREEXO_MISSING_HASH,
+            Error::LeafNotRemembered(_) => RUSTREEXO_LEAF_NOT_REMEMBERED,
+            Error::RootCountMismatch { .. } | Error::HashMismatch(_) | Error::BadNieces(_) => RUSTREEXO_DECODE,
+        }
+    }
+}
+
+/// Bytes made by the library. Freed with rustreexo_bytes_free.
+#[repr(C)]
+pub struct rustreexo_bytes {
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+/// rustreexo_error_string returns a description of the error. The string
+/// is static and must not be freed.
+#[no_mangle]
+pub extern "C" fn rustreexo_error_string(err: rustreexo_error) -> *const c_char {
+    let msg: &'static [u8] = match err {
+        RUSTREEXO_OK => b"ok\0",
+        RUSTREEXO_NULL_POINTER => b"a pointer that's needed is null\0",
+        RUSTREEXO_INVALID_PROOF => b"proof doesn't hash up to the roots\0",
+        RUSTREEXO_COUNT_MISMATCH => b"number of hashes doesn't match the proof\0",
+        RUSTREEXO_MISSING_HASH => b"a hash that's needed is not known\0",
+        RUSTREEXO_LEAF_NOT_REMEMBERED => b"leaf was not remembered\0",
+        RUSTREEXO_DECODE => b"data can't be decoded\0",
+        RUSTREEXO_PANIC => b"the library panicked\0",
+        RUSTREEXO_POISONED => b"pollard can't be used after it panicked\0",
+    };
+    msg.as_ptr() as *const c_char
+}
+
+/// rustreexo_bytes_free frees the bytes and sets them to empty.
+///
+/// # Safety
+///
+/// bytes has to be null or have been filled in by this library.
+#[no_mangle]
+pub unsafe extern "C" fn rustreexo_bytes_free(bytes: *mut rustreexo_bytes) {
+    if let Some(bytes) = bytes.as_mut() {
+        if !bytes.data.is_null() {
+            drop(Box::from_raw(ptr::slice_from_raw_parts_mut(bytes.data, bytes.len)));
+        }
+        bytes.data = ptr::null_mut();
+        bytes.len = 0;
+    }
+}
+
+/// rustreexo_pollard_new returns a new empty Pollard.
+#[no_mangle]
+pub extern "C" fn rustreexo_pollard_new() -> *mut rustreexo_pollard {
+    Box::into_raw(Box::new(rustreexo_pollard { pollard: Pollard::with_leaf_index(), poisoned: false }))
+}
+
+/// rustreexo_pollard_free frees the Pollard.
+///
+/// # Safety
+///
+/// pollard has to be null or made by this library and not freed already.
+#[no_mangle]
+pub unsafe extern "C" fn rustreexo_pollard_free(pollard: *mut rustreexo_pollard) {
+    if !pollard.is_null() {
+        drop(Box::from_raw(pollard));
+    }
+}
+
+/// rustreexo_pollard_modify deletes the leaves with the hashes in dels and
+/// then adds the leaves with the hashes in adds. remember says for each add
+/// whether the Pollard keeps it so it can be proven and deleted later. The
+/// dels need to have been remembered. If this returns RUSTREEXO_PANIC the
+/// Pollard is poisoned and can only be freed.
+///
+/// # Safety
+///
+/// adds has to point to num_adds hashes and remember to num_adds bools, dels
+/// has to point to num_dels hashes. They can be null if their count is 0.
+#[no_mangle]
+pub unsafe extern "C" fn rustreexo_pollard_modify(
+    pollard: *mut rustreexo_pollard,
+    adds: *const u8,
+    remember: *const bool,
+    num_adds: usize,
+    dels: *const u8,
+    num_dels: usize,
+) -> rustreexo_error {
+    call(|| {
+        let pollard = pollard_mut(pollard)?;
+        let remember = items(remember, num_adds)?;
+        let adds = read_hashes(adds, num_adds)?.into_iter().zip(remember)
+            .map(|(hash, remember)| Leaf { hash, remember: *remember })
+            .collect();
+        let dels = read_hashes(dels, num_dels)?;
+
+        pollard.poisoned = true;
+        #[cfg(test)]
+        tests::panic_if_asked();
+        let modified = pollard.pollard.modify_by_hash(adds, &dels);
+        pollard.poisoned = false;
+
+        modified?;
+        Ok(())
+    })
+}
+
+/// rustreexo_pollard_prove makes a proof for the leaves with the given
+/// hashes. They all need to have been remembered.
+///
+/// # Safety
+///
+/// hashes has to point to num_hashes hashes and proof to where the new proof
+/// is written.
+#[no_mangle]
+pub unsafe extern "C" fn rustreexo_pollard_prove(
+    pollard: *const rustreexo_pollard,
+    hashes: *const u8,
+    num_hashes: usize,
+    proof: *mut *mut rustreexo_proof,
+) -> rustreexo_error {
+    call(|| {
+        let pollard = pollard_ref(pollard)?;
+        let hashes = read_hashes(hashes, num_hashes)?;
+        let made = pollard.prove(&hashes)?;
+
+        write_handle(proof, rustreexo_proof(made))
+    })
+}
+
+/// rustreexo_pollard_verify returns RUSTREEXO_OK if the proof proves the
+/// leaves with the given hashes and RUSTREEXO_INVALID_PROOF if it doesn't.
+///
+/// # Safety
+///
+/// hashes has to point to num_hashes hashes.
+#[no_mangle]
+pub unsafe extern "C" fn rustreexo_pollard_verify(
+    pollard: *const rustreexo_pollard,
+    proof: *const rustreexo_proof,
+    hashes: *const u8,
+    num_hashes: usize,
+) -> rustreexo_error {
+    call(|| {
+        let pollard = pollard_ref(pollard)?;
+        let proof = handle_ref(proof)?;
+        let hashes = read_hashes(hashes, num_hashes)?;
+
+        if !proof.0.verify(&hashes, &pollard.roots(), pollard.num_leaves) {
+            return Err(RUSTREEXO_INVALID_PROOF);
+        }
+        Ok(())
+    })
+}
+
+/// rustreexo_pollard_roots writes the hashes of the roots, biggest tree
+/// first.
+///
+/// # Safety
+///
+/// roots has to point to bytes that can be written to.
+#[no_mangle]
+pub unsafe extern "C" fn rustreexo_pollard_roots(
+    pollard: *const rustreexo_pollard,
+    roots: *mut rustreexo_bytes,
+) -> rustreexo_error {
+    call(|| {
+        let pollard = pollard_ref(pollard)?;
+        write_bytes(roots, join_hashes(&pollard.roots()))
+    })
+}
+
+/// rustreexo_pollard_num_leaves returns the number of leaves the Pollard
+/// has had added minus the ones deleted. Returns 0 for null or a poisoned
+/// Pollard.
+///
+/// # Safety
+///
+/// pollard has to be null or made by this library.
+#[no_mangle]
+pub unsafe extern "C" fn rustreexo_pollard_num_leaves(pollard: *const rustreexo_pollard) -> u64 {
+    pollard_ref(pollard).map_or(0, |pollard| pollard.num_leaves)
+}
+
+/// rustreexo_pollard_serialize writes the Pollard as CBOR.
+///
+/// # Safety
+///
+/// out has to point to bytes that can be written to.
+#[no_mangle]
+pub unsafe extern "C" fn rustreexo_pollard_serialize(
+    pollard: *const rustreexo_pollard,
+    out: *mut rustreexo_bytes,
+) -> rustreexo_error {
+    call(|| {
+        let pollard = pollard_ref(pollard)?;
+        write_bytes(out, serde_cbor::to_vec(pollard).map_err(|_| RUSTREEXO_DECODE)?)
+    })
+}
+
+/// rustreexo_pollard_deserialize reads a Pollard that was written with
+/// rustreexo_pollard_serialize.
+///
+/// # Safety
+///
+/// data has to point to len bytes and pollard to where the Pollard is
+/// written.
+#[no_mangle]
+pub unsafe extern "C" fn rustreexo_pollard_deserialize(
+    data: *const u8,
+    len: usize,
+    pollard: *mut *mut rustreexo_pollard,
+) -> rustreexo_error {
+    call(|| {
+        let read = serde_cbor::from_slice(items(data, len)?).map_err(|_| RUSTREEXO_DECODE)?;
+        write_handle(pollard, rustreexo_pollard { pollard: read, poisoned: false })
+    })
+}
+
+/// rustreexo_stump_new returns a new empty Stump.
+#[no_mangle]
+pub extern "C" fn rustreexo_stump_new() -> *mut rustreexo_stump {
+    Box::into_raw(Box::new(rustreexo_stump(Stump::new())))
+}
+
+/// rustreexo_stump_free frees the Stump.
+///
+/// # Safety
+///
+/// stump has to be null or made by this library and not freed already.
+#[no_mangle]
+pub unsafe extern "C" fn rustreexo_stump_free(stump: *mut rustreexo_stump) {
+    if !stump.is_null() {
+        drop(Box::from_raw(stump));
+    }
+}
+
+/// rustreexo_stump_modify deletes the leaves with the hashes in dels, which
+/// the proof proves, and then adds the leaves with the hashes in adds.
+///
+/// # Safety
+///
+/// adds has to point to num_adds hashes and dels to num_dels hashes. They
+/// can be null if their count is 0, and so can the proof if there are no
+/// dels.
+#[no_mangle]
+pub unsafe extern "C" fn rustreexo_stump_modify(
+    stump: *mut rustreexo_stump,
+    adds: *const u8,
+    num_adds: usize,
+    dels: *const u8,
+    num_dels: usize,
+    proof: *const rustreexo_proof,
+) -> rustreexo_error {
+    call(|| {
+        let stump = handle(stump)?;
+        let empty = rustreexo_proof(BatchProof::default());
+        let proof = match (proof.is_null(), num_dels) {
+            (true, 0) => &empty,
+            _ => handle_ref(proof)?,
+        };
+        let adds = read_hashes(adds, num_adds)?;
+        let dels = read_hashes(dels, num_dels)?;
+
+        stump.0.modify(&adds, &dels, &proof.0)?;
+        Ok(())
+    })
+}
+
+/// rustreexo_stump_verify returns RUSTREEXO_OK if the proof proves the
+/// leaves with the given hashes and RUSTREEXO_INVALID_PROOF if it doesn't.
+///
+/// # Safety
+///
+/// hashes has to point to num_hashes hashes.
+#[no_mangle]
+pub unsafe extern "C" fn rustreexo_stump_verify(
+    stump: *const rustreexo_stump,
+    proof: *const rustreexo_proof,
+    hashes: *const u8,
+    num_hashes: usize,
+) -> rustreexo_error {
+    call(|| {
+        let stump = handle_ref(stump)?;
+        let proof = handle_ref(proof)?;
+        let hashes = read_hashes(hashes, num_hashes)?;
+
+        if !stump.0.verify(&proof.0, &hashes) {
+            return Err(RUSTREEXO_INVALID_PROOF);
+        }
+        Ok(())
+    })
+}
+
+/// rustreexo_stump_roots writes the hashes of the roots, biggest tree first.
+///
+/// # Safety
+///
+/// roots has to point to bytes that can be written to.
+#[no_mangle]
+pub unsafe extern "C" fn rustreexo_stump_roots(
+    stump: *const rustreexo_stump,
+    roots: *mut rustreexo_bytes,
+) -> rustreexo_error {
+    call(|| {
+        let stump = handle_ref(stump)?;
+        write_bytes(roots, join_hashes(&stump.0.roots))
+    })
+}
+
+/// rustreexo_stump_num_leaves returns the number of leaves in the
+/// accumulator. Returns 0 for null.
+///
+/// # Safety
+///
+/// stump has to be null or made by this library.
+#[no_mangle]
+pub unsafe extern "C" fn rustreexo_stump_num_leaves(stump: *const rustreexo_stump) -> u64 {
+    stump.as_ref().map_or(0, |stump| stump.0.num_leaves)
+}
+
+/// rustreexo_stump_serialize writes the Stump as CBOR.
+///
+/// # Safety
+///
+/// out has to point to bytes that can be written to.
+#[no_mangle]
+pub unsafe extern "C" fn rustreexo_stump_serialize(
+    stump: *const rustreexo_stump,
+    out: *mut rustreexo_bytes,
+) -> rustreexo_error {
+    call(|| {
+        let stump = handle_ref(stump)?;
+        write_bytes(out, serde_cbor::to_vec(&stump.0).map_err(|_| RUSTREEXO_DECODE)?)
+    })
+}
+
+/// rustreexo_stump_deserialize reads a Stump that was written with
+/// rustreexo_stump_serialize.
+///
+/// # Safety
+///
+/// data has to point to len bytes and stump to where the Stump is written.
+#[no_mangle]
+pub unsafe extern "C" fn rustreexo_stump_deserialize(
+    data: *const u8,
+    len: usize,
+    stump: *mut *mut rustreexo_stump,
+) -> rustreexo_error {
+    call(|| {
+        let read = serde_cbor::from_slice(items(data, len)?).map_err(|_| RUSTREEXO_DECODE)?;
+        write_handle(stump, rustreexo_stump(read))
+    })
+}
+
+/// rustreexo_proof_free frees the proof.
+///
+/// # Safety
+///
+/// proof has to be null or made by this library and not freed already.
+#[no_mangle]
+pub unsafe extern "C" fn rustreexo_proof_free(proof: *mut rustreexo_proof) {
+    if !proof.is_null() {
+        drop(Box::from_raw(proof));
+    }
+}
+
+/// rustreexo_proof_serialize writes the proof as CBOR.
+///
+/// # Safety
+///
+/// out has to point to bytes that can be written to.
+#[no_mangle]
+pub unsafe extern "C" fn rustreexo_proof_serialize(
+    proof: *const rustreexo_proof,
+    out: *mut rustreexo_bytes,
+) -> rustreexo_error {
+    call(|| {
+        let proof = handle_ref(proof)?;
+        write_bytes(out, serde_cbor::to_vec(&proof.0).map_err(|_| RUSTREEXO_DECODE)?)
+    })
+}
+
+/// rustreexo_proof_deserialize reads a proof that was written with
+/// rustreexo_proof_serialize.
+///
+/// # Safety
+///
+/// data has to point to len bytes and proof to where the proof is written.
+#[no_mangle]
+pub unsafe extern "C" fn rustreexo_proof_deserialize(
+    data: *const u8,
+    len: usize,
+    proof: *mut *mut rustreexo_proof,
+) -> rustreexo_error {
+    call(|| {
+        let read = serde_cbor::from_slice(items(data, len)?).map_err(|_| RUSTREEXO_DECODE)?;
+        write_handle(proof, rustreexo_proof(read))
+    })
+}
+
+// call runs the body of a call, turning a panic into an error so that it
+// doesn't unwind into C.
+fn call(body: impl FnOnce() -> Result<(), rustreexo_error>) -> rustreexo_error {
+    match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(Ok(())) => RUSTREEXO_OK,
+        Ok(Err(err)) => err,
+        Err(_) => RUSTREEXO_PANIC,
+    }
+}
+
+unsafe fn pollard_mut<'a>(ptr: *mut rustreexo_pollard) -> Result<&'a mut rustreexo_pollard, rustreexo_error> {
+    let pollard = handle(ptr)?;
+    if pollard.poisoned {
+        return Err(RUSTREEXO_POISONED);
+    }
+    Ok(pollard)
+}
+
+unsafe fn pollard_ref<'a>(ptr: *const rustreexo_pollard) -> Result<&'a Pollard, rustreexo_error> {
+    let pollard = handle_ref(ptr)?;
+    if pollard.poisoned {
+        return Err(RUSTREEXO_POISONED);
+    }
+    Ok(&pollard.pollard)
+}
+
+unsafe fn handle<'a, T>(ptr: *mut T) -> Result<&'a mut T, rustreexo_error> {
+    ptr.as_mut().ok_or(RUSTREEXO_NULL_POINTER)
+}
+
+unsafe fn handle_ref<'a, T>(ptr: *const T) -> Result<&'a T, rustreexo_error> {
+    ptr.as_ref().ok_or(RUSTREEXO_NULL_POINTER)
+}
+
+// items returns the slice of len items at ptr. ptr can only be null if
+// there's nothing in it.
+unsafe fn items<'a, T>(ptr: *const T, len: usize) -> Result<&'a [T], rustreexo_error> {
+    match (ptr.is_null(), len) {
+        (_, 0) => Ok(&[]),
+        (true, _) => Err(RUSTREEXO_NULL_POINTER),
+        (false, _) => Ok(slice::from_raw_parts(ptr, len)),
+    }
+}
+
+unsafe fn read_hashes(ptr: *const u8, count: usize) -> Result<Vec<sha256::Hash>, rustreexo_error> {
+    let bytes = items(ptr, count.checked_mul(32).ok_or(RUSTREEXO_COUNT_MISMATCH)?)?;
+    Ok(bytes.chunks_exact(32).map(|hash| sha256::Hash::from_slice(hash).unwrap()).collect())
+}
+
+fn join_hashes(hashes: &[sha256::Hash]) -> Vec<u8> {
+    hashes.iter().flat_map(|hash| hash.into_inner()).collect()
+}
+
+unsafe fn write_bytes(out: *mut rustreexo_bytes, bytes: Vec<u8>) -> Result<(), rustreexo_error> {
+    let out = out.as_mut().ok_or(RUSTREEXO_NULL_POINTER)?;
+    out.len = bytes.len();
+    out.data = Box::into_raw(bytes.into_boxed_slice()) as *mut u8;
+    Ok(())
+}
+
+unsafe fn write_handle<T>(out: *mut *mut T, value: T) -> Result<(), rustreexo_error> {
+    let out = out.as_mut().ok_or(RUSTREEXO_NULL_POINTER)?;
+    *out = Box::into_raw(Box::new(value));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::ptr;
+
+    use super::*;
+
+    thread_local! {
+        // Whether the next modify panics after the Pollard is marked
+        static PANIC_IN_MODIFY: Cell<bool> = const { Cell::new(false) };
+    }
+
+    // panic_if_asked panics in the middle of modify if the test asked for it.
+    pub(super) fn panic_if_asked() {
+        if PANIC_IN_MODIFY.with(|panic| panic.replace(false)) {
+            panic!("modify was asked to panic");
+        }
+    }
+
+    #[test]
+    fn test_poisoned() {
+        let hash = [1u8; 32];
+        let remember = true;
+
+        unsafe {
+            let pollard = rustreexo_pollard_new();
+            assert_eq!(rustreexo_pollard_modify(pollard, hash.as_ptr(), &remember, 1, ptr::null(), 0), RUSTREEXO_OK);
+
+            // What a panic in the middle of modify leaves behind
+            (*pollard).poisoned = true;
+
+            let mut proof = ptr::null_mut();
+            let mut roots = rustreexo_bytes { data: ptr::null_mut(), len: 0 };
+            assert_eq!(rustreexo_pollard_modify(pollard, ptr::null(), ptr::null(), 0, hash.as_ptr(), 1), RUSTREEXO_POISONED);
+            assert_eq!(rustreexo_pollard_prove(pollard, hash.as_ptr(), 1, &mut proof), RUSTREEXO_POISONED);
+            assert_eq!(rustreexo_pollard_roots(pollard, &mut roots), RUSTREEXO_POISONED);
+            assert_eq!(rustreexo_pollard_num_leaves(pollard), 0);
+            assert!(proof.is_null() && roots.data.is_null());
+
+            rustreexo_pollard_free(pollard);
+        }
+    }
+
+    #[test]
+    fn test_panic() {
+        let hashes = [[1u8; 32], [2u8; 32]];
+        let remember = true;
+
+        unsafe {
+            let pollard = rustreexo_pollard_new();
+            assert_eq!(rustreexo_pollard_modify(pollard, hashes[0].as_ptr(), &remember, 1, ptr::null(), 0), RUSTREEXO_OK);
+
+            // The panic doesn't get out of the call and the Pollard is left
+            // poisoned
+            PANIC_IN_MODIFY.with(|panic| panic.set(true));
+            assert_eq!(rustreexo_pollard_modify(pollard, hashes[1].as_ptr(), &remember, 1, ptr::null(), 0), RUSTREEXO_PANIC);
+
+            let mut proof = ptr::null_mut();
+            let mut roots = rustreexo_bytes { data: ptr::null_mut(), len: 0 };
+            assert_eq!(rustreexo_pollard_modify(pollard, ptr::null(), ptr::null(), 0, hashes[0].as_ptr(), 1), RUSTREEXO_POISONED);
+            assert_eq!(rustreexo_pollard_prove(pollard, hashes[0].as_ptr(), 1, &mut proof), RUSTREEXO_POISONED);
+            assert_eq!(rustreexo_pollard_roots(pollard, &mut roots), RUSTREEXO_POISONED);
+            assert_eq!(rustreexo_pollard_num_leaves(pollard), 0);
+            assert!(proof.is_null() && roots.data.is_null());
+
+            rustreexo_pollard_free(pollard);
+        }
+    }
+}
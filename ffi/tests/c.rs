@@ -0,0 +1,147 @@
+// Rustreexo
+
+//! Builds tests/c/test.c against the library with the system C compiler and
+//! runs it. Also checks that the hand-written header declares the functions
+//! the library exports with the same signatures, and the same error codes.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+// The directory the library is built into, which is the one above the
+// deps directory the test binary is in.
+fn target_dir() -> PathBuf {
+    let exe = env::current_exe().unwrap();
+    exe.parent().and_then(|deps| deps.parent()).unwrap().to_path_buf()
+}
+
+#[test]
+fn test_c() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let lib_dir = target_dir();
+    let exe = lib_dir.join("rustreexo_ffi_c_test");
+
+    let compiler = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+    let status = Command::new(compiler)
+        .arg("-std=c99")
+        .arg("-Wall")
+        .arg("-Werror")
+        .arg("-I").arg(root.join("include"))
+        .arg(root.join("tests/c/test.c"))
+        .arg("-o").arg(&exe)
+        .arg("-L").arg(&lib_dir)
+        .arg("-lrustreexo_ffi")
+        .status()
+        .unwrap();
+    assert!(status.success(), "test.c didn't build");
+
+    let output = Command::new(&exe).env("LD_LIBRARY_PATH", &lib_dir).output().unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+}
+
+// c_type returns the C type the header should use for the Rust type of an
+// argument or a return value.
+fn c_type(rust: &str) -> String {
+    let rust = rust.trim();
+    if let Some(inner) = rust.strip_prefix("*mut ") {
+        return format!("{}*", c_type(inner));
+    }
+    if let Some(inner) = rust.strip_prefix("*const ") {
+        return format!("const {}*", c_type(inner));
+    }
+
+    match rust {
+        "" => "void",
+        "u8" => "uint8_t",
+        "u64" => "uint64_t",
+        "usize" => "size_t",
+        "c_char" => "char",
+        other => other,
+    }.to_string()
+}
+
+// normalize takes out the spaces that don't matter in a C declaration so two
+// of them can be compared.
+fn normalize(decl: &str) -> String {
+    let decl = decl.split_whitespace().collect::<Vec<_>>().join(" ");
+    let mut out = String::new();
+    for c in decl.chars() {
+        if "*(),".contains(c) && out.ends_with(' ') {
+            out.pop();
+        }
+        if c == ' ' && out.ends_with(|last| "*(,".contains(last)) {
+            continue;
+        }
+        out.push(c);
+    }
+    out
+}
+
+// strip_comments returns the C source without its comments.
+fn strip_comments(source: &str) -> String {
+    let mut out = String::new();
+    let mut rest = source;
+    while let Some(start) = rest.find("/*") {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        rest = &rest[rest.find("*/").map_or(rest.len(), |end| end + 2)..];
+    }
+    out.push_str(rest);
+    out
+}
+
+// rust_prototypes returns the C prototypes of the functions the library
+// exports, made from their Rust signatures.
+fn rust_prototypes(source: &str) -> Vec<String> {
+    source.split("extern \"C\" fn ").skip(1)
+        .map(|rest| {
+            let signature = &rest[..rest.find('{').unwrap()];
+            let name = &signature[..signature.find('(').unwrap()];
+            let params = &signature[signature.find('(').unwrap() + 1..signature.rfind(')').unwrap()];
+            let ret = signature.split("->").nth(1).unwrap_or("");
+
+            let params: Vec<String> = params.split(',')
+                .filter(|param| !param.trim().is_empty())
+                .map(|param| {
+                    let (name, ty) = param.split_at(param.find(':').unwrap());
+                    format!("{} {}", c_type(&ty[1..]), name.trim())
+                })
+                .collect();
+            let params = if params.is_empty() { "void".to_string() } else { params.join(", ") };
+
+            normalize(&format!("{} {}({});", c_type(ret), name, params))
+        })
+        .collect()
+}
+
+#[test]
+fn test_header() {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let header = strip_comments(&fs::read_to_string(root.join("include/rustreexo.h")).unwrap());
+    let source = fs::read_to_string(root.join("src/lib.rs")).unwrap();
+
+    let expected = rust_prototypes(&source);
+    assert!(expected.len() > 20);
+
+    // Every declaration of a function in the header, and only those
+    let declared: Vec<String> = header.split(';')
+        .map(|decl| normalize(&format!("{};", decl.trim())))
+        .filter(|decl| decl.contains("rustreexo_") && decl.contains('(') && !decl.starts_with("typedef"))
+        .map(|decl| decl.rsplit(['}', '{']).next().unwrap().trim().to_string())
+        .collect();
+
+    for prototype in &expected {
+        assert!(declared.contains(prototype), "{} isn't in the header", prototype);
+    }
+    for decl in &declared {
+        assert!(expected.contains(decl), "{} isn't exported by the library", decl);
+    }
+
+    // The error codes have the same values
+    let variants = &source[source.find("pub enum rustreexo_error {").unwrap()..];
+    let variants = &variants[variants.find('{').unwrap() + 1..variants.find('}').unwrap()];
+    let codes = &header[header.find("typedef enum rustreexo_error {").unwrap()..];
+    let codes = &codes[codes.find('{').unwrap() + 1..codes.find('}').unwrap()];
+    assert_eq!(normalize(variants), normalize(codes));
+}
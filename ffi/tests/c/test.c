@@ -0,0 +1,143 @@
+/* Rustreexo */
+
+/*
+ * Drives the bindings from C. Run by tests/c.rs, which builds it against the
+ * library. Exits with 0 if everything worked.
+ */
+
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+#include "rustreexo.h"
+
+#define NUM_ADDS 8
+
+#define CHECK(cond)                                                    \
+    do {                                                               \
+        if (!(cond)) {                                                 \
+            fprintf(stderr, "%s:%d: %s\n", __FILE__, __LINE__, #cond); \
+            exit(1);                                                   \
+        }                                                              \
+    } while (0)
+
+#define CHECK_OK(call)                                                            \
+    do {                                                                          \
+        rustreexo_error err_ = (call);                                            \
+        if (err_ != RUSTREEXO_OK) {                                               \
+            fprintf(stderr, "%s:%d: %s: %s\n", __FILE__, __LINE__, #call,         \
+                    rustreexo_error_string(err_));                                \
+            exit(1);                                                              \
+        }                                                                         \
+    } while (0)
+
+/* Checks that the Pollard and the Stump have the same roots */
+static void check_same_roots(const rustreexo_pollard *pollard, const rustreexo_stump *stump)
+{
+    rustreexo_bytes pollard_roots, stump_roots;
+    CHECK_OK(rustreexo_pollard_roots(pollard, &pollard_roots));
+    CHECK_OK(rustreexo_stump_roots(stump, &stump_roots));
+
+    CHECK(pollard_roots.len > 0 && pollard_roots.len % 32 == 0);
+    CHECK(pollard_roots.len == stump_roots.len);
+    CHECK(memcmp(pollard_roots.data, stump_roots.data, pollard_roots.len) == 0);
+    CHECK(rustreexo_pollard_num_leaves(pollard) == rustreexo_stump_num_leaves(stump));
+
+    rustreexo_bytes_free(&pollard_roots);
+    rustreexo_bytes_free(&stump_roots);
+    CHECK(pollard_roots.data == NULL && pollard_roots.len == 0);
+}
+
+int main(void)
+{
+    uint8_t adds[NUM_ADDS * 32];
+    bool remember[NUM_ADDS];
+    for (int i = 0; i < NUM_ADDS; i++) {
+        memset(adds + i * 32, i + 1, 32);
+        remember[i] = i % 2 == 1;
+    }
+
+    rustreexo_pollard *pollard = rustreexo_pollard_new();
+    rustreexo_stump *stump = rustreexo_stump_new();
+    CHECK(pollard != NULL && stump != NULL);
+
+    CHECK_OK(rustreexo_pollard_modify(pollard, adds, remember, NUM_ADDS, NULL, 0));
+    CHECK_OK(rustreexo_stump_modify(stump, adds, NUM_ADDS, NULL, 0, NULL));
+    CHECK(rustreexo_pollard_num_leaves(pollard) == NUM_ADDS);
+    check_same_roots(pollard, stump);
+
+    /* Prove two of the remembered leaves */
+    uint8_t dels[2 * 32];
+    memcpy(dels, adds + 1 * 32, 32);
+    memcpy(dels + 32, adds + 5 * 32, 32);
+
+    rustreexo_proof *proof = NULL;
+    CHECK_OK(rustreexo_pollard_prove(pollard, dels, 2, &proof));
+    CHECK_OK(rustreexo_pollard_verify(pollard, proof, dels, 2));
+    CHECK_OK(rustreexo_stump_verify(stump, proof, dels, 2));
+
+    /* Leaves that weren't remembered can't be proven */
+    rustreexo_proof *unused = NULL;
+    CHECK(rustreexo_pollard_prove(pollard, adds, 1, &unused) == RUSTREEXO_LEAF_NOT_REMEMBERED);
+    CHECK(unused == NULL);
+
+    /* The proof goes through CBOR and back */
+    rustreexo_bytes encoded;
+    CHECK_OK(rustreexo_proof_serialize(proof, &encoded));
+    rustreexo_proof *decoded = NULL;
+    CHECK_OK(rustreexo_proof_deserialize(encoded.data, encoded.len, &decoded));
+    CHECK(rustreexo_proof_deserialize(encoded.data, 1, &unused) == RUSTREEXO_DECODE);
+    rustreexo_bytes_free(&encoded);
+
+    /* The proof is for the dels and nothing else */
+    CHECK(rustreexo_stump_verify(stump, decoded, adds, 2) == RUSTREEXO_INVALID_PROOF);
+    CHECK(rustreexo_stump_modify(stump, NULL, 0, adds, 2, decoded) == RUSTREEXO_INVALID_PROOF);
+    check_same_roots(pollard, stump);
+
+    /* Delete them from both and add one more */
+    uint8_t more[32];
+    memset(more, 0xff, 32);
+    bool keep = true;
+    CHECK_OK(rustreexo_stump_modify(stump, more, 1, dels, 2, decoded));
+    CHECK_OK(rustreexo_pollard_modify(pollard, more, &keep, 1, dels, 2));
+    CHECK(rustreexo_stump_num_leaves(stump) == NUM_ADDS - 1);
+    check_same_roots(pollard, stump);
+
+    /* Both of them go through CBOR and back */
+    CHECK_OK(rustreexo_pollard_serialize(pollard, &encoded));
+    rustreexo_pollard *pollard_copy = NULL;
+    CHECK_OK(rustreexo_pollard_deserialize(encoded.data, encoded.len, &pollard_copy));
+    rustreexo_bytes_free(&encoded);
+
+    CHECK_OK(rustreexo_stump_serialize(stump, &encoded));
+    rustreexo_stump *stump_copy = NULL;
+    CHECK_OK(rustreexo_stump_deserialize(encoded.data, encoded.len, &stump_copy));
+    rustreexo_bytes_free(&encoded);
+
+    check_same_roots(pollard_copy, stump_copy);
+
+    /* The copy still knows where the remembered leaves are */
+    rustreexo_proof *more_proof = NULL;
+    CHECK_OK(rustreexo_pollard_prove(pollard_copy, more, 1, &more_proof));
+    CHECK_OK(rustreexo_stump_verify(stump_copy, more_proof, more, 1));
+
+    /* Null handles are reported instead of crashing */
+    CHECK(rustreexo_pollard_modify(NULL, adds, remember, 1, NULL, 0) == RUSTREEXO_NULL_POINTER);
+    CHECK(rustreexo_stump_roots(stump, NULL) == RUSTREEXO_NULL_POINTER);
+    CHECK(rustreexo_pollard_prove(pollard, NULL, 1, &unused) == RUSTREEXO_NULL_POINTER);
+    CHECK(strlen(rustreexo_error_string(RUSTREEXO_NULL_POINTER)) > 0);
+
+    rustreexo_proof_free(more_proof);
+    rustreexo_proof_free(decoded);
+    rustreexo_proof_free(proof);
+    rustreexo_stump_free(stump_copy);
+    rustreexo_pollard_free(pollard_copy);
+    rustreexo_stump_free(stump);
+    rustreexo_pollard_free(pollard);
+
+    /* Freeing null does nothing */
+    rustreexo_pollard_free(NULL);
+    rustreexo_bytes_free(NULL);
+
+    return 0;
+}
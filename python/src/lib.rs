@@ -0,0 +1,272 @@
+// Rustreexo
+
+//! Python bindings for the accumulator, imported as the rustreexo module.
+//!
+//! Hashes go in and come out as 32 bytes. Errors from the accumulator are
+//! raised as RustreexoError with the message of the error. The util helpers
+//! raise ValueError for rows, positions and numbers of leaves that don't fit
+//! a forest.
+
+use bitcoin_hashes::{sha256, Hash};
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+use rustreexo::accumulator::{error::Error, pollard::Pollard, proof::BatchProof, types, util};
+
+create_exception!(rustreexo, RustreexoError, PyException);
+
+fn to_py_err(err: Error) -> PyErr {
+    RustreexoError::new_err(err.to_string())
+}
+
+fn read_hashes(hashes: Vec<[u8; 32]>) -> Vec<sha256::Hash> {
+    hashes.into_iter().map(sha256::Hash::from_inner).collect()
+}
+
+fn hash_bytes(py: Python<'_>, hash: &sha256::Hash) -> PyObject {
+    PyBytes::new_bound(py, &hash[..]).into()
+}
+
+/// A leaf to add to the Pollard. remember is whether the Pollard keeps it so
+/// it can be proven and deleted later.
+#[pyclass(name = "Leaf")]
+#[derive(Clone)]
+struct PyLeaf {
+    hash: sha256::Hash,
+
+    #[pyo3(get)]
+    remember: bool,
+}
+
+#[pymethods]
+impl PyLeaf {
+    #[new]
+    #[pyo3(signature = (hash, remember = false))]
+    fn new(hash: [u8; 32], remember: bool) -> PyLeaf {
+        PyLeaf { hash: sha256::Hash::from_inner(hash), remember }
+    }
+
+    #[getter]
+    fn hash(&self, py: Python<'_>) -> PyObject {
+        hash_bytes(py, &self.hash)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Leaf({}, remember={})", self.hash, if self.remember { "True" } else { "False" })
+    }
+}
+
+/// A proof for some of the leaves of the accumulator.
+#[pyclass(name = "BatchProof")]
+struct PyBatchProof(BatchProof);
+
+#[pymethods]
+impl PyBatchProof {
+    #[new]
+    fn new(targets: Vec<u64>, proof: Vec<[u8; 32]>) -> PyBatchProof {
+        PyBatchProof(BatchProof { targets, proof: read_hashes(proof) })
+    }
+
+    #[getter]
+    fn targets(&self) -> Vec<u64> {
+        self.0.targets.clone()
+    }
+
+    #[getter]
+    fn proof(&self, py: Python<'_>) -> Vec<PyObject> {
+        self.0.proof.iter().map(|hash| hash_bytes(py, hash)).collect()
+    }
+
+    /// verify returns whether the proof proves the leaves with the given
+    /// hashes for an accumulator with the given roots.
+    fn verify(&self, hashes: Vec<[u8; 32]>, roots: Vec<[u8; 32]>, num_leaves: u64) -> bool {
+        self.0.verify(&read_hashes(hashes), &read_hashes(roots), num_leaves)
+    }
+
+    fn __eq__(&self, other: &PyBatchProof) -> bool {
+        self.0 == other.0
+    }
+
+    fn __repr__(&self) -> String {
+        format!("BatchProof(targets={:?}, proof=<{} hashes>)", self.0.targets, self.0.proof.len())
+    }
+}
+
+/// The Pollard, which keeps track of where its remembered leaves are.
+#[pyclass(name = "Pollard")]
+struct PyPollard(Pollard);
+
+#[pymethods]
+impl PyPollard {
+    #[new]
+    fn new() -> PyPollard {
+        PyPollard(Pollard::with_leaf_index())
+    }
+
+    /// modify deletes the leaves with the hashes in dels and then adds the
+    /// adds. The dels need to have been remembered.
+    fn modify(&mut self, adds: Vec<PyLeaf>, dels: Vec<[u8; 32]>) -> PyResult<()> {
+        let adds = adds.into_iter().map(|leaf| types::Leaf { hash: leaf.hash, remember: leaf.remember }).collect();
+        self.0.modify_by_hash(adds, &read_hashes(dels)).map_err(to_py_err)
+    }
+
+    /// prove makes a proof for the remembered leaves with the given hashes.
+    fn prove(&self, hashes: Vec<[u8; 32]>) -> PyResult<PyBatchProof> {
+        self.0.prove(&read_hashes(hashes)).map(PyBatchProof).map_err(to_py_err)
+    }
+
+    /// verify returns whether the proof proves the leaves with the given
+    /// hashes.
+    fn verify(&self, proof: &PyBatchProof, hashes: Vec<[u8; 32]>) -> bool {
+        proof.0.verify(&read_hashes(hashes), &self.0.roots(), self.0.num_leaves)
+    }
+
+    /// roots returns the hashes of the roots, biggest tree first.
+    fn roots(&self, py: Python<'_>) -> Vec<PyObject> {
+        self.0.roots().iter().map(|hash| hash_bytes(py, hash)).collect()
+    }
+
+    #[getter]
+    fn num_leaves(&self) -> u64 {
+        self.0.num_leaves
+    }
+
+    /// to_dot returns the Pollard as a Graphviz digraph.
+    fn to_dot(&self) -> String {
+        self.0.to_dot()
+    }
+
+    fn __str__(&self) -> String {
+        self.0.to_string()
+    }
+}
+
+// The util functions don't check what they're given, so the arguments are
+// checked here. The biggest forest has 63 rows and 2^63 leaves.
+const MAX_FOREST_ROWS: u8 = 63;
+
+fn check_rows(forest_rows: u8) -> PyResult<()> {
+    if forest_rows > MAX_FOREST_ROWS {
+        return Err(PyValueError::new_err(format!("forest_rows {} is more than {}", forest_rows, MAX_FOREST_ROWS)));
+    }
+    Ok(())
+}
+
+fn check_row(row: u8, forest_rows: u8) -> PyResult<()> {
+    check_rows(forest_rows)?;
+    if row > forest_rows {
+        return Err(PyValueError::new_err(format!("row {} is more than forest_rows {}", row, forest_rows)));
+    }
+    Ok(())
+}
+
+fn check_pos(pos: u64, forest_rows: u8) -> PyResult<()> {
+    check_rows(forest_rows)?;
+    if pos > util::row_offset(forest_rows, forest_rows) {
+        return Err(PyValueError::new_err(format!("position {} isn't in a forest with {} rows", pos, forest_rows)));
+    }
+    Ok(())
+}
+
+fn check_leaves(num_leaves: u64, forest_rows: u8) -> PyResult<()> {
+    check_rows(forest_rows)?;
+    if num_leaves > 1 << forest_rows {
+        return Err(PyValueError::new_err(format!("{} leaves don't fit in a forest with {} rows", num_leaves, forest_rows)));
+    }
+    Ok(())
+}
+
+#[pyfunction]
+fn tree_rows(num_leaves: u64) -> PyResult<u8> {
+    check_leaves(num_leaves, MAX_FOREST_ROWS)?;
+    Ok(util::tree_rows(num_leaves))
+}
+
+#[pyfunction]
+fn detect_row(pos: u64, forest_rows: u8) -> PyResult<u8> {
+    check_pos(pos, forest_rows)?;
+    Ok(util::detect_row(pos, forest_rows))
+}
+
+#[pyfunction]
+fn row_offset(row: u8, forest_rows: u8) -> PyResult<u64> {
+    check_row(row, forest_rows)?;
+    Ok(util::row_offset(row, forest_rows))
+}
+
+#[pyfunction]
+fn parent(pos: u64, forest_rows: u8) -> PyResult<u64> {
+    check_pos(pos, forest_rows)?;
+    Ok(util::parent(pos, forest_rows))
+}
+
+#[pyfunction]
+fn child(pos: u64, forest_rows: u8) -> PyResult<u64> {
+    check_pos(pos, forest_rows)?;
+    Ok(util::child(pos, forest_rows))
+}
+
+#[pyfunction]
+fn in_forest(pos: u64, num_leaves: u64, forest_rows: u8) -> PyResult<bool> {
+    check_leaves(num_leaves, forest_rows)?;
+    Ok(util::in_forest(pos, num_leaves, forest_rows))
+}
+
+#[pyfunction]
+fn root_position(num_leaves: u64, row: u8, forest_rows: u8) -> PyResult<u64> {
+    check_leaves(num_leaves, forest_rows)?;
+    check_row(row, forest_rows)?;
+    Ok(util::root_position(num_leaves, row, forest_rows))
+}
+
+#[pyfunction]
+fn is_root_position(pos: u64, num_leaves: u64, forest_rows: u8) -> PyResult<bool> {
+    check_leaves(num_leaves, forest_rows)?;
+    check_pos(pos, forest_rows)?;
+    Ok(util::is_root_position(pos, num_leaves, forest_rows))
+}
+
+/// root_positions returns the positions of the roots, biggest tree first,
+/// the same order as Pollard.roots.
+#[pyfunction]
+fn root_positions(num_leaves: u64) -> PyResult<Vec<u64>> {
+    let forest_rows = tree_rows(num_leaves)?;
+    let mut roots = util::get_roots_reverse(num_leaves, forest_rows);
+    roots.reverse();
+    Ok(roots)
+}
+
+#[pyfunction]
+fn proof_positions(targets: Vec<u64>, num_leaves: u64, forest_rows: u8) -> PyResult<Vec<u64>> {
+    check_leaves(num_leaves, forest_rows)?;
+    if let Some(target) = targets.iter().find(|target| **target >= num_leaves) {
+        return Err(PyValueError::new_err(format!("target {} isn't one of the {} leaves", target, num_leaves)));
+    }
+    Ok(util::proof_positions(&targets, num_leaves, forest_rows))
+}
+
+#[pymodule]
+#[pyo3(name = "rustreexo")]
+fn rustreexo_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("RustreexoError", m.py().get_type_bound::<RustreexoError>())?;
+    m.add_class::<PyLeaf>()?;
+    m.add_class::<PyBatchProof>()?;
+    m.add_class::<PyPollard>()?;
+
+    let helpers = PyModule::new_bound(m.py(), "util")?;
+    helpers.add_function(wrap_pyfunction!(tree_rows, &helpers)?)?;
+    helpers.add_function(wrap_pyfunction!(detect_row, &helpers)?)?;
+    helpers.add_function(wrap_pyfunction!(row_offset, &helpers)?)?;
+    helpers.add_function(wrap_pyfunction!(parent, &helpers)?)?;
+    helpers.add_function(wrap_pyfunction!(child, &helpers)?)?;
+    helpers.add_function(wrap_pyfunction!(in_forest, &helpers)?)?;
+    helpers.add_function(wrap_pyfunction!(root_position, &helpers)?)?;
+    helpers.add_function(wrap_pyfunction!(is_root_position, &helpers)?)?;
+    helpers.add_function(wrap_pyfunction!(root_positions, &helpers)?)?;
+    helpers.add_function(wrap_pyfunction!(proof_positions, &helpers)?)?;
+    m.add_submodule(&helpers)?;
+
+    Ok(())
+}
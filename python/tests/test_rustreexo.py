@@ -0,0 +1,119 @@
+# Rustreexo
+
+import pytest
+
+import rustreexo
+from rustreexo import util
+
+
+def leaf_hash(num):
+    return num.to_bytes(8, "little") + bytes(24)
+
+
+def make_pollard(num_leaves):
+    pollard = rustreexo.Pollard()
+    pollard.modify([rustreexo.Leaf(leaf_hash(i), remember=True) for i in range(num_leaves)], [])
+    return pollard
+
+
+def test_leaf():
+    leaf = rustreexo.Leaf(leaf_hash(1))
+    assert leaf.hash == leaf_hash(1)
+    assert not leaf.remember
+    assert rustreexo.Leaf(leaf_hash(1), remember=True).remember
+
+    with pytest.raises(ValueError):
+        rustreexo.Leaf(b"short")
+
+
+def test_add():
+    pollard = make_pollard(5)
+    assert pollard.num_leaves == 5
+
+    roots = pollard.roots()
+    assert len(roots) == 2
+    assert roots[1] == leaf_hash(4)
+    assert all(len(root) == 32 for root in roots)
+
+
+def test_prove_and_verify():
+    pollard = make_pollard(8)
+    hashes = [leaf_hash(2), leaf_hash(5)]
+
+    proof = pollard.prove(hashes)
+    assert proof.targets == [2, 5]
+    assert len(proof.proof) == len(util.proof_positions([2, 5], 8, util.tree_rows(8)))
+    assert proof.proof[:2] == [leaf_hash(3), leaf_hash(4)]
+    assert pollard.verify(proof, hashes)
+    assert proof.verify(hashes, pollard.roots(), pollard.num_leaves)
+
+    # The proof is only for the leaves it was made for
+    assert not pollard.verify(proof, [leaf_hash(2), leaf_hash(6)])
+
+    copy = rustreexo.BatchProof(proof.targets, proof.proof)
+    assert copy == proof
+
+
+def test_delete():
+    pollard = make_pollard(8)
+    pollard.modify([], [leaf_hash(1)])
+    assert pollard.num_leaves == 7
+
+    with pytest.raises(rustreexo.RustreexoError):
+        pollard.prove([leaf_hash(1)])
+
+
+def test_not_remembered():
+    pollard = rustreexo.Pollard()
+    pollard.modify([rustreexo.Leaf(leaf_hash(i)) for i in range(4)], [])
+
+    with pytest.raises(rustreexo.RustreexoError):
+        pollard.modify([], [leaf_hash(0)])
+    assert pollard.num_leaves == 4
+
+
+def test_util():
+    # Positions of a forest with 8 leaves, as drawn on util::row_offset
+    forest_rows = util.tree_rows(8)
+    assert forest_rows == 3
+    assert util.parent(0, forest_rows) == 8
+    assert util.parent(12, forest_rows) == 14
+    assert util.child(14, forest_rows) == 12
+    assert util.detect_row(13, forest_rows) == 2
+    assert util.row_offset(1, forest_rows) == 8
+    assert util.root_positions(8) == [14]
+    assert util.root_positions(5) == [12, 4]
+    assert util.is_root_position(14, 8, forest_rows)
+    assert util.in_forest(4, 5, util.tree_rows(5))
+    assert not util.in_forest(5, 5, util.tree_rows(5))
+    assert util.proof_positions([0], 8, forest_rows) == [1, 9, 13]
+    assert util.root_position(5, 0, util.tree_rows(5)) == 4
+
+    # The biggest forest works, one row more doesn't
+    assert util.tree_rows(1 << 63) == 63
+    assert util.row_offset(63, 63) == (1 << 64) - 2
+    assert util.parent((1 << 64) - 4, 63) == (1 << 64) - 2
+
+
+@pytest.mark.parametrize("call", [
+    lambda: util.row_offset(5, 3),
+    lambda: util.row_offset(0, 64),
+    lambda: util.parent(0, 64),
+    lambda: util.child(15, 3),
+    lambda: util.detect_row(1 << 63, 3),
+    lambda: util.tree_rows((1 << 63) + 1),
+    lambda: util.root_positions((1 << 64) - 1),
+    lambda: util.in_forest(0, 9, 3),
+    lambda: util.root_position(8, 4, 3),
+    lambda: util.is_root_position(14, 8, 64),
+    lambda: util.proof_positions([8], 8, 3),
+])
+def test_util_bad_input(call):
+    with pytest.raises(ValueError):
+        call()
+
+
+def test_display():
+    pollard = make_pollard(2)
+    assert str(pollard)
+    assert pollard.to_dot().startswith("digraph forest {")
@@ -0,0 +1,68 @@
+// Rustreexo
+
+use core::fmt;
+
+use bitcoin_hashes::sha256;
+
+/// Error is returned by the accumulator when the given data can't be used
+/// to perform the requested operation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The number of target hashes given doesn't match the number of targets
+    /// in the proof.
+    TargetCountMismatch { targets: usize, hashes: usize },
+
+    /// The number of hashes in the proof doesn't match the number of
+    /// positions the proof needs.
+    ProofCountMismatch { positions: usize, hashes: usize },
+
+    /// The proof doesn't hash up to the roots of the accumulator.
+    InvalidProof,
+
+    /// A target of the proof isn't a leaf or is there more than once.
+    InvalidTargets,
+
+    /// The hash for the position is not known and can't be calculated.
+    MissingHash(u64),
+
+    /// The leaf with the hash wasn't remembered so it can't be proven.
+    LeafNotRemembered(sha256::Hash),
+
+    /// The Pollard doesn't have one root for each tree of its leaves.
+    RootCountMismatch { roots: usize, expected: usize },
+
+    /// The hash of the node at the position isn't the hash of its children.
+    HashMismatch(u64),
+
+    /// The node at the position has children where it shouldn't or only one
+    /// of them.
+    BadNieces(u64),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::TargetCountMismatch { targets, hashes } => {
+                write!(f, "got {} hashes for {} targets", hashes, targets)
+            }
+            Error::ProofCountMismatch { positions, hashes } => {
+                write!(f, "got {} proof hashes for {} proof positions", hashes, positions)
+            }
+            Error::InvalidProof => write!(f, "proof doesn't hash up to the roots"),
+            Error::InvalidTargets => write!(f, "proof targets aren't distinct leaves"),
+            Error::MissingHash(pos) => write!(f, "hash for position {} is not known", pos),
+            Error::LeafNotRemembered(hash) => write!(f, "leaf {} was not remembered", hash),
+            Error::RootCountMismatch { roots, expected } => {
+                write!(f, "got {} roots where there should be {}", roots, expected)
+            }
+            Error::HashMismatch(pos) => write!(f, "hash at position {} doesn't match its children", pos),
+            Error::BadNieces(pos) => write!(f, "children of position {} don't fit the tree", pos),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+/// Result is the result type used by the accumulator.
+pub type Result<T> = core::result::Result<T, Error>;
@@ -0,0 +1,425 @@
+// Rustreexo
+
+// SHA-256 for inputs that are exactly 64 bytes long, which is what every
+// parent hash is. The second block of the message is always the same
+// padding so its message schedule is worked out ahead of time.
+//
+// Multiple inputs can be hashed at once. The SHA extensions are used when
+// the CPU has them, going through the rounds of 2 inputs in turn so that
+// neither waits on the one before. Otherwise AVX2 hashes 8 inputs side by
+// side.
+
+use alloc::vec::Vec;
+#[cfg(target_arch = "x86_64")]
+use core::arch::x86_64::*;
+
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+const INITIAL_STATE: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+// The block that follows a 64 byte input: a single set bit and then the
+// length of the input in bits
+#[cfg(target_arch = "x86_64")]
+const PADDING_BLOCK: [u8; 64] = {
+    let mut block = [0u8; 64];
+    block[0] = 0x80;
+    block[62] = 0x02;
+    block
+};
+
+// The message schedule of the padding block with the round constants
+// already added in
+const PADDING_SCHEDULE: [u32; 64] = {
+    let mut w = [0u32; 64];
+    w[0] = 0x80000000;
+    w[15] = 512;
+
+    let mut i = 16;
+    while i < 64 {
+        w[i] = small_sigma1(w[i - 2])
+            .wrapping_add(w[i - 7])
+            .wrapping_add(small_sigma0(w[i - 15]))
+            .wrapping_add(w[i - 16]);
+        i += 1;
+    }
+
+    let mut i = 0;
+    while i < 64 {
+        w[i] = w[i].wrapping_add(K[i]);
+        i += 1;
+    }
+
+    w
+};
+
+const fn small_sigma0(x: u32) -> u32 {
+    x.rotate_right(7) ^ x.rotate_right(18) ^ (x >> 3)
+}
+
+const fn small_sigma1(x: u32) -> u32 {
+    x.rotate_right(17) ^ x.rotate_right(19) ^ (x >> 10)
+}
+
+/// hash64 returns the SHA-256 of the 64 byte input.
+pub fn hash64(input: &[u8; 64]) -> [u8; 32] {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if has_sha_ni() {
+            // Safe as the CPU has the instructions
+            return unsafe { hash64_sha_ni([input]) }[0];
+        }
+    }
+
+    hash64_scalar(input)
+}
+
+/// hash64_batch returns the SHA-256 of each of the 64 byte inputs, in the
+/// same order.
+pub fn hash64_batch(inputs: &[[u8; 64]]) -> Vec<[u8; 32]> {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if has_sha_ni() {
+            let mut hashes = Vec::with_capacity(inputs.len());
+
+            let mut chunks = inputs.chunks_exact(2);
+            for chunk in &mut chunks {
+                // Safe as the CPU has the instructions
+                hashes.extend_from_slice(&unsafe { hash64_sha_ni([&chunk[0], &chunk[1]]) });
+            }
+            for input in chunks.remainder() {
+                hashes.push(unsafe { hash64_sha_ni([input]) }[0]);
+            }
+
+            return hashes;
+        }
+
+        if has_avx2() {
+            let mut hashes = Vec::with_capacity(inputs.len());
+
+            let mut chunks = inputs.chunks_exact(8);
+            for chunk in &mut chunks {
+                // Safe as the CPU has the instructions
+                hashes.extend_from_slice(&unsafe { hash64_x8_avx2(chunk) });
+            }
+            hashes.extend(chunks.remainder().iter().map(hash64_scalar));
+
+            return hashes;
+        }
+    }
+
+    inputs.iter().map(hash64_scalar).collect()
+}
+
+// Without std the CPU can't be asked what it has so only what the crate
+// was built for is used
+#[cfg(all(target_arch = "x86_64", feature = "std"))]
+fn has_sha_ni() -> bool {
+    is_x86_feature_detected!("sha") &&
+        is_x86_feature_detected!("sse4.1") &&
+        is_x86_feature_detected!("ssse3")
+}
+
+#[cfg(all(target_arch = "x86_64", not(feature = "std")))]
+fn has_sha_ni() -> bool {
+    cfg!(all(target_feature = "sha", target_feature = "sse4.1", target_feature = "ssse3"))
+}
+
+#[cfg(all(target_arch = "x86_64", feature = "std"))]
+fn has_avx2() -> bool {
+    is_x86_feature_detected!("avx2")
+}
+
+#[cfg(all(target_arch = "x86_64", not(feature = "std")))]
+fn has_avx2() -> bool {
+    cfg!(target_feature = "avx2")
+}
+
+// hash64_scalar is hash64 on any CPU.
+fn hash64_scalar(input: &[u8; 64]) -> [u8; 32] {
+    let mut w = [0u32; 64];
+    for (i, word) in input.chunks_exact(4).enumerate() {
+        w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+    }
+    for i in 16..64 {
+        w[i] = small_sigma1(w[i - 2])
+            .wrapping_add(w[i - 7])
+            .wrapping_add(small_sigma0(w[i - 15]))
+            .wrapping_add(w[i - 16]);
+    }
+    for i in 0..64 {
+        w[i] = w[i].wrapping_add(K[i]);
+    }
+
+    let mut state = INITIAL_STATE;
+    compress(&mut state, &w);
+    compress(&mut state, &PADDING_SCHEDULE);
+
+    let mut hash = [0u8; 32];
+    for (i, word) in state.iter().enumerate() {
+        hash[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+    }
+
+    hash
+}
+
+// compress runs the 64 rounds over the state. The message schedule has the
+// round constants added in.
+fn compress(state: &mut [u32; 8], schedule: &[u32; 64]) {
+    let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = *state;
+
+    for kw in schedule.iter() {
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let ch = (e & f) ^ (!e & g);
+        let t1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(*kw);
+
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let t2 = s0.wrapping_add(maj);
+
+        h = g;
+        g = f;
+        f = e;
+        e = d.wrapping_add(t1);
+        d = c;
+        c = b;
+        b = a;
+        a = t1.wrapping_add(t2);
+    }
+
+    for (word, new) in state.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+        *word = word.wrapping_add(*new);
+    }
+}
+
+// hash64_sha_ni hashes the inputs with the SHA extensions. The rounds of
+// the inputs are done in turn so that the CPU can run the ones for one input
+// while the others wait on the rounds before.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sha,sse2,ssse3,sse4.1")]
+unsafe fn hash64_sha_ni<const N: usize>(inputs: [&[u8; 64]; N]) -> [[u8; 32]; N] {
+    // Flips the bytes of each word from big endian
+    let mask = _mm_set_epi64x(0x0c0d_0e0f_0809_0a0b, 0x0405_0607_0001_0203);
+
+    // The instructions want the state as ABEF and CDGH
+    let state = INITIAL_STATE.as_ptr() as *const __m128i;
+    let dcba = _mm_shuffle_epi32(_mm_loadu_si128(state), 0xb1);
+    let hgfe = _mm_shuffle_epi32(_mm_loadu_si128(state.add(1)), 0x1b);
+    let mut abef = [_mm_alignr_epi8(dcba, hgfe, 8); N];
+    let mut cdgh = [_mm_blend_epi16(hgfe, dcba, 0xf0); N];
+
+    let padding: &[u8; 64] = &PADDING_BLOCK;
+    for block in 0..2 {
+        let abef_save = abef;
+        let cdgh_save = cdgh;
+
+        let mut w = [[_mm_setzero_si128(); 4]; N];
+        for (w, input) in w.iter_mut().zip(inputs.iter()) {
+            let data = if block == 0 { input.as_ptr() } else { padding.as_ptr() } as *const __m128i;
+            for (j, word) in w.iter_mut().enumerate() {
+                *word = _mm_shuffle_epi8(_mm_loadu_si128(data.add(j)), mask);
+            }
+        }
+
+        // 4 rounds at a time, working out the next 4 words of the schedule
+        // from the last 16 once the first 16 rounds are done
+        for i in 0..16 {
+            let k = _mm_loadu_si128(K.as_ptr().add(i * 4) as *const __m128i);
+
+            for lane in 0..N {
+                let w = &mut w[lane];
+                if i >= 4 {
+                    let t = _mm_add_epi32(_mm_sha256msg1_epu32(w[i % 4], w[(i + 1) % 4]),
+                                          _mm_alignr_epi8(w[(i + 3) % 4], w[(i + 2) % 4], 4));
+                    w[i % 4] = _mm_sha256msg2_epu32(t, w[(i + 3) % 4]);
+                }
+
+                let kw = _mm_add_epi32(w[i % 4], k);
+                cdgh[lane] = _mm_sha256rnds2_epu32(cdgh[lane], abef[lane], kw);
+                abef[lane] = _mm_sha256rnds2_epu32(abef[lane], cdgh[lane], _mm_shuffle_epi32(kw, 0x0e));
+            }
+        }
+
+        for lane in 0..N {
+            abef[lane] = _mm_add_epi32(abef[lane], abef_save[lane]);
+            cdgh[lane] = _mm_add_epi32(cdgh[lane], cdgh_save[lane]);
+        }
+    }
+
+    let mut hashes = [[0u8; 32]; N];
+    for (lane, hash) in hashes.iter_mut().enumerate() {
+        // Back to ABCD and EFGH
+        let feba = _mm_shuffle_epi32(abef[lane], 0x1b);
+        let dchg = _mm_shuffle_epi32(cdgh[lane], 0xb1);
+        let dcba = _mm_blend_epi16(feba, dchg, 0xf0);
+        let hgef = _mm_alignr_epi8(dchg, feba, 8);
+
+        let mut state = [0u32; 8];
+        _mm_storeu_si128(state.as_mut_ptr() as *mut __m128i, dcba);
+        _mm_storeu_si128(state.as_mut_ptr().add(4) as *mut __m128i, hgef);
+
+        for (i, word) in state.iter().enumerate() {
+            hash[i * 4..i * 4 + 4].copy_from_slice(&word.to_be_bytes());
+        }
+    }
+
+    hashes
+}
+
+// hash64_x8_avx2 hashes 8 inputs at once, one in each 32 bit lane.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn hash64_x8_avx2(inputs: &[[u8; 64]]) -> [[u8; 32]; 8] {
+    macro_rules! rotr {
+        ($x:expr, $n:literal) => {
+            _mm256_or_si256(_mm256_srli_epi32($x, $n), _mm256_slli_epi32($x, 32 - $n))
+        };
+    }
+
+    // Lay the words out so that word i of every input is in w[i]
+    let mut words = [[0u32; 8]; 16];
+    for (lane, input) in inputs.iter().enumerate() {
+        for (i, word) in input.chunks_exact(4).enumerate() {
+            words[i][lane] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+    }
+
+    let mut w = [_mm256_setzero_si256(); 64];
+    for i in 0..16 {
+        w[i] = _mm256_loadu_si256(words[i].as_ptr() as *const __m256i);
+    }
+    for i in 16..64 {
+        let s0 = _mm256_xor_si256(_mm256_xor_si256(rotr!(w[i - 15], 7), rotr!(w[i - 15], 18)),
+                                  _mm256_srli_epi32(w[i - 15], 3));
+        let s1 = _mm256_xor_si256(_mm256_xor_si256(rotr!(w[i - 2], 17), rotr!(w[i - 2], 19)),
+                                  _mm256_srli_epi32(w[i - 2], 10));
+        w[i] = _mm256_add_epi32(_mm256_add_epi32(s1, w[i - 7]), _mm256_add_epi32(s0, w[i - 16]));
+    }
+    for i in 0..64 {
+        w[i] = _mm256_add_epi32(w[i], _mm256_set1_epi32(K[i] as i32));
+    }
+
+    let mut state = [_mm256_setzero_si256(); 8];
+    for (word, initial) in state.iter_mut().zip(INITIAL_STATE.iter()) {
+        *word = _mm256_set1_epi32(*initial as i32);
+    }
+
+    let mut padding = [_mm256_setzero_si256(); 64];
+    for (kw, padding_kw) in padding.iter_mut().zip(PADDING_SCHEDULE.iter()) {
+        *kw = _mm256_set1_epi32(*padding_kw as i32);
+    }
+
+    for schedule in [&w, &padding].iter() {
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+
+        for kw in schedule.iter() {
+            let s1 = _mm256_xor_si256(_mm256_xor_si256(rotr!(e, 6), rotr!(e, 11)), rotr!(e, 25));
+            let ch = _mm256_xor_si256(_mm256_and_si256(e, f), _mm256_andnot_si256(e, g));
+            let t1 = _mm256_add_epi32(_mm256_add_epi32(h, s1), _mm256_add_epi32(ch, *kw));
+
+            let s0 = _mm256_xor_si256(_mm256_xor_si256(rotr!(a, 2), rotr!(a, 13)), rotr!(a, 22));
+            let maj = _mm256_xor_si256(_mm256_xor_si256(_mm256_and_si256(a, b), _mm256_and_si256(a, c)),
+                                       _mm256_and_si256(b, c));
+            let t2 = _mm256_add_epi32(s0, maj);
+
+            h = g;
+            g = f;
+            f = e;
+            e = _mm256_add_epi32(d, t1);
+            d = c;
+            c = b;
+            b = a;
+            a = _mm256_add_epi32(t1, t2);
+        }
+
+        for (word, new) in state.iter_mut().zip([a, b, c, d, e, f, g, h].iter()) {
+            *word = _mm256_add_epi32(*word, *new);
+        }
+    }
+
+    let mut hashes = [[0u8; 32]; 8];
+    for (i, word) in state.iter().enumerate() {
+        let mut lanes = [0u32; 8];
+        _mm256_storeu_si256(lanes.as_mut_ptr() as *mut __m256i, *word);
+
+        for (hash, lane) in hashes.iter_mut().zip(lanes.iter()) {
+            hash[i * 4..i * 4 + 4].copy_from_slice(&lane.to_be_bytes());
+        }
+    }
+
+    hashes
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin_hashes::{sha256, Hash};
+
+    fn inputs(count: usize) -> Vec<[u8; 64]> {
+        (0..count).map(|i| {
+            let mut input = [0u8; 64];
+            for (j, byte) in input.iter_mut().enumerate() {
+                *byte = (i * 64 + j).wrapping_mul(167) as u8;
+            }
+            input
+        }).collect()
+    }
+
+    fn expected(input: &[u8; 64]) -> [u8; 32] {
+        sha256::Hash::hash(input).into_inner()
+    }
+
+    #[test]
+    fn test_hash64() {
+        for input in inputs(100).iter().chain([[0u8; 64], [0xff; 64]].iter()) {
+            assert_eq!(super::hash64_scalar(input), expected(input));
+            assert_eq!(super::hash64(input), expected(input));
+        }
+
+        for count in [0, 1, 7, 8, 9, 17, 64].iter() {
+            let inputs = inputs(*count);
+            let hashes: Vec<_> = inputs.iter().map(expected).collect();
+            assert_eq!(super::hash64_batch(&inputs), hashes);
+        }
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_hash64_simd() {
+        let inputs = inputs(64);
+
+        if super::has_sha_ni() {
+            for input in &inputs {
+                assert_eq!(unsafe { super::hash64_sha_ni([input]) }, [expected(input)]);
+            }
+
+            for chunk in inputs.chunks(4) {
+                let pair = unsafe { super::hash64_sha_ni([&chunk[0], &chunk[1]]) };
+                assert_eq!(pair, [expected(&chunk[0]), expected(&chunk[1])]);
+
+                let hashes = unsafe { super::hash64_sha_ni([&chunk[0], &chunk[1], &chunk[2], &chunk[3]]) };
+                for (hash, input) in hashes.iter().zip(chunk) {
+                    assert_eq!(*hash, expected(input));
+                }
+            }
+        }
+
+        if super::has_avx2() {
+            for chunk in inputs.chunks(8) {
+                let hashes = unsafe { super::hash64_x8_avx2(chunk) };
+                for (hash, input) in hashes.iter().zip(chunk) {
+                    assert_eq!(*hash, expected(input));
+                }
+            }
+        }
+    }
+}
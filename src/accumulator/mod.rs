@@ -1,4 +1,15 @@
 pub mod util;
 pub mod types;
 pub mod transform;
+#[cfg(feature = "std")]
 pub mod pollard;
+pub mod proof;
+pub mod error;
+#[cfg(feature = "std")]
+pub mod observer;
+pub mod hash;
+#[cfg(feature = "std")]
+pub mod render;
+#[cfg(feature = "std")]
+pub mod shared;
+pub mod stump;
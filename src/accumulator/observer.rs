@@ -0,0 +1,106 @@
+// Rustreexo
+
+use std::collections::{HashMap, HashSet};
+
+use bitcoin_hashes::sha256;
+
+use super::{types, transform};
+
+/// AccumulatorObserver gets told about the leaves as the accumulator is
+/// modified. Deletions come first, then the moves of the transform and then
+/// the adds, which is the same order the accumulator applies them in.
+///
+/// All the methods do nothing by default so only the ones that are of
+/// interest need to be implemented.
+pub trait AccumulatorObserver {
+    /// on_add is called when a leaf is added at the given position.
+    fn on_add(&mut self, _hash: &sha256::Hash, _pos: u64) {}
+
+    /// on_move is called for every arrow of the transform once it's applied.
+    /// The arrow moves the whole subtree at arrow.from on the given row to
+    /// arrow.to, along with all the leaves under it. The positions are for a
+    /// forest with forest_rows.
+    fn on_move(&mut self, _arrow: &types::Arrow, _row: u8, _forest_rows: u8) {}
+
+    /// on_delete is called when the leaf at the given position is deleted.
+    /// The position is from before any of the leaves were moved.
+    fn on_delete(&mut self, _pos: u64) {}
+}
+
+// The unit type is the observer for when nobody is watching
+impl AccumulatorObserver for () {}
+
+/// LeafTracker keeps track of the positions of a set of watched leaves as
+/// they are added, moved around and deleted in the accumulator.
+#[derive(Clone, Debug, Default)]
+pub struct LeafTracker {
+    // Hashes of the leaves to keep track of
+    watched: HashSet<sha256::Hash>,
+
+    // Positions of the watched leaves that are in the accumulator
+    positions: HashMap<sha256::Hash, u64>,
+}
+
+impl LeafTracker {
+    /// Returns a new LeafTracker that isn't watching anything
+    pub fn new() -> LeafTracker {
+        LeafTracker::default()
+    }
+
+    /// watch starts tracking the leaf with the given hash once it is added
+    /// to the accumulator.
+    pub fn watch(&mut self, hash: sha256::Hash) {
+        self.watched.insert(hash);
+    }
+
+    /// watch_at starts tracking a leaf that is already in the accumulator at
+    /// the given position.
+    pub fn watch_at(&mut self, hash: sha256::Hash, pos: u64) {
+        self.watched.insert(hash);
+        self.positions.insert(hash, pos);
+    }
+
+    /// unwatch stops tracking the leaf with the given hash.
+    pub fn unwatch(&mut self, hash: &sha256::Hash) {
+        self.watched.remove(hash);
+        self.positions.remove(hash);
+    }
+
+    /// position returns the current position of the watched leaf. Returns
+    /// None if the leaf isn't in the accumulator.
+    pub fn position(&self, hash: &sha256::Hash) -> Option<u64> {
+        self.positions.get(hash).copied()
+    }
+
+    /// positions returns the positions of all the watched leaves that are
+    /// in the accumulator.
+    pub fn positions(&self) -> &HashMap<sha256::Hash, u64> {
+        &self.positions
+    }
+}
+
+impl AccumulatorObserver for LeafTracker {
+    fn on_add(&mut self, hash: &sha256::Hash, pos: u64) {
+        if self.watched.contains(hash) {
+            self.positions.insert(*hash, pos);
+        }
+    }
+
+    fn on_move(&mut self, arrow: &types::Arrow, row: u8, forest_rows: u8) {
+        for pos in self.positions.values_mut() {
+            *pos ^= transform::swap_if_descendant(arrow, *pos, row, 0, forest_rows);
+        }
+    }
+
+    fn on_delete(&mut self, pos: u64) {
+        // A spent leaf doesn't come back so stop watching it
+        let spent: Vec<sha256::Hash> = self.positions.iter()
+            .filter(|(_, leaf_pos)| **leaf_pos == pos)
+            .map(|(hash, _)| *hash)
+            .collect();
+
+        for hash in spent {
+            self.unwatch(&hash);
+        }
+    }
+}
@@ -1,15 +1,28 @@
 // Rustreexo
 
 use std::collections::HashMap;
+use std::fmt;
 use std::mem;
+use std::sync::{Arc, Mutex};
 
 use super::{
     types,
     util,
-    transform
+    transform,
+    render,
+    shared::{SharedMap, SharedVec},
+    error::{Error, Result},
+    observer::AccumulatorObserver,
+    proof::BatchProof,
 };
 
-use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin_hashes::{sha256, Hash, HashEngine};
+#[cfg(feature = "serde")]
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+// Rows with fewer nodes to hash than this are hashed on the calling thread
+// as starting the threads would take longer than the hashing
+const PARALLEL_REHASH_THRESHOLD: usize = 1024;
 
 /// Pollard is the sparse representation of the utreexo forest
 /// It is a collection of multitude of trees with leaves that are
@@ -20,258 +33,1030 @@ use bitcoin::hashes::{sha256, Hash, HashEngine};
 ///
 /// Its structure resembles of that of a binary tree, except that
 /// the pointers point to aunts - nieces, not parents - children
+///
+/// The nodes all live in one arena and point to their nieces by their
+/// index in it. The slots of the nodes that get removed are reused.
+///
+/// The arena and the leaf index are shared between clones of the Pollard
+/// until they change, so cloning it and taking a snapshot are cheap.
 #[derive(Clone)]
 pub struct Pollard {
-    /// Roots are the top-most nodes of the tree
-    /// There may be multiple roots as Utreexo is organized as a
-    /// collection of perfect trees.
-    pub roots: Option<Vec<PolNode>>,
+    // Roots are the top-most nodes of the tree
+    // There may be multiple roots as Utreexo is organized as a
+    // collection of perfect trees.
+    roots: Vec<u32>,
 
     /// Total number of leaves (nodes on the bottom row) in the Pollard
     pub num_leaves: u64,
+
+    // All the nodes of the Pollard, including the ones that were removed
+    nodes: SharedVec<PolNode>,
+
+    // Indexes of the removed nodes whose slots can be used again
+    free: SharedVec<u32>,
+
+    // Positions of the remembered leaves by their hash. Only kept if the
+    // Pollard was made with with_leaf_index
+    leaf_index: Option<LeafIndex>,
+
+    // Number of threads to hash the nodes of a row with after a deletion
+    rehash_threads: usize,
+
+    // Whether the nodes that change are only marked dirty and get hashed
+    // on commit
+    lazy: bool,
+
+    // The hashes the dirty nodes will get on commit, kept until a node
+    // changes
+    committed: HashCache,
 }
 
 impl Pollard {
     /// Returns a new pollard
     pub fn new() -> Pollard {
-        Pollard{roots: None, num_leaves:0, }
+        Pollard{roots: Vec::new(), num_leaves:0, nodes: SharedVec::new(), free: SharedVec::new(), leaf_index: None,
+                rehash_threads: 1, lazy: false, committed: HashCache::default(), }
+    }
+
+    /// Returns a new pollard that keeps track of where its remembered leaves
+    /// are, so they can be deleted by their hash with modify_by_hash.
+    pub fn with_leaf_index() -> Pollard {
+        Pollard{leaf_index: Some(LeafIndex::default()), ..Pollard::new()}
+    }
+
+    /// set_rehash_threads sets the number of threads used to hash the nodes
+    /// that changed after a deletion. Rows with only a few nodes to hash are
+    /// always done on the calling thread. Defaults to 1.
+    pub fn set_rehash_threads(&mut self, threads: usize) {
+        self.rehash_threads = threads.max(1);
+    }
+
+    /// set_lazy turns the lazy mode on or off. In lazy mode the nodes that
+    /// change are only marked dirty and get hashed when the roots are asked
+    /// for or on commit, so blocks can be applied one after another without
+    /// hashing the nodes they have in common more than once. The dirty nodes
+    /// are committed when it's turned off.
+    pub fn set_lazy(&mut self, lazy: bool) {
+        if !lazy {
+            self.commit();
+        }
+
+        self.lazy = lazy;
+    }
+
+    /// commit hashes all the dirty nodes and prunes what isn't needed under
+    /// them anymore. Does nothing if nothing is dirty.
+    pub fn commit(&mut self) {
+        for (node, sib, hash) in self.dirty_hashes() {
+            let node = self.node_mut(node);
+            node.data = hash;
+            node.dirty = false;
+
+            self.prune(sib);
+        }
+    }
+
+    // dirty_hashes returns the dirty nodes along with their siblings and the
+    // hashes they should have, lowest row first. Dirty nodes always have a
+    // dirty parent so only the dirty branches are walked.
+    fn dirty_hashes(&self) -> Vec<(u32, u32, sha256::Hash)> {
+        let forest_rows = util::tree_rows(self.num_leaves);
+
+        // The dirty nodes on each row
+        let mut rows: Vec<Vec<(u32, u32)>> = vec![Vec::new(); forest_rows as usize + 1];
+
+        let root_rows = (0..=forest_rows).rev().filter(|row| (self.num_leaves >> row) & 1 == 1);
+        let mut stack: Vec<(u32, u32, u8)> = self.roots.iter()
+            .zip(root_rows)
+            .map(|(root, row)| (*root, *root, row))
+            .collect();
+
+        while let Some((node, sib, row)) = stack.pop() {
+            if !self.nodes[node as usize].dirty {
+                continue
+            }
+
+            rows[row as usize].push((node, sib));
+
+            // The children swap siblings as the sibling points to the nieces
+            if let Some((l_child, r_child)) = self.nodes[sib as usize].nieces() {
+                stack.push((l_child, r_child, row - 1));
+                stack.push((r_child, l_child, row - 1));
+            }
+        }
+
+        let mut hashes: HashMap<u32, sha256::Hash> = HashMap::new();
+        let mut dirty = Vec::new();
+        for row in rows {
+            let data = |idx: u32| hashes.get(&idx).copied().unwrap_or(self.nodes[idx as usize].data);
+            let pairs: Vec<_> = row.iter()
+                .map(|(_, sib)| {
+                    let (l_niece, r_niece) = self.nodes[*sib as usize].nieces().unwrap();
+                    (data(l_niece), data(r_niece))
+                })
+                .collect();
+
+            for ((node, sib), hash) in row.into_iter().zip(hash_pairs(&pairs, self.rehash_threads)) {
+                hashes.insert(node, hash);
+                dirty.push((node, sib, hash));
+            }
+        }
+
+        dirty
+    }
+
+    // committed_hashes returns the hashes the dirty nodes should have by
+    // their index. Empty if nothing is dirty. The hashes are only worked out
+    // again once a node has changed.
+    fn committed_hashes(&self) -> Arc<HashMap<u32, sha256::Hash>> {
+        if let Some(hashes) = self.committed.get() {
+            return hashes;
+        }
+
+        let hashes = if self.roots.iter().any(|root| self.nodes[*root as usize].dirty) {
+            self.dirty_hashes().into_iter().map(|(node, _, hash)| (node, hash)).collect()
+        } else {
+            HashMap::new()
+        };
+
+        let hashes = Arc::new(hashes);
+        self.committed.set(hashes.clone());
+        hashes
+    }
+
+    /// snapshot returns the current state of the Pollard so that it can be
+    /// brought back with restore. The snapshot shares the nodes with the
+    /// Pollard and doesn't change along with it.
+    ///
+    /// Any number of snapshots can be kept and restored in any order. Taking
+    /// one costs a pointer for every 256 nodes. After that the nodes that
+    /// change are copied in chunks of 256, along with the leaf index entries
+    /// on the way to the ones that change.
+    pub fn snapshot(&self) -> PollardSnapshot {
+        PollardSnapshot(self.clone())
+    }
+
+    /// restore brings the Pollard back to how it was when the snapshot was
+    /// taken. The snapshot can be restored again afterwards. The number of
+    /// threads to rehash with is kept.
+    pub fn restore(&mut self, snapshot: &PollardSnapshot) {
+        let rehash_threads = self.rehash_threads;
+        *self = snapshot.0.clone();
+        self.rehash_threads = rehash_threads;
     }
 
     /// Modify changes the Utreexo tree state given the utxos and stxos
     /// stxos are denoted by their value
-    pub fn modify(&mut self, utxos: Vec<types::Leaf>, stxos: Vec<u64>) {
+    ///
+    /// The stxos need to be remembered by the Pollard or ingested from a proof
+    /// beforehand. Errors without changing anything if they are not.
+    pub fn modify(&mut self, utxos: Vec<types::Leaf>, stxos: Vec<u64>) -> Result<()> {
+        self.modify_with_observer(utxos, stxos, &mut ())
+    }
+
+    /// modify_with_observer is modify, but the observer gets called for every
+    /// leaf that gets deleted, moved or added along the way.
+    pub fn modify_with_observer(&mut self, utxos: Vec<types::Leaf>, stxos: Vec<u64>,
+                                observer: &mut dyn AccumulatorObserver) -> Result<()> {
         // Order matters here. Adding then removing will result in a different
         // tree vs deleting then adding. For ease of use, only modify is visible
         // for external crates. This is consensus critical.
-        Pollard::remove(self, stxos);
-        Pollard::add(self, utxos);
+        self.remove(stxos, observer)?;
+        self.add_with_observer(utxos, observer);
+
+        Ok(())
+    }
+
+    /// simulate_modify returns the roots that modify would end up with,
+    /// without changing the Pollard. dels are the hashes of the leaves that
+    /// are spent and proof is the proof for them. Errors if the proof
+    /// doesn't match the roots.
+    pub fn simulate_modify(&self, adds: &[types::Leaf], dels: &[sha256::Hash],
+                           proof: &BatchProof) -> Result<Vec<sha256::Hash>> {
+        // Only the roots and what's in the proof are needed to apply the block
+        let mut pol = Pollard::new();
+        for root in self.roots() {
+            let root = PolNode::new(root, false);
+            let idx = pol.alloc(root);
+            pol.roots.push(idx);
+        }
+        pol.num_leaves = self.num_leaves;
+
+        pol.ingest(proof, dels)?;
+
+        let adds = adds.iter().map(|add| types::Leaf { hash: add.hash, remember: false }).collect();
+        pol.modify(adds, proof.targets.clone())?;
+
+        Ok(pol.roots())
+    }
+
+    /// modify_by_hash is modify, but the stxos are given by their hashes. The
+    /// stxos need to be remembered by the Pollard. Errors without changing
+    /// anything if they are not.
+    pub fn modify_by_hash(&mut self, utxos: Vec<types::Leaf>, stxos: &[sha256::Hash]) -> Result<()> {
+        let positions = self.leaf_positions(stxos)?;
+        self.modify(utxos, positions)
+    }
+
+    // leaf_positions returns the positions of the remembered leaves with the
+    // given hashes. Goes through the whole Pollard if there's no leaf index.
+    fn leaf_positions(&self, hashes: &[sha256::Hash]) -> Result<Vec<u64>> {
+        let index = match &self.leaf_index {
+            Some(index) => index,
+            None => {
+                let scanned: HashMap<sha256::Hash, u64> = self.remembered_leaves().into_iter()
+                    .map(|(pos, hash)| (hash, pos))
+                    .collect();
+                return hashes.iter()
+                    .map(|hash| scanned.get(hash).copied().ok_or(Error::LeafNotRemembered(*hash)))
+                    .collect();
+            }
+        };
+
+        hashes.iter()
+            .map(|hash| index.positions.get(hash).copied().ok_or(Error::LeafNotRemembered(*hash)))
+            .collect()
+    }
+
+    /// ingest adds the nodes of the proof to the Pollard so that the targets
+    /// can be deleted even if they weren't remembered. The proof is verified
+    /// against the roots first.
+    pub fn ingest(&mut self, proof: &BatchProof, target_hashes: &[sha256::Hash]) -> Result<()> {
+        let nodes = proof.calculate_nodes(target_hashes, &self.roots(), self.num_leaves)?;
+        let forest_rows = util::tree_rows(self.num_leaves);
+
+        for target in &proof.targets {
+            let (tree, branch_len, _) = util::detect_offset(*target, self.num_leaves);
+
+            // Go down the branch, filling in the nieces that are missing
+            let mut node = self.roots[tree as usize];
+            for depth in 1..=branch_len {
+                let pos = util::n_grandparent(*target, branch_len - depth, forest_rows).unwrap();
+
+                let nieces = self.nodes[node as usize].nieces();
+                if nieces.is_none() {
+                    let l_data = *nodes.get(&(pos & !1)).ok_or(Error::MissingHash(pos & !1))?;
+                    let r_data = *nodes.get(&(pos | 1)).ok_or(Error::MissingHash(pos | 1))?;
+
+                    // A lone niece is replaced along with whatever is under it
+                    self.chop(node);
+
+                    let l_niece = self.alloc(PolNode::new(l_data, false));
+                    let r_niece = self.alloc(PolNode::new(r_data, false));
+                    let node = self.node_mut(node);
+                    node.l_niece = Some(l_niece);
+                    node.r_niece = Some(r_niece);
+                }
+
+                // the sibling points to the children of the node on the branch
+                node = self.nodes[node as usize].niece((pos & 1) ^ 1).unwrap();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// prove returns a proof for the leaves with the given hashes. The targets
+    /// of the proof are in the same order as the hashes. All the leaves need to
+    /// have been remembered when they were added.
+    pub fn prove(&self, hashes: &[sha256::Hash]) -> Result<BatchProof> {
+        let targets = self.leaf_positions(hashes)?;
+
+        let hashes = self.committed_hashes();
+        let forest_rows = util::tree_rows(self.num_leaves);
+        let proof = util::proof_positions(&targets, self.num_leaves, forest_rows).into_iter()
+            .map(|pos| {
+                let (node, _) = self.grab_idx(pos).ok_or(Error::MissingHash(pos))?;
+                Ok(hashes.get(&node).copied().unwrap_or(self.nodes[node as usize].data))
+            })
+            .collect::<Result<Vec<sha256::Hash>>>()?;
+
+        Ok(BatchProof { targets, proof })
+    }
+
+    // remembered_leaves returns the positions and hashes of all the leaves
+    // that are remembered.
+    fn remembered_leaves(&self) -> Vec<(u64, sha256::Hash)> {
+        let mut leaves = Vec::new();
+
+        let forest_rows = util::tree_rows(self.num_leaves);
+        let root_positions = util::get_roots_reverse(self.num_leaves, forest_rows);
+
+        // Walk down each tree. Every node is kept along with its sibling as
+        // the sibling is the one pointing to the children
+        let mut stack: Vec<(u32, u32, u64)> = self.roots.iter()
+            .zip(root_positions.into_iter().rev())
+            .map(|(root, pos)| (*root, *root, pos))
+            .collect();
+
+        while let Some((node, sib, pos)) = stack.pop() {
+            if pos < self.num_leaves {
+                let node = &self.nodes[node as usize];
+                if node.remember {
+                    leaves.push((pos, node.data));
+                }
+                continue
+            }
+
+            if let Some((l_niece, r_niece)) = self.nodes[sib as usize].nieces() {
+                let left = util::child(pos, forest_rows);
+                stack.push((l_niece, r_niece, left));
+                stack.push((r_niece, l_niece, left | 1));
+            }
+        }
+
+        leaves
+    }
+
+    /// check_integrity goes through the whole Pollard and errors at the first
+    /// position where something doesn't add up. There needs to be a root for
+    /// each tree, every node with children needs to have both of them and
+    /// the hash of them, and only the nodes above the bottom row can have
+    /// children. The trees are walked from the top, biggest one first.
+    pub fn check_integrity(&self) -> Result<()> {
+        let expected = self.num_leaves.count_ones() as usize;
+        if self.roots.len() != expected {
+            return Err(Error::RootCountMismatch { roots: self.roots.len(), expected });
+        }
+
+        // Dirty nodes are checked against the hashes they'll get on commit
+        let hashes = self.committed_hashes();
+        let data = |idx: u32| hashes.get(&idx).copied().unwrap_or(self.nodes[idx as usize].data);
+
+        let forest_rows = util::tree_rows(self.num_leaves);
+        let root_positions = util::get_roots_reverse(self.num_leaves, forest_rows);
+
+        let mut stack: Vec<(u32, u32, u64)> = self.roots.iter()
+            .zip(root_positions.into_iter().rev())
+            .map(|(root, pos)| (*root, *root, pos))
+            .rev()
+            .collect();
+
+        while let Some((node, sib, pos)) = stack.pop() {
+            let sib = &self.nodes[sib as usize];
+            let (l_niece, r_niece) = match (sib.l_niece, sib.r_niece) {
+                (None, None) => continue,
+                (Some(l_niece), Some(r_niece)) if util::detect_row(pos, forest_rows) > 0 => (l_niece, r_niece),
+                _ => return Err(Error::BadNieces(pos)),
+            };
+
+            if types::parent_hash(&data(l_niece), &data(r_niece)) != data(node) {
+                return Err(Error::HashMismatch(pos));
+            }
+
+            let left = util::child(pos, forest_rows);
+            stack.push((r_niece, l_niece, left | 1));
+            stack.push((l_niece, r_niece, left));
+        }
+
+        Ok(())
+    }
+
+    /// to_dot returns the nodes of the Pollard as a Graphviz digraph, labeled
+    /// with their positions and the start of their hashes. It only goes
+    /// through the nodes the Pollard keeps, so it takes time in the number of
+    /// them.
+    pub fn to_dot(&self) -> String {
+        let nodes = self.node_positions().into_iter()
+            .map(|(pos, idx)| (pos, self.idx_short_hash(idx, 8)))
+            .collect();
+        render::dot(self.num_leaves, &nodes)
+    }
+
+    // node_positions returns the position and the index of every node in the
+    // trees, walking down from the roots.
+    fn node_positions(&self) -> Vec<(u64, u32)> {
+        let mut nodes = Vec::new();
+
+        let forest_rows = util::tree_rows(self.num_leaves);
+        let root_positions = util::get_roots_reverse(self.num_leaves, forest_rows);
+
+        let mut stack: Vec<(u32, u32, u64)> = self.roots.iter()
+            .zip(root_positions.into_iter().rev())
+            .map(|(root, pos)| (*root, *root, pos))
+            .collect();
+
+        while let Some((node, sib, pos)) = stack.pop() {
+            nodes.push((pos, node));
+            if pos < self.num_leaves {
+                continue
+            }
+
+            if let Some((l_niece, r_niece)) = self.nodes[sib as usize].nieces() {
+                let left = util::child(pos, forest_rows);
+                stack.push((l_niece, r_niece, left));
+                stack.push((r_niece, l_niece, left | 1));
+            }
+        }
+
+        nodes
+    }
+
+    // short_hash returns the first chars of the hex of the hash at the
+    // position, or None if the node isn't there.
+    fn short_hash(&self, pos: u64, chars: usize) -> Option<String> {
+        let forest_rows = util::tree_rows(self.num_leaves);
+        if !util::in_forest(pos, self.num_leaves, forest_rows) {
+            return None
+        }
+
+        let (node, _) = self.grab_idx(pos)?;
+        Some(self.idx_short_hash(node, chars))
+    }
+
+    // idx_short_hash is short_hash for the node at the index.
+    fn idx_short_hash(&self, idx: u32, chars: usize) -> String {
+        let node = &self.nodes[idx as usize];
+
+        // The hash of a dirty node is only known once it's committed
+        if node.dirty {
+            return "*".repeat(chars);
+        }
+
+        node.data.to_string()[..chars].to_string()
+    }
+
+    /// roots returns the hashes of the roots, biggest tree first.
+    pub fn roots(&self) -> Vec<sha256::Hash> {
+        let hashes = self.committed_hashes();
+        self.roots.iter()
+            .map(|root| hashes.get(root).copied().unwrap_or(self.nodes[*root as usize].data))
+            .collect()
+    }
+
+    /// num_nodes returns how many nodes the Pollard keeps, leaves and roots
+    /// included.
+    pub fn num_nodes(&self) -> usize {
+        self.nodes.len() - self.free.len()
     }
 
     pub fn add(&mut self, adds: Vec<types::Leaf>) {
+        self.add_with_observer(adds, &mut ());
+    }
+
+    fn add_with_observer(&mut self, adds: Vec<types::Leaf>, observer: &mut dyn AccumulatorObserver) {
         // General algo goes:
-        // 1 make a new node & assign data (no nieces; at bottom)
-        // 2 if this node is on a row where there's already a root,
-        // then swap nieces with that root, hash the two datas, and build a new
-        // node 1 higher pointing to them.
-        // goto 2.
-
-        for add in adds {
-            //if add.remember {
-            //    // TODO Should cache the add data
-            //}
-            Pollard::add_single(self, add.hash, false);
-        }
-    }
-
-    // AddSingle adds a single given utxo to the tree
-    // TODO activate caching (use remember). This isn't done in the
-    // Go repo either yet
-    fn add_single(&mut self, utxo: sha256::Hash, remember: bool) {
-
-        // recurse from the right side of the tree until we hit a tree with no root
-        // Destorys roots along the way
-        fn add(pol: &mut Pollard, node: &mut PolNode, num_leaves: u64) -> PolNode{
-            let mut return_node = node.clone();
-
-            if num_leaves & 1 == 1 {
-                match &mut pol.roots {
-                    // if num_leaves & 1 is true, pol.roots can't be none
-                    None => (),
-                    Some(roots) => {
-                        //let before_len = roots.clone().len();
-                        let mut left_root = roots.pop().unwrap();
-                        //assert_ne!(roots.clone().len(), before_len); // sanity
-
-                        mem::swap(&mut left_root.l_niece, &mut node.l_niece);
-                        mem::swap(&mut left_root.r_niece, &mut node.r_niece);
-
-                        let n_hash = types::parent_hash(&left_root.data.clone(), &node.data.clone());
-                        let new_node = &mut PolNode {
-                            data: n_hash,
-                            l_niece: Some(Box::new(left_root)),
-                            r_niece: Some(Box::new(node.clone())),
-                        };
-
-                        new_node.prune();
-                        return_node = add(pol, new_node, num_leaves>>1);
-                    },
-                }
+        // 1 make new nodes & assign data (no nieces; at bottom)
+        // 2 if there's already a root on this row, it goes to the left of
+        // the new nodes.
+        // 3 pair up the nodes, swapping nieces between the pairs. Hash the
+        // two datas and build a new node 1 higher pointing to them.
+        // 4 if a node is left over, it's a root now.
+        // goto 2 with the new nodes.
+        //
+        // This ends up with the same Pollard as adding the leaves one at a
+        // time but every node gets built only once.
+
+        let mut row_nodes = Vec::with_capacity(adds.len());
+        for (i, add) in adds.into_iter().enumerate() {
+            let pos = self.num_leaves + i as u64;
+            if let (true, Some(index)) = (add.remember, &mut self.leaf_index) {
+                index.insert(add.hash, pos);
             }
 
-            return return_node;
+            observer.on_add(&add.hash, pos);
+            row_nodes.push(self.alloc(PolNode::new(add.hash, add.remember)));
         }
 
-        // init node. If the Pollard is perfect (meaning only one root), this will become a
-        // new root
-        let mut node = &mut PolNode {
-            data: utxo,
-            l_niece: None,
-            r_niece: None,
-        };
+        let num_adds = row_nodes.len() as u64;
 
-        let add_node = add(self, &mut node, self.num_leaves);
+        // Roots that are left over from each row, lowest row first
+        let mut new_roots = Vec::new();
 
-        match &mut self.roots {
-            None => {
-                self.roots = Some(vec![add_node; 1]);
-            },
-            Some(root) => {
-                root.push(add_node)
+        let mut row = 0;
+        while !row_nodes.is_empty() {
+            // The root on this row comes before everything that's added
+            if (self.num_leaves >> row) & 1 == 1 {
+                row_nodes.insert(0, self.roots.pop().unwrap());
+            }
+
+            if row_nodes.len() % 2 != 0 {
+                new_roots.push(row_nodes.pop().unwrap());
             }
+
+            // Hash the whole row at once, unless the hashing is left for later
+            let hashes: Vec<Option<sha256::Hash>> = if self.lazy {
+                vec![None; row_nodes.len() / 2]
+            } else {
+                let pairs: Vec<_> = row_nodes.chunks(2)
+                    .map(|pair| (self.nodes[pair[0] as usize].data, self.nodes[pair[1] as usize].data))
+                    .collect();
+                types::parent_hash_batch(&pairs).into_iter().map(Some).collect()
+            };
+
+            row_nodes = row_nodes.chunks(2).zip(hashes)
+                .map(|(pair, hash)| self.join(pair[0], pair[1], hash))
+                .collect::<Vec<u32>>();
+
+            row += 1;
+        }
+
+        self.roots.extend(new_roots.into_iter().rev());
+        self.num_leaves += num_adds;
+    }
+
+    // join makes the two nodes siblings and returns their new parent, which
+    // has the given hash. The siblings swap their nieces as they point to
+    // each other's children now. The nieces are pruned if nothing under them
+    // is remembered. Without a hash the parent is left dirty and pruning is
+    // left for commit.
+    fn join(&mut self, left: u32, right: u32, hash: Option<sha256::Hash>) -> u32 {
+        let (l_node, r_node) = self.pair_mut(left, right);
+        mem::swap(&mut l_node.l_niece, &mut r_node.l_niece);
+        mem::swap(&mut l_node.r_niece, &mut r_node.r_niece);
+
+        let mut parent = PolNode::new(hash.unwrap_or_default(), false);
+        parent.l_niece = Some(left);
+        parent.r_niece = Some(right);
+        parent.dirty = hash.is_none();
+
+        let parent = self.alloc(parent);
+        if hash.is_some() {
+            self.prune(parent);
         }
 
-        // increment leaf count
-        self.num_leaves += 1;
+        parent
     }
 
-    fn remove(&mut self, mut dels: Vec<u64>) {
+    fn remove(&mut self, mut dels: Vec<u64>, observer: &mut dyn AccumulatorObserver) -> Result<()> {
         // if there is nothing to delete, return
-        if dels.len() == 0 {
-            return
+        if dels.is_empty() {
+            return Ok(())
+        }
+
+        dels.sort_unstable();
+        dels.dedup();
+
+        // Everything that gets moved around is either on the branch of a
+        // deleted leaf or a sibling of it. Make sure they are all here
+        // before touching anything
+        for del in &dels {
+            if *del >= self.num_leaves || self.grab_pos(*del).is_none() {
+                return Err(Error::MissingHash(*del));
+            }
         }
 
         let pollard_rows = util::tree_rows(self.num_leaves);
 
         let leaves_after_del = self.num_leaves - dels.len() as u64;
 
+        for del in &dels {
+            if let Some(index) = &mut self.leaf_index {
+                index.remove(*del);
+            }
+
+            observer.on_delete(*del);
+        }
+
         // get all the swaps, then apply them all
         let swap_rows = transform::transform(dels, self.num_leaves, pollard_rows);
 
-        println!("{:?}", swap_rows);
+        let mut hash_dirt: Vec<u64> = Vec::new();
+        let mut new_roots = Vec::new();
 
-        //let hash_dirt: Vec<u64>;
-        //let next_hash_dirt: Vec<u64>;
-        //let prev_hash: u64;
+        for (row, swaps) in swap_rows.iter().enumerate() {
+            // Everything below this row is in place now. Hash the dirty nodes
+            // before they get moved around by the swaps on this row
+            let mut next_hash_dirt = self.rehash(hash_dirt, pollard_rows);
 
-        //for row in 0..pollard_rows {
-        //}
+            for swap in swaps {
+                self.swap_nodes(swap);
+                if let Some(index) = &mut self.leaf_index {
+                    index.swap_subtrees(swap, pollard_rows);
+                }
 
-    }
+                observer.on_move(swap, row as u8, pollard_rows);
 
-    fn grab_pos(&mut self, pos: u64) -> Option<(PolNode, PolNode, HashableNode)> {
+                next_hash_dirt.push(util::parent(swap.from, pollard_rows));
+                next_hash_dirt.push(util::parent(swap.to, pollard_rows));
+            }
 
-        fn grab_niece(mut p_node: PolNode, mut p_node_sib: PolNode, branch_len: u8, bits: u64) -> Option<(PolNode, PolNode, u8)> {
+            // Nothing on this row changes anymore so the root of the smaller
+            // forest on this row can be taken out. It has to be done now as
+            // hashing the next row prunes the deleted nodes it's under
+            if leaves_after_del & (1 << row) != 0 {
+                let pos = root_origin(leaves_after_del, row as u8, &swap_rows, pollard_rows);
+                let (node, sib) = self.grab_idx(pos).ok_or(Error::MissingHash(pos))?;
 
-            // calculate the left root. 0 means left_niece, 1 means right_niece
-            let lr = bits>>branch_len & 1;
+                let node = &self.nodes[node as usize];
+                let mut root = PolNode::new(node.data, node.remember);
+                root.dirty = node.dirty;
 
-            // 0 is the left, 1 is the right as
-            if lr == 0 {
-                match &mut p_node.l_niece {
-                    None => return None,
-                    Some(niece) => {
-                        p_node = *niece.clone()
-                    }
-                }
+                let sib = self.node_mut(sib);
+                root.l_niece = sib.l_niece.take();
+                root.r_niece = sib.r_niece.take();
 
-                match &mut p_node.r_niece {
-                    None => return None,
-                    Some(niece) => {
-                        p_node_sib = *niece.clone()
-                    }
-                }
-            } else {
-                match &mut p_node.r_niece {
-                    None => return None,
-                    Some(niece) => {
-                        p_node = *niece.clone()
-                    }
-                }
+                new_roots.push(self.alloc(root));
+            }
 
-                match &mut p_node.l_niece {
-                    None => return None,
-                    Some(niece) => {
-                        p_node_sib = *niece.clone()
-                    }
-                }
+            hash_dirt = next_hash_dirt;
+        }
+
+        // The roots were taken out from the bottom up but the biggest tree
+        // goes first
+        new_roots.reverse();
+
+        // What's left under the old roots was deleted
+        for root in mem::replace(&mut self.roots, new_roots) {
+            self.free_subtree(root);
+        }
+        self.num_leaves = leaves_after_del;
+
+        Ok(())
+    }
+
+    // rehash hashes the nodes at the given positions again from their
+    // children and returns the positions of their parents.
+    fn rehash(&mut self, mut hash_dirt: Vec<u64>, forest_rows: u8) -> Vec<u64> {
+        hash_dirt.sort_unstable();
+        hash_dirt.dedup();
+
+        // Find all the nodes first so that they can be hashed all at once
+        let mut dirty = Vec::with_capacity(hash_dirt.len());
+        let mut pairs = Vec::with_capacity(hash_dirt.len());
+        for pos in hash_dirt {
+            // Parents of the roots aren't in the forest
+            if !util::in_forest(pos, self.num_leaves, forest_rows) {
+                continue
             }
 
-            // Check if next recurse is gonna be at the bottom
-            // Recurse as long as we're not at bottom
-            if branch_len-1 != 0 {
-                grab_niece(p_node.clone(), p_node_sib.clone(), branch_len-1, bits);
+            let (node, sib) = match self.grab_idx(pos) {
+                Some(found) => found,
+                None => continue,
+            };
+
+            let (l_niece, r_niece) = match self.nodes[sib as usize].nieces() {
+                Some(nieces) => nieces,
+                None => continue,
+            };
+
+            dirty.push((pos, node, sib));
+            if !self.lazy {
+                pairs.push((self.nodes[l_niece as usize].data, self.nodes[r_niece as usize].data));
             }
+        }
 
-            return Some((p_node, p_node_sib, branch_len-1));
+        // In lazy mode the nodes are only marked and commit hashes them
+        if self.lazy {
+            return dirty.into_iter()
+                .map(|(pos, node, _)| {
+                    self.node_mut(node).dirty = true;
+                    util::parent(pos, forest_rows)
+                })
+                .collect();
         }
 
+        let hashes = hash_pairs(&pairs, self.rehash_threads);
+
+        let mut next_hash_dirt = Vec::with_capacity(dirty.len());
+        for ((pos, node, sib), hash) in dirty.into_iter().zip(hashes) {
+            // The children aren't needed anymore if there's nothing to
+            // remember under them
+            self.prune(sib);
+
+            self.node_mut(node).data = hash;
+            next_hash_dirt.push(util::parent(pos, forest_rows));
+        }
+
+        next_hash_dirt
+    }
+
+    // swap_nodes swaps the subtrees at both ends of the arrow. The data is
+    // swapped between the nodes and the nieces are swapped between their
+    // siblings, as those are the ones that point to the children.
+    fn swap_nodes(&mut self, swap: &types::Arrow) {
+        let (from, from_sib) = self.grab_idx(swap.from).unwrap();
+        let (to, to_sib) = self.grab_idx(swap.to).unwrap();
+
+        let (from, to) = self.pair_mut(from, to);
+        mem::swap(&mut from.data, &mut to.data);
+        mem::swap(&mut from.remember, &mut to.remember);
+        mem::swap(&mut from.dirty, &mut to.dirty);
+
+        let (from_sib, to_sib) = self.pair_mut(from_sib, to_sib);
+        mem::swap(&mut from_sib.l_niece, &mut to_sib.l_niece);
+        mem::swap(&mut from_sib.r_niece, &mut to_sib.r_niece);
+    }
+
+    // grab_pos returns the node at the given position along with its sibling.
+    // The sibling is the one that points to the children of the node. Roots
+    // don't have a sibling and point to their own children so the root is
+    // returned twice.
+    fn grab_pos(&self, pos: u64) -> Option<(&PolNode, &PolNode)> {
+        let (node, sib) = self.grab_idx(pos)?;
+        Some((&self.nodes[node as usize], &self.nodes[sib as usize]))
+    }
+
+    // grab_idx is grab_pos, but returns the indexes of the nodes in the arena.
+    fn grab_idx(&self, pos: u64) -> Option<(u32, u32)> {
         // Grab the tree that the position is at
-        let (tree, branch_len, bits) = util::detect_offset(pos, self.num_leaves);
+        let (tree, branch_len, _) = util::detect_offset(pos, self.num_leaves);
 
-        match &self.roots {
-            None => (),
-            Some(root) => {
-                if tree as usize >= root.len() {
-                    return None
-                }
+        let mut node = *self.roots.get(tree as usize)?;
+        if branch_len == 0 {
+            return Some((node, node));
+        }
 
-                let node = root[tree as usize].clone();
-                let node_sib = root[tree as usize].clone();
+        // Go down the branch through the siblings of the nodes on the way
+        for depth in 1..branch_len {
+            let lr = (pos >> (branch_len - depth)) & 1;
+            node = self.nodes[node as usize].niece(lr ^ 1)?;
+        }
+
+        let lr = pos & 1;
+        let node = &self.nodes[node as usize];
+        Some((node.niece(lr)?, node.niece(lr ^ 1)?))
+    }
 
-                match grab_niece(node, node_sib, branch_len, bits) {
-                    None => return None,
-                    Some(res) => {
-                        let hnode = HashableNode {
-                            sib: Some(Box::new(res.0.clone() )),
-                            dest: Some(Box::new(res.1.clone() )),
-                            position: pos
-                        };
+    // node_mut returns a mutable reference to the node in the arena. The
+    // chunk it's in is copied first if it's shared.
+    fn node_mut(&mut self, idx: u32) -> &mut PolNode {
+        self.committed.clear();
+        &mut self.nodes[idx as usize]
+    }
 
-                        return Some((res.0, res.1, hnode));
+    // pair_mut returns mutable references to two different nodes in the arena.
+    fn pair_mut(&mut self, a: u32, b: u32) -> (&mut PolNode, &mut PolNode) {
+        self.committed.clear();
+        self.nodes.pair_mut(a as usize, b as usize)
+    }
 
-                    }
-                }
+    // alloc puts the node in the arena and returns its index.
+    fn alloc(&mut self, node: PolNode) -> u32 {
+        match self.free.pop() {
+            Some(idx) => {
+                *self.node_mut(idx) = node;
+                idx
             }
+            None => {
+                self.committed.clear();
+                self.nodes.push(node);
+                (self.nodes.len() - 1) as u32
+            }
+        }
+    }
+
+    // free_subtree frees the node along with its nieces and everything they
+    // point to.
+    fn free_subtree(&mut self, idx: u32) {
+        let mut stack = vec![idx];
+        while let Some(idx) = stack.pop() {
+            let node = self.node_mut(idx);
+            stack.extend(node.l_niece.take());
+            stack.extend(node.r_niece.take());
+
+            self.free.push(idx);
+        }
+    }
+
+    // chop frees the nieces of the node along with everything under them.
+    fn chop(&mut self, idx: u32) {
+        let node = self.node_mut(idx);
+        let nieces = [node.l_niece.take(), node.r_niece.take()];
+
+        for niece in nieces.iter().flatten() {
+            self.free_subtree(*niece);
+        }
+    }
+
+    // prune chops off the nieces if both of them are dead ends. The nieces
+    // are kept as a pair as the one that isn't needed still has the hash
+    // that's needed to prove the other.
+    fn prune(&mut self, idx: u32) {
+        let node = &self.nodes[idx as usize];
+        let l_dead = node.l_niece.map_or(true, |niece| self.nodes[niece as usize].dead_end());
+        let r_dead = node.r_niece.map_or(true, |niece| self.nodes[niece as usize].dead_end());
+
+        if l_dead && r_dead {
+            self.chop(idx)
         }
-        return None
     }
 }
 
-/// PolNode represents a node in the utreexo pollard tree. It points
-/// to its nieces
+/// The Pollard is drawn as the forest it is, with the positions of the nodes
+/// that are there and the start of their hashes under them. Dirty nodes in
+/// lazy mode have stars for a hash. Meant for debugging small Pollards.
+impl fmt::Display for Pollard {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&render::draw(self.num_leaves, |pos| {
+            self.short_hash(pos, 4).map(|hash| vec![hash])
+        }))
+    }
+}
+
+// PollardData is what a Pollard gets serialized as. Only the nodes that are
+// in the trees are kept and they're numbered again, parents before their
+// nieces. The snapshot isn't kept and the leaf index is made again from
+// the remembered leaves.
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+struct PollardData {
+    num_leaves: u64,
+    roots: Vec<u32>,
+    nodes: Vec<PolNode>,
+    leaf_index: bool,
+    lazy: bool,
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for Pollard {
+    fn serialize<S: Serializer>(&self, s: S) -> std::result::Result<S::Ok, S::Error> {
+        let mut nodes: Vec<PolNode> = Vec::with_capacity(self.nodes.len() - self.free.len());
+        let mut roots = Vec::with_capacity(self.roots.len());
+        for root in &self.roots {
+            roots.push(nodes.len() as u32);
+            nodes.push(self.nodes[*root as usize]);
+        }
+
+        // The nieces of each node go at the end and it points to them there
+        let mut i = 0;
+        while i < nodes.len() {
+            if let Some(niece) = nodes[i].l_niece {
+                nodes[i].l_niece = Some(nodes.len() as u32);
+                nodes.push(self.nodes[niece as usize]);
+            }
+            if let Some(niece) = nodes[i].r_niece {
+                nodes[i].r_niece = Some(nodes.len() as u32);
+                nodes.push(self.nodes[niece as usize]);
+            }
+            i += 1;
+        }
+
+        PollardData {
+            num_leaves: self.num_leaves,
+            roots,
+            nodes,
+            leaf_index: self.leaf_index.is_some(),
+            lazy: self.lazy,
+        }.serialize(s)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Pollard {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> std::result::Result<Pollard, D::Error> {
+        use de::Error as _;
+
+        let data = PollardData::deserialize(d)?;
+
+        // Every node needs to be in exactly one of the trees
+        let mut seen = vec![false; data.nodes.len()];
+        let mut stack = data.roots.clone();
+        while let Some(idx) = stack.pop() {
+            match seen.get_mut(idx as usize) {
+                Some(seen) if !*seen => *seen = true,
+                _ => return Err(D::Error::custom(format!("node {} is missing or in a tree twice", idx))),
+            }
+
+            let node = &data.nodes[idx as usize];
+            stack.extend(node.l_niece.iter().chain(node.r_niece.iter()));
+        }
+        if let Some(idx) = seen.iter().position(|seen| !seen) {
+            return Err(D::Error::custom(format!("node {} isn't in any tree", idx)));
+        }
+
+        let mut pol = Pollard {
+            roots: data.roots,
+            num_leaves: data.num_leaves,
+            nodes: data.nodes.into(),
+            lazy: data.lazy,
+            ..Pollard::new()
+        };
+        pol.check_integrity().map_err(D::Error::custom)?;
+
+        if data.leaf_index {
+            let mut index = LeafIndex::default();
+            for (pos, hash) in pol.remembered_leaves() {
+                index.insert(hash, pos);
+            }
+            pol.leaf_index = Some(index);
+        }
+
+        Ok(pol)
+    }
+}
+
+// HashCache holds on to the hashes from committed_hashes. It's behind a
+// Mutex so that the Pollard can still be shared between threads.
+#[derive(Default)]
+struct HashCache(Mutex<Option<Arc<HashMap<u32, sha256::Hash>>>>);
+
+impl Clone for HashCache {
+    fn clone(&self) -> Self {
+        HashCache(Mutex::new(self.get()))
+    }
+}
+
+impl HashCache {
+    fn get(&self) -> Option<Arc<HashMap<u32, sha256::Hash>>> {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn set(&self, hashes: Arc<HashMap<u32, sha256::Hash>>) {
+        *self.0.lock().unwrap() = Some(hashes);
+    }
+
+    fn clear(&mut self) {
+        *self.0.get_mut().unwrap() = None;
+    }
+}
+
+/// PollardSnapshot is the state of a Pollard at the time snapshot was
+/// called on it. It shares its nodes with the Pollard.
 #[derive(Clone)]
+pub struct PollardSnapshot(Pollard);
+
+// LeafIndex maps the hashes of the remembered leaves to their positions and
+// back. The positions are kept sorted so that all the leaves under a node can
+// be found when the node gets swapped.
+#[derive(Clone, Default)]
+struct LeafIndex {
+    positions: SharedMap<sha256::Hash, u64>,
+    leaves: SharedMap<u64, sha256::Hash>,
+}
+
+impl LeafIndex {
+    fn insert(&mut self, hash: sha256::Hash, pos: u64) {
+        self.positions.insert(hash, pos);
+        self.leaves.insert(pos, hash);
+    }
+
+    fn remove(&mut self, pos: u64) {
+        if let Some(hash) = self.leaves.remove(&pos) {
+            self.positions.remove(&hash);
+        }
+    }
+
+    // swap_subtrees moves the leaves under arrow.from to under arrow.to and
+    // the other way around.
+    fn swap_subtrees(&mut self, arrow: &types::Arrow, forest_rows: u8) {
+        let (from, run) = util::subtree_leafrange(arrow.from, forest_rows);
+        let (to, _) = util::subtree_leafrange(arrow.to, forest_rows);
+
+        let moved: Vec<(u64, sha256::Hash)> = self.leaves.range(from, from + run - 1).into_iter()
+            .chain(self.leaves.range(to, to + run - 1))
+            .map(|(pos, hash)| (*pos, *hash))
+            .collect();
+
+        for (pos, _) in &moved {
+            self.leaves.remove(pos);
+        }
+
+        for (pos, hash) in moved {
+            let new_pos = if pos >= from && pos < from + run {
+                pos - from + to
+            } else {
+                pos - to + from
+            };
+
+            self.insert(hash, new_pos);
+        }
+    }
+}
+
+/// PolNode represents a node in the utreexo pollard tree. It points
+/// to its nieces by their index in the arena of the Pollard.
+#[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct PolNode {
     // The hash
     pub data: sha256::Hash,
 
-    pub l_niece: Option<Box<PolNode>>,
-    pub r_niece: Option<Box<PolNode>>,
+    // Whether the leaf should be kept around. Only means something for
+    // the leaves
+    pub remember: bool,
+
+    // Whether the hash is out of date and needs to be done again from the
+    // children. Only set in lazy mode
+    dirty: bool,
+
+    l_niece: Option<u32>,
+    r_niece: Option<u32>,
 }
 
 impl PolNode {
-    /// aunt_op returns the hash of a nodes' nieces. Errors if called on nieces
-    /// that are nil.
-    fn aunt_op(&self) -> sha256::Hash {
-        types::parent_hash(&self.l_niece.as_ref().unwrap().data, &self.r_niece.as_ref().unwrap().data)
+    /// Returns a new PolNode with no nieces
+    pub fn new(data: sha256::Hash, remember: bool) -> PolNode {
+        PolNode { data, remember, dirty: false, l_niece: None, r_niece: None }
     }
 
-    fn dead_end(&self) -> bool {
-        self.l_niece.is_none() && self.r_niece.is_none()
+    // niece returns the left niece for 0 and the right niece for 1
+    fn niece(&self, lr: u64) -> Option<u32> {
+        if lr == 0 {
+            self.l_niece
+        } else {
+            self.r_niece
+        }
     }
 
-    fn chop(&mut self) {
-        self.l_niece = None;
-        self.r_niece = None;
+    // nieces returns both nieces if the node has them
+    fn nieces(&self) -> Option<(u32, u32)> {
+        Some((self.l_niece?, self.r_niece?))
     }
 
-    fn prune(&mut self) {
-        match &mut self.l_niece {
-            None => (),
-            Some(node) =>  {
-                if node.dead_end() {
-                    node.chop()
-                }
-            }
-        }
-
-        match &mut self.r_niece {
-            None => (),
-            Some(node) =>  {
-                if node.dead_end() {
-                    node.chop()
-                }
-            }
-        }
+    // dead_end returns true if the node has no nieces and isn't remembered
+    fn dead_end(&self) -> bool {
+        self.l_niece.is_none() && self.r_niece.is_none() && !self.remember
     }
 }
 
@@ -295,10 +1080,47 @@ fn pol_swap<'a, 'b>(mut a: &'a mut PolNode, mut asib: &'b mut PolNode, mut b: &'
     mem::swap(&mut asib,&mut bsib);
 }
 
+// hash_pairs returns the parent hashes of the pairs. The pairs are split up
+// between the threads if there are enough of them.
+fn hash_pairs(pairs: &[(sha256::Hash, sha256::Hash)], threads: usize) -> Vec<sha256::Hash> {
+    if threads <= 1 || pairs.len() < PARALLEL_REHASH_THRESHOLD {
+        return types::parent_hash_batch(pairs);
+    }
+
+    let chunk_size = (pairs.len() + threads - 1) / threads;
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = pairs.chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || hash_pairs(chunk, 1)))
+            .collect();
+
+        handles.into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect()
+    })
+}
+
+// root_origin returns where the root on the given row of a forest with
+// num_leaves is once the swaps up to and including that row are done. The
+// swaps on the rows above still move it to its final root position.
+fn root_origin(num_leaves: u64, row: u8, swap_rows: &[Vec<types::Arrow>], forest_rows: u8) -> u64 {
+    let mut pos = util::root_position(num_leaves, row, forest_rows);
+
+    // Undo the swaps above the row, the last one first
+    for (arrow_row, swaps) in swap_rows.iter().enumerate().skip(row as usize + 1).rev() {
+        for swap in swaps.iter().rev() {
+            pos ^= transform::swap_if_descendant(swap, pos, arrow_row as u8, row, forest_rows);
+        }
+    }
+
+    pos
+}
+
 #[cfg(test)]
 mod tests {
+    use super::util::{hash_from_u64, Rng};
+
     fn pollard_add_five() {
-        use bitcoin::hashes::{sha256, Hash, HashEngine};
+        use bitcoin_hashes::{sha256, Hash, HashEngine};
         use super::types;
 
         let mut pollard = super::Pollard::new();
@@ -306,39 +1128,39 @@ mod tests {
         for i in 1..5 {
             // boilerplate hashgen
             // TODO maybe there's a better way?
-            let mut engine = bitcoin::hashes::sha256::Hash::engine();
+            let mut engine = bitcoin_hashes::sha256::Hash::engine();
             let num: &[u8; 1] = &[i as u8];
             engine.input(num);
             let h = sha256::Hash::from_engine(engine);
             let leaf = types::Leaf{hash: h, remember: false};
 
             // add one leaf
-            &pollard.modify(vec![leaf], vec![]);
+            pollard.modify(vec![leaf], vec![]).unwrap();
 
             match i {
                 1 => {
-                    check_count(pollard.num_leaves, pollard.roots.clone().unwrap().len());
-                    assert_eq!(pollard.roots.clone().unwrap()[0].data, h);
+                    check_count(pollard.num_leaves, pollard.roots().len());
+                    assert_eq!(pollard.roots()[0], h);
                 }
 
                 2 => {
-                    check_count(pollard.num_leaves, pollard.roots.clone().unwrap().len());
-                    assert_ne!(pollard.roots.clone().unwrap()[0].data, h);
+                    check_count(pollard.num_leaves, pollard.roots().len());
+                    assert_ne!(pollard.roots()[0], h);
                 }
 
                 3 => {
-                    check_count(pollard.num_leaves, pollard.roots.clone().unwrap().len());
-                    assert_eq!(pollard.roots.clone().unwrap()[1].data, h);
+                    check_count(pollard.num_leaves, pollard.roots().len());
+                    assert_eq!(pollard.roots()[1], h);
                 }
 
                 4 => {
-                    check_count(pollard.num_leaves, pollard.roots.clone().unwrap().len());
-                    assert_ne!(pollard.roots.clone().unwrap()[0].data, h);
+                    check_count(pollard.num_leaves, pollard.roots().len());
+                    assert_ne!(pollard.roots()[0], h);
                 }
 
                 5 => {
-                    check_count(pollard.num_leaves, pollard.roots.clone().unwrap().len());
-                    assert_eq!(pollard.roots.clone().unwrap()[1].data, h);
+                    check_count(pollard.num_leaves, pollard.roots().len());
+                    assert_eq!(pollard.roots()[1], h);
                 }
 
                 _ => ()
@@ -356,8 +1178,9 @@ mod tests {
     fn check_root() {
     }
 
+    #[test]
     fn test_pol_del() {
-        use bitcoin::hashes::{sha256, Hash, HashEngine};
+        use bitcoin_hashes::{sha256, Hash, HashEngine};
         use super::types;
 
         let mut pol = super::Pollard::new();
@@ -365,37 +1188,620 @@ mod tests {
         for i in 1..5 {
             // boilerplate hashgen
             // TODO maybe there's a better way?
-            let mut engine = bitcoin::hashes::sha256::Hash::engine();
+            let mut engine = bitcoin_hashes::sha256::Hash::engine();
             let num: &[u8; 1] = &[i as u8];
             engine.input(num);
             let h = sha256::Hash::from_engine(engine);
-            let leaf = types::Leaf{hash: h, remember: false};
+            let leaf = types::Leaf{hash: h, remember: true};
 
             // add one leaf
-            &pol.modify(vec![leaf], vec![]);
+            pol.modify(vec![leaf], vec![]).unwrap();
         }
 
-        pol.modify(vec![], vec![0]);
+        pol.modify(vec![], vec![0]).unwrap();
+        assert_eq!(pol.num_leaves, 3);
+        check_count(pol.num_leaves, pol.roots().len());
+
+        pol.modify(vec![], vec![0, 1, 2]).unwrap();
+        assert_eq!(pol.num_leaves, 0);
+        assert!(pol.roots().is_empty());
+    }
+
+
+    fn leaves_from(hashes: &[bitcoin_hashes::sha256::Hash], remember: bool) -> Vec<super::types::Leaf> {
+        hashes.iter().map(|hash| super::types::Leaf{hash: *hash, remember}).collect()
+    }
+
+    #[test]
+    fn test_pol_modify() {
+        use super::{transform, util};
+
+        let mut rng = Rng::new(0x4f1bbcdcbfa53e0b);
+
+        for num_leaves in 1..70u64 {
+            for _ in 0..5 {
+                let hashes: Vec<_> = (0..num_leaves).map(hash_from_u64).collect();
+                let mut pol = super::Pollard::new();
+                pol.modify(leaves_from(&hashes, true), vec![]).unwrap();
+
+                let dels = rng.dels(num_leaves, 3);
+                let adds: Vec<_> = (0..rng.next() % 10).map(|i| hash_from_u64(1000 + i)).collect();
+
+                pol.modify(leaves_from(&adds, true), dels.clone()).unwrap();
+
+                // Build the same forest from scratch with the leaves in the
+                // order they should end up in after the deletion
+                let forest_rows = util::tree_rows(num_leaves);
+                let positions: Vec<u64> = (0..num_leaves).collect();
+                let moved = transform::transform_positions(&positions, &dels, num_leaves, forest_rows);
+
+                let mut expected: Vec<_> = moved.iter().zip(&hashes)
+                    .filter_map(|(pos, hash)| Some((pos.as_ref()?, *hash)))
+                    .collect();
+                expected.sort_unstable();
+
+                let mut expected: Vec<_> = expected.into_iter().map(|(_, hash)| hash).collect();
+                expected.extend_from_slice(&adds);
+
+                let mut fresh = super::Pollard::new();
+                fresh.modify(leaves_from(&expected, false), vec![]).unwrap();
+
+                assert_eq!(pol.num_leaves, fresh.num_leaves);
+                assert_eq!(pol.roots(), fresh.roots());
+            }
+        }
+    }
+
+    #[test]
+    fn test_pol_prune() {
+        let hashes: Vec<_> = (0..64).map(hash_from_u64).collect();
+
+        // Nothing to remember so only the roots are kept
+        let mut pol = super::Pollard::new();
+        pol.modify(leaves_from(&hashes[..63], false), vec![]).unwrap();
+        for root in &pol.roots {
+            assert!(pol.nodes[*root as usize].nieces().is_none());
+        }
+        assert_eq!(pol.nodes.len() - pol.free.len(), pol.roots.len());
+
+        // Can't delete what isn't there
+        assert_eq!(pol.modify(vec![], vec![5]), Err(super::Error::MissingHash(5)));
+        assert_eq!(pol.num_leaves, 63);
+
+        let mut leaves = leaves_from(&hashes[63..], true);
+        pol.modify(leaves.split_off(0), vec![]).unwrap();
+        assert_eq!(pol.remembered_leaves(), vec![(63, hashes[63])]);
+    }
+
+    #[test]
+    fn test_pol_prove() {
+        let hashes: Vec<_> = (0..103).map(hash_from_u64).collect();
+
+        let mut leaves = leaves_from(&hashes[..100], false);
+        for leaf in leaves.iter_mut().step_by(7) {
+            leaf.remember = true;
+        }
+
+        let mut pol = super::Pollard::new();
+        pol.modify(leaves, vec![]).unwrap();
+
+        let remembered: Vec<_> = hashes[..100].iter().copied().step_by(7).collect();
+        let proof = pol.prove(&remembered).unwrap();
+        assert_eq!(proof.targets, (0..100).step_by(7).collect::<Vec<u64>>());
+        assert!(proof.verify(&remembered, &pol.roots(), pol.num_leaves));
+
+        // The proof still works after the leaves get moved around
+        pol.modify(leaves_from(&hashes[100..], true), vec![0, 21, 49, 70]).unwrap();
+        let remembered: Vec<_> = remembered.into_iter()
+            .filter(|hash| ![0, 21, 49, 70].iter().any(|i| hashes[*i] == *hash))
+            .chain(hashes[100..].iter().copied())
+            .collect();
+
+        let proof = pol.prove(&remembered).unwrap();
+        assert!(proof.verify(&remembered, &pol.roots(), pol.num_leaves));
+
+        // Leaves that weren't remembered can't be proven
+        assert_eq!(pol.prove(&[hashes[8]]), Err(super::Error::LeafNotRemembered(hashes[8])));
+        assert_eq!(pol.prove(&[hashes[21]]), Err(super::Error::LeafNotRemembered(hashes[21])));
+    }
+
+    #[test]
+    fn test_pol_ingest() {
+        let mut rng = Rng::new(0x2f6b4a8d3c1e5f79);
+
+        // full remembers everything and serves the proofs for the compact
+        // one, which doesn't remember anything
+        let mut full = super::Pollard::new();
+        let mut compact = super::Pollard::new();
+
+        let mut next_leaf = 0;
+        for _ in 0..50 {
+            let dels = rng.dels(full.num_leaves, 5);
+            let del_hashes: Vec<_> = dels.iter().map(|pos| full.grab_pos(*pos).unwrap().0.data).collect();
+
+            let proof = full.prove(&del_hashes).unwrap();
+            compact.ingest(&proof, &del_hashes).unwrap();
+
+            let num_adds = rng.next() % 30;
+            let adds: Vec<_> = (next_leaf..next_leaf + num_adds).map(hash_from_u64).collect();
+            next_leaf += num_adds;
+
+            full.modify(leaves_from(&adds, true), dels.clone()).unwrap();
+            compact.modify(leaves_from(&adds, false), dels).unwrap();
+
+            assert_eq!(full.roots(), compact.roots());
+            assert!(compact.remembered_leaves().is_empty());
+            assert_eq!(full.check_integrity(), Ok(()));
+            assert_eq!(compact.check_integrity(), Ok(()));
+
+            // Nothing that got deleted or pruned is left behind in the arena
+            let live = |pol: &super::Pollard| pol.nodes.len() - pol.free.len();
+            assert_eq!(live(&full) as u64, 2 * full.num_leaves - full.roots.len() as u64);
+            assert_eq!(live(&compact), compact.roots.len());
+        }
+
+        // A proof that doesn't match the roots is no good
+        let hash = full.grab_pos(0).unwrap().0.data;
+        let mut proof = full.prove(&[hash]).unwrap();
+        proof.proof[0] = hash;
+        assert_eq!(compact.ingest(&proof, &[hash]), Err(super::Error::InvalidProof));
+    }
+
+    #[test]
+    fn test_pol_snapshot() {
+        let hashes: Vec<_> = (0..1100).map(hash_from_u64).collect();
+
+        let mut pol = super::Pollard::with_leaf_index();
+        pol.modify(leaves_from(&hashes[..1000], true), vec![]).unwrap();
+
+        // A clone shares all of the nodes
+        let copy = pol.clone();
+        let chunks = (pol.nodes.len() + 255) / 256;
+        assert!(chunks > 1);
+        assert_eq!(copy.nodes.shared_chunks(&pol.nodes), chunks);
+
+        let before = pol.snapshot();
+        let leaves = pol.remembered_leaves();
+
+        // A block that gets thrown away. Only the chunks that were changed
+        // got copied
+        pol.modify_by_hash(leaves_from(&hashes[1000..1050], true), &hashes[100..200]).unwrap();
+        let shared = pol.nodes.shared_chunks(&before.0.nodes);
+        assert!(shared > 0 && shared < chunks);
+
+        // A snapshot taken on top of the other one
+        let middle = pol.snapshot();
+        let middle_roots = pol.roots();
+        let middle_leaves = pol.remembered_leaves();
+        pol.set_rehash_threads(4);
+        pol.modify(leaves_from(&hashes[1050..], false), vec![0, 1, 500, 900]).unwrap();
+
+        pol.restore(&middle);
+        assert_eq!(pol.roots(), middle_roots);
+        assert_eq!(pol.remembered_leaves(), middle_leaves);
+        assert_eq!(pol.rehash_threads, 4);
+
+        pol.restore(&before);
+        assert_eq!(pol.roots(), copy.roots());
+        assert_eq!(pol.num_leaves, copy.num_leaves);
+        assert_eq!(pol.remembered_leaves(), leaves);
+        assert_eq!(pol.leaf_positions(&hashes[..1000]), copy.leaf_positions(&hashes[..1000]));
+        assert_eq!(pol.leaf_positions(&hashes[1000..1001]), Err(super::Error::LeafNotRemembered(hashes[1000])));
+
+        // The snapshots didn't change and can be restored again
+        pol.restore(&middle);
+        assert_eq!(pol.roots(), middle_roots);
+        assert_eq!(pol.leaf_positions(&hashes[1000..1001]), middle.0.leaf_positions(&hashes[1000..1001]));
+        assert!(pol.leaf_positions(&hashes[1000..1001]).is_ok());
+        pol.restore(&before);
+
+        // Both end up at the same place after the same block
+        let mut expected = copy;
+        for pol in [&mut pol, &mut expected].iter_mut() {
+            pol.modify_by_hash(leaves_from(&hashes[1000..1010], true), &hashes[300..310]).unwrap();
+        }
+        assert_eq!(pol.roots(), expected.roots());
+        assert_eq!(pol.remembered_leaves(), expected.remembered_leaves());
+        assert_eq!(pol.num_nodes(), expected.num_nodes());
+        assert_eq!(before.0.remembered_leaves(), leaves);
+    }
+
+    #[test]
+    fn test_pol_simulate_modify() {
+        let hashes: Vec<_> = (0..300).map(hash_from_u64).collect();
+
+        let mut full = super::Pollard::new();
+        full.modify(leaves_from(&hashes[..250], true), vec![]).unwrap();
+
+        let mut compact = super::Pollard::new();
+        compact.modify(leaves_from(&hashes[..250], false), vec![]).unwrap();
+
+        let dels = [hashes[3], hashes[64], hashes[65], hashes[200], hashes[249]];
+        let adds = leaves_from(&hashes[250..], true);
+        let proof = full.prove(&dels).unwrap();
+
+        // Nothing changes until the block is actually applied
+        let roots = compact.simulate_modify(&adds, &dels, &proof).unwrap();
+        assert_eq!(compact.num_leaves, 250);
+        assert_eq!(compact.roots(), full.roots());
+        assert_eq!(full.simulate_modify(&adds, &dels, &proof).unwrap(), roots);
+
+        full.modify_by_hash(leaves_from(&hashes[250..], true), &dels).unwrap();
+        assert_eq!(full.roots(), roots);
+
+        // The proof has to match the roots
+        let mut bad_proof = proof.clone();
+        bad_proof.proof[0] = hashes[0];
+        assert_eq!(compact.simulate_modify(&adds, &dels, &bad_proof), Err(super::Error::InvalidProof));
+    }
+
+    #[test]
+    fn test_pol_bulk_add() {
+        let hashes: Vec<_> = (0..200).map(hash_from_u64).collect();
+        let leaves = |range: std::ops::Range<usize>| -> Vec<super::types::Leaf> {
+            range.map(|i| super::types::Leaf{hash: hashes[i], remember: i % 3 == 0}).collect()
+        };
+
+        for existing in [0, 1, 7, 8, 13, 64, 100].iter().copied() {
+            for num_adds in [0, 1, 2, 5, 31, 32, 99].iter().copied() {
+                let end = existing + num_adds;
+
+                let mut bulk = super::Pollard::new();
+                bulk.modify(leaves(0..existing), vec![]).unwrap();
+                bulk.modify(leaves(existing..end), vec![]).unwrap();
+
+                // The same leaves added one at a time
+                let mut single = super::Pollard::new();
+                for i in 0..end {
+                    single.modify(leaves(i..i + 1), vec![]).unwrap();
+                }
+
+                assert_eq!(bulk.num_leaves, single.num_leaves);
+                assert_eq!(bulk.roots(), single.roots());
+                assert_eq!(bulk.remembered_leaves(), single.remembered_leaves());
+                assert_eq!(bulk.nodes.len() - bulk.free.len(), single.nodes.len() - single.free.len());
+            }
+        }
+    }
+
+    #[test]
+    fn test_pol_parallel_rehash() {
+        let hashes: Vec<_> = (0..10000).map(hash_from_u64).collect();
+
+        let pairs: Vec<_> = hashes.chunks(2).map(|pair| (pair[0], pair[1])).collect();
+        assert_eq!(super::hash_pairs(&pairs, 4), super::hash_pairs(&pairs, 1));
+        assert_eq!(super::hash_pairs(&pairs[..7], 4), super::hash_pairs(&pairs[..7], 1));
+
+        let mut single = super::Pollard::new();
+        let mut parallel = super::Pollard::new();
+        parallel.set_rehash_threads(4);
+
+        // Enough deletions for the lower rows to be hashed in parallel
+        let dels: Vec<u64> = (0..10000).filter(|pos| pos % 3 != 1).collect();
+        for pol in [&mut single, &mut parallel].iter_mut() {
+            pol.modify(leaves_from(&hashes, true), vec![]).unwrap();
+            pol.modify(vec![], dels.clone()).unwrap();
+        }
+
+        assert_eq!(parallel.roots(), single.roots());
+        assert_eq!(parallel.remembered_leaves(), single.remembered_leaves());
+    }
+
+    #[test]
+    fn test_pol_lazy() {
+        let hashes: Vec<_> = (0..2000).map(hash_from_u64).collect();
+
+        let mut eager = super::Pollard::with_leaf_index();
+        let mut lazy = super::Pollard::with_leaf_index();
+        lazy.set_lazy(true);
+
+        // Every other leaf is remembered and the blocks spend some of the
+        // remembered ones from the blocks before
+        for block in 0..10 {
+            let adds = &hashes[block * 200..(block + 1) * 200];
+            let dels: Vec<_> = (0..block * 200).step_by(14).map(|i| hashes[i]).collect();
+
+            for pol in [&mut eager, &mut lazy].iter_mut() {
+                let mut leaves = leaves_from(adds, true);
+                leaves.iter_mut().skip(1).step_by(2).for_each(|leaf| leaf.remember = false);
+
+                let dels: Vec<_> = dels.iter().filter(|hash| pol.leaf_positions(&[**hash]).is_ok())
+                    .copied().collect();
+                pol.modify_by_hash(leaves, &dels).unwrap();
+            }
+
+            if block % 3 == 0 {
+                assert_eq!(lazy.roots(), eager.roots());
+            }
+        }
+
+        // Nothing got hashed yet
+        assert!(lazy.roots.iter().any(|root| lazy.nodes[*root as usize].dirty));
+        assert_eq!(lazy.roots(), eager.roots());
+
+        let targets = [hashes[2], hashes[1000], hashes[1998]];
+        assert_eq!(lazy.prove(&targets), eager.prove(&targets));
+
+        // The hashes are kept until something changes
+        let committed = lazy.committed_hashes();
+        assert!(!committed.is_empty());
+        assert!(super::Arc::ptr_eq(&committed, &lazy.committed_hashes()));
+        assert!(lazy.check_integrity().is_ok());
+        assert!(super::Arc::ptr_eq(&committed, &lazy.committed_hashes()));
+
+        let mut changed = lazy.clone();
+        changed.modify_by_hash(vec![], &targets[..1]).unwrap();
+        assert!(!super::Arc::ptr_eq(&committed, &changed.committed_hashes()));
+        assert_ne!(changed.roots(), lazy.roots());
+
+        lazy.commit();
+        assert!(lazy.dirty_hashes().is_empty());
+        assert_eq!(lazy.roots(), eager.roots());
+        assert_eq!(lazy.remembered_leaves(), eager.remembered_leaves());
+        assert_eq!(lazy.prove(&targets), eager.prove(&targets));
+        assert_eq!(lazy.nodes.len() - lazy.free.len(), eager.nodes.len() - eager.free.len());
+
+        // Turning it off hashes what's dirty
+        lazy.modify_by_hash(vec![], &targets[1..2]).unwrap();
+        eager.modify_by_hash(vec![], &targets[1..2]).unwrap();
+        lazy.set_lazy(false);
+        assert!(lazy.dirty_hashes().is_empty());
+        assert_eq!(lazy.roots(), eager.roots());
+    }
+
+    #[test]
+    fn test_pol_max_rows() {
+        // Nothing walks the trees with recursion so a forest as tall as it
+        // gets fits in a small stack
+        let thread = std::thread::Builder::new().stack_size(64 * 1024).spawn(|| {
+            let leaf = hash_from_u64(0);
+            let sibs: Vec<_> = (1..64).map(hash_from_u64).collect();
+            let root = sibs.iter().fold(leaf, |node, sib| super::types::parent_hash(&node, sib));
+
+            let mut pol = super::Pollard::new();
+            let root = pol.alloc(super::PolNode::new(root, false));
+            pol.roots.push(root);
+            pol.num_leaves = 1 << 63;
+
+            let proof = super::BatchProof { targets: vec![0], proof: sibs.clone() };
+            pol.ingest(&proof, &[leaf]).unwrap();
+            assert_eq!(pol.nodes.len(), 1 + 2 * 63);
+
+            // The siblings on the way up are all that's left
+            pol.modify(vec![], vec![0]).unwrap();
+            assert_eq!(pol.roots(), sibs.iter().rev().copied().collect::<Vec<_>>());
+
+            let add = hash_from_u64(64);
+            pol.modify(leaves_from(&[add], true), vec![]).unwrap();
+            assert_eq!(pol.roots.len(), 1);
+            assert_eq!(pol.prove(&[add]).unwrap().proof.len(), 63);
+
+            drop(pol);
+        }).unwrap();
+
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_pol_check_integrity() {
+        let hashes: Vec<_> = (0..20).map(hash_from_u64).collect();
+
+        let mut pol = super::Pollard::new();
+        pol.modify(leaves_from(&hashes, true), vec![]).unwrap();
+        pol.modify(vec![], vec![3, 8, 9, 14]).unwrap();
+        assert_eq!(pol.check_integrity(), Ok(()));
+
+        // 16 leaves in one tree, forest of 4 rows
+        let (leaf, _) = pol.grab_idx(5).unwrap();
+        let mut bad = pol.clone();
+        bad.nodes[leaf as usize].data = hashes[0];
+        assert_eq!(bad.check_integrity(), Err(super::Error::HashMismatch(18)));
+
+        let mut bad = pol.clone();
+        bad.nodes[bad.roots[0] as usize].data = hashes[0];
+        assert_eq!(bad.check_integrity(), Err(super::Error::HashMismatch(30)));
+
+        let mut bad = pol.clone();
+        let (_, sib) = bad.grab_idx(20).unwrap();
+        bad.nodes[sib as usize].r_niece = None;
+        assert_eq!(bad.check_integrity(), Err(super::Error::BadNieces(20)));
+
+        // Leaves can't have children
+        let mut bad = pol.clone();
+        let (_, sib) = bad.grab_idx(4).unwrap();
+        bad.nodes[sib as usize].l_niece = Some(leaf);
+        bad.nodes[sib as usize].r_niece = Some(leaf);
+        assert_eq!(bad.check_integrity(), Err(super::Error::BadNieces(4)));
+
+        let mut bad = pol.clone();
+        bad.roots.pop();
+        assert_eq!(bad.check_integrity(), Err(super::Error::RootCountMismatch { roots: 0, expected: 1 }));
+
+        // Dirty nodes are fine as long as they hash up once committed
+        pol.set_lazy(true);
+        pol.modify(leaves_from(&hashes[..3], false), vec![0, 1, 7]).unwrap();
+        assert_eq!(pol.check_integrity(), Ok(()));
+    }
+
+    #[test]
+    fn test_pol_display() {
+        let hashes: Vec<_> = (0..5).map(hash_from_u64).collect();
+
+        let mut pol = super::Pollard::new();
+        let mut leaves = leaves_from(&hashes, false);
+        leaves[2].remember = true;
+        pol.modify(leaves, vec![]).unwrap();
+        // Only the branch of the remembered leaf is left under the root
+        let expected = "\
+12
+da82
+|-----------\\
+08          09
+c821        c9b3
+            |-----\\
+            02    03    04
+            d86e  35be  f0a0
+";
+        assert_eq!(pol.to_string(), expected);
+
+        let dot = pol.to_dot();
+        assert!(dot.contains("    12 [label=\"12\\nda82b215\"];\n"));
+        assert!(dot.contains("    9 -> 3;\n"));
+        assert!(!dot.contains("8 -> "));
+
+        // Dirty nodes don't have a hash to show yet
+        pol.set_lazy(true);
+        pol.modify(leaves_from(&hashes[..1], false), vec![]).unwrap();
+        assert!(pol.to_string().contains("\nc821        c9b3        ****\n"));
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_pol_serde() {
+        let hashes: Vec<_> = (0..100).map(hash_from_u64).collect();
+
+        let mut pol = super::Pollard::with_leaf_index();
+        let mut leaves = leaves_from(&hashes, true);
+        leaves.iter_mut().step_by(3).for_each(|leaf| leaf.remember = false);
+        pol.modify(leaves, vec![]).unwrap();
+        pol.modify_by_hash(vec![], &[hashes[1], hashes[50], hashes[98]]).unwrap();
+
+        // The freed slots are left out
+        let json = serde_json::to_string(&pol).unwrap();
+        let decoded: super::Pollard = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.nodes.len(), pol.nodes.len() - pol.free.len());
+        assert!(decoded.free.is_empty());
+
+        assert!(json.contains(&format!("\"data\":\"{}\"", hashes[2])));
+        let binary = serde_cbor::to_vec(&pol).unwrap();
+        assert!(binary.windows(32).any(|window| window == &hashes[2][..]));
+
+        for decoded in [decoded, serde_cbor::from_slice(&binary).unwrap()].iter_mut() {
+            assert_eq!(decoded.roots(), pol.roots());
+            assert_eq!(decoded.remembered_leaves(), pol.remembered_leaves());
+            assert_eq!(decoded.prove(&hashes[2..10]), pol.prove(&hashes[2..10]));
+
+            // Both keep working the same way
+            decoded.modify_by_hash(leaves_from(&hashes[..1], true), &hashes[22..24]).unwrap();
+        }
+
+        // Dirty nodes come back dirty
+        pol.set_lazy(true);
+        pol.modify_by_hash(vec![], &hashes[40..42]).unwrap();
+        let mut decoded: super::Pollard = serde_json::from_str(&serde_json::to_string(&pol).unwrap()).unwrap();
+        assert_eq!(decoded.roots(), pol.roots());
+        decoded.commit();
+        pol.commit();
+        assert_eq!(decoded.nodes.len(), pol.nodes.len() - pol.free.len());
+
+        // Nodes that aren't in a tree or are in one twice are no good
+        let mut bad: serde_json::Value = serde_json::from_str(&json).unwrap();
+        bad["nodes"][1]["l_niece"] = bad["nodes"][0]["l_niece"].clone();
+        assert!(serde_json::from_value::<super::Pollard>(bad).is_err());
+
+        let mut bad: serde_json::Value = serde_json::from_str(&json).unwrap();
+        bad["nodes"][5]["data"] = bad["nodes"][6]["data"].clone();
+        assert!(serde_json::from_value::<super::Pollard>(bad).is_err());
+    }
+
+    #[test]
+    fn test_leaf_tracker() {
+        use super::super::observer::LeafTracker;
+
+        let hashes: Vec<_> = (0..30).map(hash_from_u64).collect();
+
+        let mut tracker = LeafTracker::new();
+        tracker.watch(hashes[3]);
+        tracker.watch(hashes[17]);
+        tracker.watch(hashes[28]);
+
+        let mut pol = super::Pollard::new();
+        pol.modify_with_observer(leaves_from(&hashes, true), vec![], &mut tracker).unwrap();
+
+        assert_eq!(tracker.position(&hashes[3]), Some(3));
+        assert_eq!(tracker.position(&hashes[17]), Some(17));
+        assert_eq!(tracker.position(&hashes[28]), Some(28));
+
+        let adds: Vec<_> = (100..104).map(hash_from_u64).collect();
+        tracker.watch(adds[2]);
+        pol.modify_with_observer(leaves_from(&adds, true), vec![0, 1, 5, 17, 20, 29], &mut tracker).unwrap();
+
+        // the spent leaf is gone and the rest can be found where the tracker
+        // says they are
+        assert_eq!(tracker.position(&hashes[17]), None);
+        assert_eq!(tracker.positions().len(), 3);
+        for (hash, pos) in tracker.positions() {
+            let (node, _) = pol.grab_pos(*pos).unwrap();
+            assert_eq!(node.data, *hash);
+        }
+        assert_eq!(tracker.position(&adds[2]), Some(26));
+    }
+
+    #[test]
+    fn test_pol_modify_by_hash() {
+        let mut rng = Rng::new(0x6a09e667f3bcc908);
+
+        // indexed deletes by hash and plain by position. They should end up
+        // with the same leaves in the same places
+        let mut indexed = super::Pollard::with_leaf_index();
+        let mut plain = super::Pollard::new();
+
+        let mut next_leaf = 0;
+        for _ in 0..50 {
+            let leaves = plain.remembered_leaves();
+            let dels: Vec<u64> = leaves.iter()
+                .map(|(pos, _)| *pos)
+                .filter(|_| rng.next() % 4 == 0)
+                .collect();
+            let del_hashes: Vec<_> = dels.iter().map(|pos| plain.grab_pos(*pos).unwrap().0.data).collect();
+
+            let num_adds = rng.next() % 40;
+            let adds: Vec<_> = (next_leaf..next_leaf + num_adds).map(hash_from_u64).collect();
+            next_leaf += num_adds;
+
+            indexed.modify_by_hash(leaves_from(&adds, true), &del_hashes).unwrap();
+            plain.modify(leaves_from(&adds, true), dels).unwrap();
+
+            assert_eq!(indexed.roots(), plain.roots());
+
+            let mut index: Vec<_> = indexed.leaf_index.as_ref().unwrap().leaves.iter()
+                .map(|(pos, hash)| (*pos, *hash))
+                .collect();
+            index.sort();
+            let mut leaves = plain.remembered_leaves();
+            leaves.sort();
+            assert_eq!(index, leaves);
+        }
+
+        // Without the index the positions are looked up the slow way
+        let hash = plain.remembered_leaves()[0].1;
+        assert_eq!(plain.leaf_positions(&[hash]), indexed.leaf_positions(&[hash]));
+
+        // Spent leaves can't be spent again
+        let spent = hash_from_u64(next_leaf);
+        indexed.modify_by_hash(leaves_from(&[spent], true), &[]).unwrap();
+        indexed.modify_by_hash(vec![], &[spent]).unwrap();
+        assert_eq!(indexed.modify_by_hash(vec![], &[spent]), Err(super::Error::LeafNotRemembered(spent)));
     }
 
     #[test]
     fn test_pol_add() {
-        use bitcoin::hashes::{sha256, Hash, HashEngine};
+        use bitcoin_hashes::{sha256, Hash, HashEngine};
         use super::types;
 
         let mut pol = super::Pollard::new();
 
         for i in 0..50000 {
-            let mut engine = bitcoin::hashes::sha256::Hash::engine();
+            let mut engine = bitcoin_hashes::sha256::Hash::engine();
             let num: &[u8; 1] = &[(i % 255) as u8];
             engine.input(num);
             let h = sha256::Hash::from_engine(engine);
             let leaf = types::Leaf{hash: h, remember: false};
 
-            pol.modify(vec![leaf], vec![]);
+            pol.modify(vec![leaf], vec![]).unwrap();
 
             if i % 10000 == 0 {
-                check_count(pol.num_leaves, pol.roots.clone().unwrap().len());
+                check_count(pol.num_leaves, pol.roots().len());
             }
 
             // Check if power of two
@@ -404,35 +1810,35 @@ mod tests {
             //}
         }
 
-        check_count(pol.num_leaves, pol.roots.clone().unwrap().len());
+        check_count(pol.num_leaves, pol.roots().len());
 
         pollard_add_five();
     }
 
     #[test]
     fn test_pol_swap() {
-        use bitcoin::hashes::{sha256, Hash, HashEngine};
+        use bitcoin_hashes::{sha256, Hash, HashEngine};
         use std::mem;
 
-        let mut engine = bitcoin::hashes::sha256::Hash::engine();
+        let mut engine = bitcoin_hashes::sha256::Hash::engine();
         let num: &[u8; 1] = &[1 as u8];
         engine.input(num);
         let h1 = sha256::Hash::from_engine(engine);
         let h1_copy = h1.clone();
 
-        let mut engine1 = bitcoin::hashes::sha256::Hash::engine();
+        let mut engine1 = bitcoin_hashes::sha256::Hash::engine();
         let num2: &[u8; 1] = &[2 as u8];
         engine1.input(num2);
         let h2 = sha256::Hash::from_engine(engine1);
         let h2_copy = h2.clone();
 
-        let mut engine2 = bitcoin::hashes::sha256::Hash::engine();
+        let mut engine2 = bitcoin_hashes::sha256::Hash::engine();
         let num3: &[u8; 1] = &[3 as u8];
         engine2.input(num3);
         let h3 = sha256::Hash::from_engine(engine2);
         let h3_copy = h3.clone();
 
-        let mut engine3 = bitcoin::hashes::sha256::Hash::engine();
+        let mut engine3 = bitcoin_hashes::sha256::Hash::engine();
         let num4: &[u8; 1] = &[3 as u8];
         engine3.input(num4);
         let h4 = sha256::Hash::from_engine(engine3);
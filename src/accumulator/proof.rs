@@ -0,0 +1,481 @@
+// Rustreexo
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use bitcoin_hashes::sha256;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use super::{
+    error::{Error, Result},
+    types,
+    util,
+    transform
+};
+
+/// BatchProof is the inclusion proof for multiple leaves in the accumulator.
+///
+/// The proof holds the hashes of all the nodes needed to hash the targets
+/// up to their roots, except for the ones that can be calculated from the
+/// targets themselves. The hashes are sorted by their position.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct BatchProof {
+    /// Positions of the leaves that are being proven
+    pub targets: Vec<u64>,
+
+    /// Hashes needed to hash the targets up to the roots
+    pub proof: Vec<sha256::Hash>,
+}
+
+impl BatchProof {
+    /// verify returns whether the proof, along with the hashes of the targets,
+    /// hashes up to the given roots. The roots are in the same order as they
+    /// are kept in the Pollard, biggest tree first.
+    pub fn verify(&self, target_hashes: &[sha256::Hash], roots: &[sha256::Hash], num_leaves: u64) -> bool {
+        self.calculate_nodes(target_hashes, roots, num_leaves).is_ok()
+    }
+
+    /// update modifies the proof so that it's valid for the accumulator after
+    /// a block is applied to it. Targets that are spent in the block are dropped
+    /// from the proof, along with their cached hashes. The rest of the targets
+    /// are moved to where they end up after the block and are kept sorted.
+    ///
+    /// cached_hashes are the hashes of the targets of this proof. block_dels
+    /// are the hashes of the targets of the block_proof. roots and num_leaves
+    /// describe the accumulator before the block is applied.
+    pub fn update(
+        &mut self,
+        cached_hashes: &mut Vec<sha256::Hash>,
+        block_adds: &[sha256::Hash],
+        block_dels: &[sha256::Hash],
+        block_proof: &BatchProof,
+        roots: &[sha256::Hash],
+        num_leaves: u64,
+    ) -> Result<()> {
+        let forest_rows = util::tree_rows(num_leaves);
+
+        // Everything that's known about the forest before the block. This
+        // also errors if the targets of either proof aren't distinct leaves,
+        // which the rest of this relies on
+        let mut nodes = self.calculate_nodes(cached_hashes, roots, num_leaves)?;
+        nodes.append(&mut block_proof.calculate_nodes(block_dels, roots, num_leaves)?);
+
+        let dels = &block_proof.targets;
+
+        // Move the targets that don't get spent in this block
+        let moved = transform::transform_positions(&self.targets, dels, num_leaves, forest_rows);
+        let mut targets: Vec<(u64, sha256::Hash)> = moved.into_iter()
+            .zip(cached_hashes.iter().copied())
+            .filter_map(|(pos, hash)| Some((pos?, hash)))
+            .collect();
+
+        let nodes = apply_block(nodes, roots, dels, block_adds, num_leaves)?;
+        let final_n_leaves = num_leaves.checked_sub(dels.len() as u64).ok_or(Error::InvalidTargets)? +
+            block_adds.len() as u64;
+        let final_rows = util::tree_rows(final_n_leaves);
+
+        targets.sort_unstable_by_key(|(pos, _)| *pos);
+        let target_positions: Vec<u64> = targets.iter().map(|(pos, _)| *pos).collect();
+
+        let proof = util::proof_positions(&target_positions, final_n_leaves, final_rows)
+            .into_iter()
+            .map(|pos| nodes.get(&pos).copied().ok_or(Error::MissingHash(pos)))
+            .collect::<Result<Vec<sha256::Hash>>>()?;
+
+        *cached_hashes = targets.iter().map(|(_, hash)| *hash).collect();
+        self.targets = target_positions;
+        self.proof = proof;
+
+        Ok(())
+    }
+
+    // calculate_nodes returns the hashes of all the nodes that the proof
+    // touches, including the ones that were calculated on the way up to the
+    // roots. Errors if the proof doesn't hash up to the given roots.
+    pub(crate) fn calculate_nodes(&self, target_hashes: &[sha256::Hash], roots: &[sha256::Hash], num_leaves: u64) -> Result<BTreeMap<u64, sha256::Hash>> {
+        if self.targets.len() != target_hashes.len() {
+            return Err(Error::TargetCountMismatch {
+                targets: self.targets.len(),
+                hashes: target_hashes.len(),
+            });
+        }
+
+        if roots.len() != num_leaves.count_ones() as usize {
+            return Err(Error::InvalidProof);
+        }
+
+        check_targets(&self.targets, num_leaves)?;
+
+        let forest_rows = util::tree_rows(num_leaves);
+        let positions = util::proof_positions(&self.targets, num_leaves, forest_rows);
+
+        if positions.len() != self.proof.len() {
+            return Err(Error::ProofCountMismatch {
+                positions: positions.len(),
+                hashes: self.proof.len(),
+            });
+        }
+
+        let mut nodes = BTreeMap::new();
+        for (pos, hash) in self.targets.iter().zip(target_hashes) {
+            nodes.insert(*pos, *hash);
+        }
+        nodes.extend(positions.into_iter().zip(self.proof.iter().copied()));
+
+        hash_up(&mut nodes, num_leaves, forest_rows);
+
+        let root_positions = util::get_roots_reverse(num_leaves, forest_rows);
+        for (pos, root) in root_positions.iter().rev().zip(roots) {
+            if let Some(hash) = nodes.get(pos) {
+                if hash != root {
+                    return Err(Error::InvalidProof);
+                }
+            }
+        }
+
+        Ok(nodes)
+    }
+}
+
+// check_targets errors if a target isn't a leaf of a forest with num_leaves
+// or is there more than once. A repeated target would let one of its hashes
+// go unchecked. The targets don't need to be sorted as each of them goes
+// with its own hash.
+pub(crate) fn check_targets(targets: &[u64], num_leaves: u64) -> Result<()> {
+    if targets.iter().any(|pos| *pos >= num_leaves) {
+        return Err(Error::InvalidTargets);
+    }
+
+    let mut sorted = targets.to_vec();
+    sorted.sort_unstable();
+    if sorted.windows(2).any(|pair| pair[0] == pair[1]) {
+        return Err(Error::InvalidTargets);
+    }
+
+    Ok(())
+}
+
+// apply_block moves the known nodes of a forest with num_leaves to where
+// they are after the block is applied and returns them. The roots are known
+// too. Nodes that had a deleted leaf under them are hashed again from their
+// children if both of them are known. The adds are hashed up the same way
+// the Pollard does it. Errors if the dels aren't distinct leaves.
+pub(crate) fn apply_block(
+    mut nodes: BTreeMap<u64, sha256::Hash>,
+    roots: &[sha256::Hash],
+    dels: &[u64],
+    adds: &[sha256::Hash],
+    num_leaves: u64,
+) -> Result<BTreeMap<u64, sha256::Hash>> {
+    check_targets(dels, num_leaves)?;
+    let forest_rows = util::tree_rows(num_leaves);
+
+    let root_positions = util::get_roots_reverse(num_leaves, forest_rows);
+    for (pos, root) in root_positions.into_iter().rev().zip(roots) {
+        nodes.insert(pos, *root);
+    }
+
+    // Move the nodes. Nodes that got changed are dropped
+    let positions: Vec<u64> = nodes.keys().copied().collect();
+    let moved = transform::transform_positions(&positions, dels, num_leaves, forest_rows);
+    let mut nodes: BTreeMap<u64, sha256::Hash> = moved.into_iter()
+        .zip(nodes.into_values())
+        .filter_map(|(pos, hash)| Some((pos?, hash)))
+        .collect();
+
+    // Anything left outside of the forest is what was deleted. Hash
+    // back up the nodes that were changed
+    let next_n_leaves = num_leaves.checked_sub(dels.len() as u64).ok_or(Error::InvalidTargets)?;
+    nodes.retain(|pos, _| util::in_forest(*pos, next_n_leaves, forest_rows));
+    hash_up(&mut nodes, next_n_leaves, forest_rows);
+
+    // The forest may get taller with the adds
+    let final_n_leaves = next_n_leaves + adds.len() as u64;
+    let final_rows = util::tree_rows(final_n_leaves);
+    if final_rows != forest_rows {
+        nodes = nodes.into_iter()
+            .map(|(pos, hash)| (remap(pos, forest_rows, final_rows), hash))
+            .collect();
+    }
+
+    // Every time a root is hit, the new node gets hashed with it
+    for (i, add) in adds.iter().enumerate() {
+        let leaves = next_n_leaves + i as u64;
+        let mut pos = leaves;
+        nodes.insert(pos, *add);
+
+        let mut row = 0;
+        while (leaves >> row) & 1 == 1 {
+            let left = nodes.get(&(pos ^ 1)).ok_or(Error::MissingHash(pos ^ 1))?;
+            let parent = types::parent_hash(left, &nodes[&pos]);
+
+            pos = util::parent(pos, final_rows);
+            nodes.insert(pos, parent);
+            row += 1;
+        }
+    }
+
+    Ok(nodes)
+}
+
+// hash_up calculates the parents of all the nodes that have their sibling
+// present, going up row by row. Nodes that are already present are kept
+// as is.
+fn hash_up(nodes: &mut BTreeMap<u64, sha256::Hash>, num_leaves: u64, forest_rows: u8) {
+    for row in 0..forest_rows {
+        let mut parents = Vec::new();
+
+        for (pos, hash) in nodes.range(row_range(row, forest_rows)) {
+            // only look at the left siblings
+            if pos & 1 == 1 || util::is_root_position(*pos, num_leaves, forest_rows) {
+                continue
+            }
+
+            if let Some(sib) = nodes.get(&(pos | 1)) {
+                let parent = util::parent(*pos, forest_rows);
+                if !nodes.contains_key(&parent) {
+                    parents.push((parent, types::parent_hash(hash, sib)));
+                }
+            }
+        }
+
+        nodes.extend(parents);
+    }
+}
+
+// remap translates a position in a forest with from_rows to the position of
+// the same node in a forest with to_rows.
+fn remap(pos: u64, from_rows: u8, to_rows: u8) -> u64 {
+    let row = util::detect_row(pos, from_rows);
+    pos - util::row_offset(row, from_rows) + util::row_offset(row, to_rows)
+}
+
+// row_range returns the range of positions that the given row covers
+fn row_range(row: u8, forest_rows: u8) -> Range<u64> {
+    let start = util::row_offset(row, forest_rows);
+    if row < forest_rows {
+        start..util::row_offset(row + 1, forest_rows)
+    } else {
+        start..start + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use bitcoin_hashes::sha256;
+
+    use super::super::{transform, util};
+    use super::super::util::{hash_from_u64, Rng};
+    use super::BatchProof;
+
+    // Forest keeps every node so that the proofs can be checked against
+    // something simple.
+    struct Forest {
+        leaves: Vec<sha256::Hash>,
+        nodes: BTreeMap<u64, sha256::Hash>,
+        roots: Vec<sha256::Hash>,
+    }
+
+    impl Forest {
+        fn new(leaves: Vec<sha256::Hash>) -> Forest {
+            let num_leaves = leaves.len() as u64;
+            let forest_rows = util::tree_rows(num_leaves);
+
+            let mut nodes: BTreeMap<u64, sha256::Hash> = leaves.iter().copied()
+                .enumerate()
+                .map(|(pos, hash)| (pos as u64, hash))
+                .collect();
+            super::hash_up(&mut nodes, num_leaves, forest_rows);
+
+            let roots = util::get_roots_reverse(num_leaves, forest_rows).iter()
+                .rev()
+                .map(|pos| nodes[pos])
+                .collect();
+
+            Forest { leaves, nodes, roots }
+        }
+
+        fn num_leaves(&self) -> u64 {
+            self.leaves.len() as u64
+        }
+
+        fn prove(&self, targets: &[u64]) -> (BatchProof, Vec<sha256::Hash>) {
+            let forest_rows = util::tree_rows(self.num_leaves());
+            let proof = util::proof_positions(targets, self.num_leaves(), forest_rows).iter()
+                .map(|pos| self.nodes[pos])
+                .collect();
+            let hashes = targets.iter().map(|pos| self.leaves[*pos as usize]).collect();
+
+            (BatchProof { targets: targets.to_vec(), proof }, hashes)
+        }
+
+        // modify deletes and then adds, moving the leaves around the same
+        // way the transform says they should.
+        fn modify(&self, adds: &[sha256::Hash], dels: &[u64]) -> Forest {
+            let forest_rows = util::tree_rows(self.num_leaves());
+            let swap_rows = transform::transform(dels.to_vec(), self.num_leaves(), forest_rows);
+
+            let mut leaves: Vec<Option<sha256::Hash>> = (0..1u64 << forest_rows).map(|pos| {
+                if pos < self.num_leaves() && !dels.contains(&pos) {
+                    Some(self.leaves[pos as usize])
+                } else {
+                    None
+                }
+            }).collect();
+
+            for (row, swaps) in swap_rows.iter().enumerate() {
+                let row = row as u8;
+                for swap in swaps {
+                    let from = (swap.from - util::row_offset(row, forest_rows)) << row;
+                    let to = (swap.to - util::row_offset(row, forest_rows)) << row;
+
+                    for i in 0..1 << row {
+                        leaves.swap((from + i) as usize, (to + i) as usize);
+                    }
+                }
+            }
+
+            let mut leaves: Vec<sha256::Hash> = leaves.into_iter()
+                .take((self.num_leaves() - dels.len() as u64) as usize)
+                .map(|leaf| leaf.unwrap())
+                .collect();
+            leaves.extend_from_slice(adds);
+
+            Forest::new(leaves)
+        }
+    }
+
+    #[test]
+    fn test_proof_verify() {
+        for num_leaves in 1..64 {
+            let forest = Forest::new((0..num_leaves).map(hash_from_u64).collect());
+
+            for target in 0..num_leaves {
+                let targets = vec![target, (target * 7) % num_leaves, num_leaves - 1];
+                let mut targets_sorted = targets.clone();
+                targets_sorted.sort_unstable();
+                targets_sorted.dedup();
+
+                let (proof, hashes) = forest.prove(&targets_sorted);
+                assert!(proof.verify(&hashes, &forest.roots, num_leaves));
+
+                // a wrong target hash shouldn't verify
+                let mut bad_hashes = hashes.clone();
+                bad_hashes[0] = hash_from_u64(num_leaves + 1);
+                assert!(!proof.verify(&bad_hashes, &forest.roots, num_leaves));
+
+                // neither should a wrong proof hash
+                if !proof.proof.is_empty() {
+                    let mut bad_proof = proof.clone();
+                    bad_proof.proof[0] = hash_from_u64(num_leaves + 1);
+                    assert!(!bad_proof.verify(&hashes, &forest.roots, num_leaves));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_proof_update() {
+        let mut rng = Rng::new(0x9e3779b97f4a7c15);
+        let mut next_leaf = 0;
+
+        for num_leaves in 1..80u64 {
+            for _ in 0..10 {
+                let leaves: Vec<sha256::Hash> = (next_leaf..next_leaf + num_leaves).map(hash_from_u64).collect();
+                next_leaf += num_leaves;
+                let forest = Forest::new(leaves);
+
+                let mut targets = Vec::new();
+                let mut dels = Vec::new();
+                for pos in 0..num_leaves {
+                    if rng.next() % 4 == 0 {
+                        targets.push(pos);
+                    }
+                    if rng.next() % 3 == 0 {
+                        dels.push(pos);
+                    }
+                }
+
+                let num_adds = rng.next() % 20;
+                let adds: Vec<sha256::Hash> = (next_leaf..next_leaf + num_adds).map(hash_from_u64).collect();
+                next_leaf += num_adds;
+
+                let (mut proof, mut cached_hashes) = forest.prove(&targets);
+                let (block_proof, block_dels) = forest.prove(&dels);
+
+                proof.update(&mut cached_hashes, &adds, &block_dels, &block_proof, &forest.roots, num_leaves).unwrap();
+
+                let new_forest = forest.modify(&adds, &dels);
+                assert!(proof.verify(&cached_hashes, &new_forest.roots, new_forest.num_leaves()));
+
+                // the proof should be the same as a fresh one for the leaves
+                // that weren't spent
+                let mut expected_targets: Vec<u64> = targets.iter()
+                    .filter(|pos| !dels.contains(pos))
+                    .map(|pos| {
+                        let hash = forest.leaves[*pos as usize];
+                        new_forest.leaves.iter().position(|leaf| *leaf == hash).unwrap() as u64
+                    })
+                    .collect();
+                expected_targets.sort_unstable();
+
+                let (expected, expected_hashes) = new_forest.prove(&expected_targets);
+                assert_eq!(proof, expected);
+                assert_eq!(cached_hashes, expected_hashes);
+            }
+        }
+    }
+
+    #[test]
+    fn test_proof_update_bad_block_proof() {
+        let forest = Forest::new((0..8).map(hash_from_u64).collect());
+
+        let (mut proof, mut cached_hashes) = forest.prove(&[1, 6]);
+        let (block_proof, mut block_dels) = forest.prove(&[2]);
+        block_dels[0] = hash_from_u64(100);
+
+        let err = proof.update(&mut cached_hashes, &[], &block_dels, &block_proof, &forest.roots, 8);
+        assert_eq!(err, Err(super::Error::InvalidProof));
+    }
+
+    #[test]
+    fn test_proof_update_repeated_dels() {
+        let forest = Forest::new((0..4).map(hash_from_u64).collect());
+
+        let (mut proof, mut cached_hashes) = forest.prove(&[0]);
+        let (block_proof, block_dels) = forest.prove(&[1, 2, 3]);
+        let before = (proof.clone(), cached_hashes.clone());
+
+        // More dels than there are leaves once the repeats are counted
+        let repeated = BatchProof { targets: vec![1, 1, 2, 2, 3], proof: block_proof.proof.clone() };
+        let dels = [block_dels[0], block_dels[0], block_dels[1], block_dels[1], block_dels[2]];
+        let err = proof.update(&mut cached_hashes, &[], &dels, &repeated, &forest.roots, 4);
+        assert_eq!(err, Err(super::Error::InvalidTargets));
+        assert_eq!((proof, cached_hashes), before);
+    }
+
+    #[test]
+    fn test_proof_bad_targets() {
+        let forest = Forest::new((0..8).map(hash_from_u64).collect());
+        let (proof, hashes) = forest.prove(&[1]);
+
+        // The first hash of a repeated target would never be checked
+        let repeated = BatchProof { targets: vec![1, 1], proof: proof.proof.clone() };
+        let bogus = [hash_from_u64(100), hashes[0]];
+        assert!(!repeated.verify(&bogus, &forest.roots, 8));
+        assert_eq!(repeated.calculate_nodes(&bogus, &forest.roots, 8), Err(super::Error::InvalidTargets));
+
+        let outside = BatchProof { targets: vec![8], proof: proof.proof.clone() };
+        assert!(!outside.verify(&hashes, &forest.roots, 8));
+
+        // Unsorted targets are fine as long as the hashes are in the same order
+        let (proof, hashes) = forest.prove(&[6, 1]);
+        assert!(proof.verify(&hashes, &forest.roots, 8));
+        assert!(!proof.verify(&[hashes[1], hashes[0]], &forest.roots, 8));
+    }
+}
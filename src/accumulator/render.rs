@@ -0,0 +1,225 @@
+// Rustreexo
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+use super::{types, util};
+
+/// draw_positions draws the positions of a forest with the given number of
+/// leaves, the same way as the diagram on util::row_offset.
+pub fn draw_positions(num_leaves: u64) -> String {
+    let forest_rows = util::tree_rows(num_leaves);
+    draw(num_leaves, |pos| {
+        if util::in_forest(pos, num_leaves, forest_rows) {
+            Some(Vec::new())
+        } else {
+            None
+        }
+    })
+}
+
+/// draw_arrows draws the forest before the arrows of a transform are applied
+/// to it. Where a subtree gets moved to is written under the position it
+/// gets moved from. The arrows are the ones returned by transform for the
+/// same number of leaves.
+pub fn draw_arrows(num_leaves: u64, swap_rows: &[Vec<types::Arrow>]) -> String {
+    let forest_rows = util::tree_rows(num_leaves);
+    let digits = position_digits(forest_rows);
+
+    let moves: HashMap<u64, u64> = swap_rows.iter().flatten()
+        .map(|arrow| (arrow.from, arrow.to))
+        .collect();
+    let targets: Vec<u64> = moves.values().copied().collect();
+
+    draw(num_leaves, |pos| {
+        if !util::in_forest(pos, num_leaves, forest_rows) && !targets.contains(&pos) {
+            return None
+        }
+
+        Some(moves.get(&pos).map(|to| vec![format!(">{:0w$}", to, w = digits)]).unwrap_or_default())
+    })
+}
+
+// draw lays out the nodes of a forest with the given number of leaves from
+// the top row down. node returns the lines that go under the position of
+// the node, or None if the node isn't there. The lines to the children are
+// drawn when both of them are there.
+//
+// The diagram is as wide as the bottom row of the forest so it's only good
+// for small ones.
+pub(crate) fn draw(num_leaves: u64, node: impl Fn(u64) -> Option<Vec<String>>) -> String {
+    if num_leaves == 0 {
+        return String::new();
+    }
+
+    let forest_rows = util::tree_rows(num_leaves);
+    let digits = position_digits(forest_rows);
+
+    // The nodes that are there on each row, by their position
+    let rows: Vec<BTreeMap<u64, Vec<String>>> = (0..=forest_rows)
+        .map(|row| {
+            let offset = util::row_offset(row, forest_rows);
+            (offset..offset + (1 << (forest_rows - row)))
+                .filter_map(|pos| node(pos).map(|lines| (pos, lines)))
+                .collect()
+        })
+        .collect();
+
+    // Every cell on the bottom row is as wide as the widest label plus a gap
+    let label_width = rows.iter().flat_map(|row| row.values().flatten())
+        .map(|line| line.chars().count())
+        .max()
+        .unwrap_or(0);
+    let width = digits.max(label_width) + 2;
+
+    let mut out = Vec::new();
+    for row in (0..=forest_rows).rev() {
+        let nodes = &rows[row as usize];
+        let offset = util::row_offset(row, forest_rows);
+        let span = width << row;
+        let column = |pos: u64| (pos - offset) as usize * span;
+
+        let num_lines = 1 + nodes.values().map(|lines| lines.len()).max().unwrap_or(0);
+        for line in 0..num_lines {
+            let mut text = String::new();
+            for (pos, lines) in nodes {
+                let cell = match line {
+                    0 => format!("{:0w$}", pos, w = digits),
+                    _ => lines.get(line - 1).cloned().unwrap_or_default(),
+                };
+                pad(&mut text, column(*pos));
+                text.push_str(&cell);
+            }
+            out.push(text);
+        }
+
+        if row == 0 {
+            continue
+        }
+
+        let mut text = String::new();
+        for pos in nodes.keys() {
+            let left = util::child(*pos, forest_rows);
+            let below = &rows[row as usize - 1];
+            if below.contains_key(&left) && below.contains_key(&(left | 1)) {
+                pad(&mut text, column(*pos));
+                write!(text, "|{}\\", "-".repeat(span / 2 - 1)).unwrap();
+            }
+        }
+        out.push(text);
+    }
+
+    let mut drawing = String::new();
+    for line in out.iter().map(|line| line.trim_end()).filter(|line| !line.is_empty()) {
+        drawing.push_str(line);
+        drawing.push('\n');
+    }
+
+    drawing
+}
+
+// dot writes the nodes of a forest with the given number of leaves as a
+// Graphviz digraph with the edges going from the parents to the children.
+// nodes are the positions of the nodes that are there with the labels that
+// go under them, so it takes time in the number of them and not in the size
+// of the forest.
+pub(crate) fn dot(num_leaves: u64, nodes: &BTreeMap<u64, String>) -> String {
+    let forest_rows = util::tree_rows(num_leaves);
+
+    let mut out = String::from("digraph forest {\n");
+    for (pos, label) in nodes {
+        writeln!(out, "    {} [label=\"{}\\n{}\"];", pos, pos, label).unwrap();
+    }
+
+    for pos in nodes.keys() {
+        if util::detect_row(*pos, forest_rows) == 0 {
+            continue
+        }
+
+        let left = util::child(*pos, forest_rows);
+        for child in [left, left | 1].iter().filter(|child| nodes.contains_key(child)) {
+            writeln!(out, "    {} -> {};", pos, child).unwrap();
+        }
+    }
+    out.push_str("}\n");
+
+    out
+}
+
+// position_digits returns how many digits the biggest position of a forest
+// with the given rows has.
+fn position_digits(forest_rows: u8) -> usize {
+    util::row_offset(forest_rows, forest_rows).to_string().len()
+}
+
+// pad adds spaces to the end of the text until it's at the given column.
+// There's always at least one space between two things on the same line.
+fn pad(text: &mut String, column: usize) {
+    let len = text.chars().count();
+    let spaces = if len == 0 { column } else { column.saturating_sub(len).max(1) };
+    text.extend(std::iter::repeat(' ').take(spaces));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{transform, util};
+
+    #[test]
+    fn test_draw_positions() {
+        let expected = "\
+14
+|---------------\\
+12              13
+|-------\\       |-------\\
+08      09      10      11
+|---\\   |---\\   |---\\   |---\\
+00  01  02  03  04  05  06  07
+";
+        assert_eq!(super::draw_positions(8), expected);
+
+        // Only what's in the forest is drawn
+        let expected = "\
+12
+|-------\\
+08      09
+|---\\   |---\\
+00  01  02  03  04
+";
+        assert_eq!(super::draw_positions(5), expected);
+        assert_eq!(super::draw_positions(0), "");
+    }
+
+    #[test]
+    fn test_draw_arrows() {
+        let forest_rows = util::tree_rows(8);
+        let swap_rows = transform::transform(vec![1, 4], 8, forest_rows);
+
+        // 5 moves into the deleted 1 and 11 becomes the root of row 1
+        let expected = "\
+14
+|-------------------\\
+12                  13
+|---------\\         |---------\\
+08        09        10        11
+                              >10
+|----\\    |----\\    |----\\    |----\\
+00   01   02   03   04   05   06   07
+                         >01
+";
+        assert_eq!(super::draw_arrows(8, &swap_rows), expected);
+    }
+
+    #[test]
+    fn test_dot() {
+        let nodes = (0..7).filter(|pos| *pos != 3).map(|pos| (pos, format!("n{}", pos))).collect();
+        let dot = super::dot(4, &nodes);
+        assert!(dot.starts_with("digraph forest {\n"));
+        assert!(dot.contains("    6 [label=\"6\\nn6\"];\n"));
+        assert!(dot.contains("    6 -> 4;\n"));
+        assert!(dot.contains("    4 -> 0;\n"));
+        assert!(dot.contains("    5 -> 2;\n"));
+        assert!(!dot.contains("5 -> 3"));
+        assert!(!dot.contains("    3 ["));
+        assert!(dot.ends_with("}\n"));
+    }
+}
@@ -0,0 +1,493 @@
+// Rustreexo
+
+use std::mem;
+use std::ops::{Index, IndexMut};
+use std::sync::Arc;
+
+use bitcoin_hashes::{sha256, Hash};
+
+// Number of bits of the index that pick the element within a chunk
+const CHUNK_BITS: usize = 8;
+const CHUNK_SIZE: usize = 1 << CHUNK_BITS;
+
+// Number of bits of the radix that pick the child of a branch
+const MAP_BITS: u32 = 4;
+const FANOUT: usize = 1 << MAP_BITS;
+
+// Number of levels it takes to go through all 64 bits of the radix
+const MAX_HEIGHT: u32 = 64 / MAP_BITS;
+
+// Number of entries a leaf of the map holds before it's split up
+const LEAF_SIZE: usize = 32;
+
+/// SharedVec is a Vec whose elements are kept in chunks behind Arcs, so
+/// cloning it only copies a pointer for every 256 elements. The clones share
+/// the chunks until one of them changes; a chunk is copied the first time
+/// it's changed while it's shared.
+pub struct SharedVec<T> {
+    chunks: Vec<Arc<Vec<T>>>,
+    len: usize,
+}
+
+impl<T> Clone for SharedVec<T> {
+    fn clone(&self) -> Self {
+        SharedVec { chunks: self.chunks.clone(), len: self.len }
+    }
+}
+
+impl<T> Default for SharedVec<T> {
+    fn default() -> Self {
+        SharedVec { chunks: Vec::new(), len: 0 }
+    }
+}
+
+impl<T: Clone> SharedVec<T> {
+    /// new returns an empty SharedVec
+    pub fn new() -> Self {
+        SharedVec::default()
+    }
+
+    /// len returns the number of elements
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// is_empty returns true if there are no elements
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// get returns the element at the index if there is one
+    pub fn get(&self, idx: usize) -> Option<&T> {
+        self.chunks.get(idx >> CHUNK_BITS)?.get(idx & (CHUNK_SIZE - 1))
+    }
+
+    /// get_mut returns the element at the index if there is one. Its chunk
+    /// is copied first if it's shared.
+    pub fn get_mut(&mut self, idx: usize) -> Option<&mut T> {
+        let chunk = self.chunks.get_mut(idx >> CHUNK_BITS)?;
+        Arc::make_mut(chunk).get_mut(idx & (CHUNK_SIZE - 1))
+    }
+
+    /// pair_mut returns the elements at two different indexes
+    pub fn pair_mut(&mut self, a: usize, b: usize) -> (&mut T, &mut T) {
+        assert_ne!(a, b, "can't borrow the same element twice");
+        assert!(a < self.len && b < self.len, "index out of bounds");
+
+        let (a_chunk, b_chunk) = (a >> CHUNK_BITS, b >> CHUNK_BITS);
+        let (a, b) = (a & (CHUNK_SIZE - 1), b & (CHUNK_SIZE - 1));
+        if a_chunk == b_chunk {
+            return pair_mut(&mut Arc::make_mut(&mut self.chunks[a_chunk])[..], a, b);
+        }
+
+        let (a_chunk, b_chunk) = pair_mut(&mut self.chunks, a_chunk, b_chunk);
+        (&mut Arc::make_mut(a_chunk)[a], &mut Arc::make_mut(b_chunk)[b])
+    }
+
+    /// push adds the element to the end
+    pub fn push(&mut self, value: T) {
+        if self.len % CHUNK_SIZE == 0 {
+            self.chunks.push(Arc::new(Vec::with_capacity(CHUNK_SIZE)));
+        }
+
+        let last = self.chunks.last_mut().unwrap();
+        Arc::make_mut(last).push(value);
+        self.len += 1;
+    }
+
+    /// pop takes the last element off if there is one
+    pub fn pop(&mut self) -> Option<T> {
+        let last = self.chunks.last_mut()?;
+        let value = Arc::make_mut(last).pop();
+        if last.is_empty() {
+            self.chunks.pop();
+        }
+
+        self.len -= 1;
+        value
+    }
+
+    /// iter returns the elements in order
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.chunks.iter().flat_map(|chunk| chunk.iter())
+    }
+
+    /// shared_chunks returns how many chunks are the same ones as in other
+    pub fn shared_chunks(&self, other: &SharedVec<T>) -> usize {
+        self.chunks.iter().zip(other.chunks.iter())
+            .filter(|(a, b)| Arc::ptr_eq(a, b))
+            .count()
+    }
+}
+
+impl<T: Clone> From<Vec<T>> for SharedVec<T> {
+    fn from(values: Vec<T>) -> Self {
+        SharedVec {
+            len: values.len(),
+            chunks: values.chunks(CHUNK_SIZE).map(|chunk| Arc::new(chunk.to_vec())).collect(),
+        }
+    }
+}
+
+impl<T: Clone> Index<usize> for SharedVec<T> {
+    type Output = T;
+
+    fn index(&self, idx: usize) -> &T {
+        self.get(idx).expect("index out of bounds")
+    }
+}
+
+impl<T: Clone> IndexMut<usize> for SharedVec<T> {
+    fn index_mut(&mut self, idx: usize) -> &mut T {
+        self.get_mut(idx).expect("index out of bounds")
+    }
+}
+
+// pair_mut returns mutable references to two different elements of the slice.
+fn pair_mut<T>(slice: &mut [T], a: usize, b: usize) -> (&mut T, &mut T) {
+    if a < b {
+        let (left, right) = slice.split_at_mut(b);
+        (&mut left[a], &mut right[0])
+    } else {
+        let (left, right) = slice.split_at_mut(a);
+        (&mut right[0], &mut left[b])
+    }
+}
+
+/// RadixKey is a key of a SharedMap. The radix picks where the key goes in
+/// the map and has to sort the same way as the keys do, but different keys
+/// can have the same radix.
+pub trait RadixKey: Ord + Clone {
+    fn radix(&self) -> u64;
+}
+
+impl RadixKey for u64 {
+    fn radix(&self) -> u64 {
+        *self
+    }
+}
+
+impl RadixKey for sha256::Hash {
+    fn radix(&self) -> u64 {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&self.into_inner()[..8]);
+        u64::from_be_bytes(bytes)
+    }
+}
+
+/// SharedMap is a sorted map that's kept as a trie of Arcs, 4 bits of the
+/// radix of the keys at a time. Cloning it only copies the pointer to the
+/// top of the trie and the clones share the rest until one of them changes.
+/// A change copies the nodes on the way to the key that changed.
+pub struct SharedMap<K, V> {
+    root: Arc<MapNode<K, V>>,
+
+    // Number of levels of branches there can be above the leaves. The radix
+    // of every key fits in MAP_BITS bits for each of them
+    height: u32,
+    len: usize,
+}
+
+// MapNode is either a leaf with the entries sorted by their key or a branch
+// with a child for each value of the next MAP_BITS bits of the radix.
+#[derive(Clone)]
+enum MapNode<K, V> {
+    Leaf(Vec<(K, V)>),
+    Branch(Vec<Option<Arc<MapNode<K, V>>>>),
+}
+
+impl<K, V> Clone for SharedMap<K, V> {
+    fn clone(&self) -> Self {
+        SharedMap { root: self.root.clone(), height: self.height, len: self.len }
+    }
+}
+
+impl<K, V> Default for SharedMap<K, V> {
+    fn default() -> Self {
+        SharedMap { root: Arc::new(MapNode::Leaf(Vec::new())), height: 0, len: 0 }
+    }
+}
+
+impl<K: RadixKey, V: Clone> SharedMap<K, V> {
+    /// new returns an empty SharedMap
+    pub fn new() -> Self {
+        SharedMap::default()
+    }
+
+    /// len returns the number of entries
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// is_empty returns true if there are no entries
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// get returns the value of the key if it's in the map
+    pub fn get(&self, key: &K) -> Option<&V> {
+        let radix = key.radix();
+        if !self.fits(radix) {
+            return None;
+        }
+
+        let mut node = &*self.root;
+        let mut level = self.height;
+        loop {
+            match node {
+                MapNode::Leaf(entries) => {
+                    let i = entries.binary_search_by(|(k, _)| k.cmp(key)).ok()?;
+                    return Some(&entries[i].1);
+                }
+                MapNode::Branch(children) => {
+                    node = children[digit(radix, level)].as_deref()?;
+                    level -= 1;
+                }
+            }
+        }
+    }
+
+    /// insert puts the key in the map and returns the value it had before
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let radix = key.radix();
+        while !self.fits(radix) {
+            if let MapNode::Branch(_) = *self.root {
+                let mut children = vec![None; FANOUT];
+                children[0] = Some(self.root.clone());
+                self.root = Arc::new(MapNode::Branch(children));
+            }
+            self.height += 1;
+        }
+
+        let old = insert(&mut self.root, self.height, radix, key, value);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    /// remove takes the key out of the map and returns its value
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        // Nothing gets copied if the key isn't there
+        self.get(key)?;
+
+        self.len -= 1;
+        remove(&mut self.root, self.height, key.radix(), key)
+    }
+
+    /// range returns the entries whose keys have a radix from lo up to and
+    /// including hi, sorted by their key.
+    pub fn range(&self, lo: u64, hi: u64) -> Vec<(&K, &V)> {
+        let mut entries = Vec::new();
+        collect(&self.root, self.height, 0, lo, hi, &mut entries);
+        entries
+    }
+
+    /// iter returns all the entries sorted by their key
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.range(0, u64::MAX).into_iter()
+    }
+
+    // fits returns true if the radix is under the top of the trie.
+    fn fits(&self, radix: u64) -> bool {
+        self.height >= MAX_HEIGHT || radix >> (MAP_BITS * self.height) == 0
+    }
+}
+
+// digit returns which child of a branch on the given level the radix goes
+// under.
+fn digit(radix: u64, level: u32) -> usize {
+    (radix >> (MAP_BITS * (level - 1))) as usize & (FANOUT - 1)
+}
+
+// insert puts the key under the node, which is on the given level. Leaves
+// that get too big are split up unless they're on the bottom level.
+fn insert<K: RadixKey, V: Clone>(node: &mut Arc<MapNode<K, V>>, level: u32, radix: u64, key: K, value: V) -> Option<V> {
+    let node = Arc::make_mut(node);
+    let entries = match node {
+        MapNode::Branch(children) => {
+            let child = children[digit(radix, level)]
+                .get_or_insert_with(|| Arc::new(MapNode::Leaf(Vec::new())));
+            return insert(child, level - 1, radix, key, value);
+        }
+        MapNode::Leaf(entries) => entries,
+    };
+
+    match entries.binary_search_by(|(k, _)| k.cmp(&key)) {
+        Ok(i) => return Some(mem::replace(&mut entries[i].1, value)),
+        Err(i) => entries.insert(i, (key, value)),
+    }
+
+    if entries.len() > LEAF_SIZE && level > 0 {
+        // The entries stay sorted as they're pushed in order
+        let mut children: Vec<Option<Arc<MapNode<K, V>>>> = vec![None; FANOUT];
+        for (key, value) in mem::take(entries) {
+            let child = children[digit(key.radix(), level)]
+                .get_or_insert_with(|| Arc::new(MapNode::Leaf(Vec::new())));
+            if let MapNode::Leaf(entries) = Arc::make_mut(child) {
+                entries.push((key, value));
+            }
+        }
+        *node = MapNode::Branch(children);
+    }
+
+    None
+}
+
+// remove takes the key out from under the node, which is on the given level.
+// Leaves that end up empty are taken off their branch.
+fn remove<K: RadixKey, V: Clone>(node: &mut Arc<MapNode<K, V>>, level: u32, radix: u64, key: &K) -> Option<V> {
+    match Arc::make_mut(node) {
+        MapNode::Leaf(entries) => {
+            let i = entries.binary_search_by(|(k, _)| k.cmp(key)).ok()?;
+            Some(entries.remove(i).1)
+        }
+        MapNode::Branch(children) => {
+            let d = digit(radix, level);
+            let value = remove(children[d].as_mut()?, level - 1, radix, key);
+            if matches!(children[d].as_deref(), Some(MapNode::Leaf(entries)) if entries.is_empty()) {
+                children[d] = None;
+            }
+            value
+        }
+    }
+}
+
+// collect adds the entries under the node whose radix is from lo up to and
+// including hi. The radixes under the node all start with prefix.
+fn collect<'a, K: RadixKey, V>(node: &'a MapNode<K, V>, level: u32, prefix: u64, lo: u64, hi: u64,
+                               entries: &mut Vec<(&'a K, &'a V)>) {
+    match node {
+        MapNode::Leaf(leaf) => {
+            entries.extend(leaf.iter()
+                .filter(|(key, _)| (lo..=hi).contains(&key.radix()))
+                .map(|(key, value)| (key, value)));
+        }
+        MapNode::Branch(children) => {
+            let shift = MAP_BITS * (level - 1);
+            for (d, child) in children.iter().enumerate() {
+                let child_lo = prefix | (d as u64) << shift;
+                let child_hi = child_lo + ((1 << shift) - 1);
+                if let Some(child) = child {
+                    if child_lo <= hi && child_hi >= lo {
+                        collect(child, level - 1, child_lo, lo, hi, entries);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use bitcoin_hashes::{sha256, Hash};
+
+    use super::super::util::{hash_from_u64, Rng};
+
+    #[test]
+    fn test_shared_vec() {
+        let mut vec = super::SharedVec::new();
+        let mut expected = Vec::new();
+        for i in 0..1000u32 {
+            vec.push(i);
+            expected.push(i);
+        }
+
+        let copy = vec.clone();
+        assert_eq!(vec.shared_chunks(&copy), 4);
+
+        // Only the chunks that change get copied
+        vec[3] = 1003;
+        *vec.pair_mut(4, 5).1 = 1005;
+        let (a, b) = vec.pair_mut(999, 6);
+        *a = 1999;
+        *b = 1006;
+        assert_eq!(vec.shared_chunks(&copy), 2);
+        assert_eq!(copy.iter().copied().collect::<Vec<_>>(), expected);
+
+        expected[3] = 1003;
+        expected[5] = 1005;
+        expected[6] = 1006;
+        expected[999] = 1999;
+        assert_eq!(vec.iter().copied().collect::<Vec<_>>(), expected);
+
+        for _ in 0..300 {
+            assert_eq!(vec.pop(), expected.pop());
+        }
+        assert_eq!(vec.len(), 700);
+        assert_eq!(vec.get(700), None);
+        assert_eq!(copy.len(), 1000);
+
+        let from: super::SharedVec<u32> = expected.clone().into();
+        assert_eq!(from.iter().copied().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn test_shared_map() {
+        let mut rng = Rng::new(7);
+        let mut map = super::SharedMap::new();
+        let mut expected = BTreeMap::new();
+        let mut copies = Vec::new();
+
+        for i in 0..5000 {
+            let key = match i % 3 {
+                0 => rng.next() % 100,
+                1 => rng.next() % 10_000,
+                _ => rng.next(),
+            };
+
+            if rng.next() % 4 == 0 {
+                assert_eq!(map.remove(&key), expected.remove(&key));
+            } else {
+                assert_eq!(map.insert(key, i), expected.insert(key, i));
+            }
+
+            if i % 1000 == 0 {
+                copies.push((map.clone(), expected.clone()));
+            }
+        }
+
+        // The copies didn't change along with the map
+        copies.push((map, expected));
+        for (map, expected) in copies {
+            assert_eq!(map.len(), expected.len());
+            let entries: Vec<_> = map.iter().map(|(k, v)| (*k, *v)).collect();
+            assert_eq!(entries, expected.iter().map(|(k, v)| (*k, *v)).collect::<Vec<_>>());
+
+            let range: Vec<_> = map.range(50, 5000).into_iter().map(|(k, v)| (*k, *v)).collect();
+            assert_eq!(range, expected.range(50..=5000).map(|(k, v)| (*k, *v)).collect::<Vec<_>>());
+
+            for key in expected.keys() {
+                assert_eq!(map.get(key), expected.get(key));
+            }
+            assert_eq!(map.get(&u64::MAX), None);
+        }
+    }
+
+    #[test]
+    fn test_shared_map_hashes() {
+        // The hashes all have the same radix so they end up in one leaf
+        let hashes: Vec<sha256::Hash> = (0..100u8).map(|i| {
+            let mut bytes = [0; 32];
+            bytes[31] = i;
+            sha256::Hash::from_inner(bytes)
+        }).collect();
+
+        let mut map = super::SharedMap::new();
+        for (i, hash) in hashes.iter().enumerate() {
+            map.insert(*hash, i);
+            map.insert(hash_from_u64(i as u64), i);
+        }
+
+        for (i, hash) in hashes.iter().enumerate() {
+            assert_eq!(map.get(hash), Some(&i));
+            assert_eq!(map.get(&hash_from_u64(i as u64)), Some(&i));
+        }
+        assert_eq!(map.range(0, 0).len(), 100);
+        assert_eq!(map.remove(&hashes[5]), Some(5));
+        assert_eq!(map.get(&hashes[5]), None);
+        assert_eq!(map.len(), 199);
+    }
+}
@@ -0,0 +1,179 @@
+// Rustreexo
+
+use alloc::vec::Vec;
+
+use bitcoin_hashes::sha256;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use super::{
+    error::{Error, Result},
+    proof::{self, BatchProof},
+    util,
+};
+
+/// Stump is the accumulator with only its roots. It can't prove anything
+/// but it can check proofs and apply blocks with them, which is all that's
+/// needed to verify. Unlike the Pollard it doesn't need std.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Stump {
+    /// Hashes of the roots, biggest tree first
+    pub roots: Vec<sha256::Hash>,
+
+    /// Total number of leaves in the accumulator
+    pub num_leaves: u64,
+}
+
+impl Stump {
+    /// Returns a new empty stump
+    pub fn new() -> Stump {
+        Stump::default()
+    }
+
+    /// verify returns whether the proof proves that the leaves with the given
+    /// hashes are in the accumulator.
+    pub fn verify(&self, proof: &BatchProof, del_hashes: &[sha256::Hash]) -> bool {
+        proof.verify(del_hashes, &self.roots, self.num_leaves)
+    }
+
+    /// modify deletes the targets of the proof, which have the hashes in
+    /// dels, and then adds the adds. Errors without changing anything if the
+    /// proof doesn't prove the dels.
+    pub fn modify(&mut self, adds: &[sha256::Hash], dels: &[sha256::Hash], proof: &BatchProof) -> Result<()> {
+        let nodes = proof.calculate_nodes(dels, &self.roots, self.num_leaves)?;
+        let nodes = proof::apply_block(nodes, &self.roots, &proof.targets, adds, self.num_leaves)?;
+
+        let num_leaves = self.num_leaves.checked_sub(proof.targets.len() as u64).ok_or(Error::InvalidTargets)? +
+            adds.len() as u64;
+        let forest_rows = util::tree_rows(num_leaves);
+
+        let roots = util::get_roots_reverse(num_leaves, forest_rows).into_iter().rev()
+            .map(|pos| nodes.get(&pos).copied().ok_or(Error::MissingHash(pos)))
+            .collect::<Result<Vec<sha256::Hash>>>()?;
+
+        self.roots = roots;
+        self.num_leaves = num_leaves;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{error::Error, proof::BatchProof, types};
+    use super::super::util::hash_from_u64;
+
+    #[test]
+    fn test_stump_add() {
+        let hashes: Vec<_> = (0..3).map(hash_from_u64).collect();
+
+        let mut stump = super::Stump::new();
+        stump.modify(&hashes, &[], &BatchProof::default()).unwrap();
+
+        assert_eq!(stump.num_leaves, 3);
+        assert_eq!(stump.roots, vec![types::parent_hash(&hashes[0], &hashes[1]), hashes[2]]);
+    }
+
+    #[test]
+    fn test_stump_bad_proof() {
+        let hashes: Vec<_> = (0..4).map(hash_from_u64).collect();
+
+        let mut stump = super::Stump::new();
+        stump.modify(&hashes, &[], &BatchProof::default()).unwrap();
+
+        let proof = BatchProof {
+            targets: vec![1],
+            proof: vec![hashes[0], types::parent_hash(&hashes[2], &hashes[3])],
+        };
+        assert!(stump.verify(&proof, &hashes[1..2]));
+
+        // Nothing changes if the proof is for something else
+        let before = stump.clone();
+        assert!(!stump.verify(&proof, &hashes[2..3]));
+        assert_eq!(stump.modify(&[], &hashes[2..3], &proof), Err(Error::InvalidProof));
+        assert_eq!(stump, before);
+
+        stump.modify(&[], &hashes[1..2], &proof).unwrap();
+        assert_eq!(stump.num_leaves, 3);
+    }
+
+    #[test]
+    fn test_stump_bad_targets() {
+        let hashes: Vec<_> = (0..2).map(hash_from_u64).collect();
+
+        let mut stump = super::Stump::new();
+        stump.modify(&hashes, &[], &BatchProof::default()).unwrap();
+        let before = stump.clone();
+
+        // Repeats that add up to more targets than leaves, and targets that
+        // aren't leaves, are errors and not an overflow
+        let repeated = BatchProof { targets: vec![0, 0, 1], proof: vec![] };
+        let dels = [hashes[0], hashes[0], hashes[1]];
+        assert_eq!(stump.modify(&[], &dels, &repeated), Err(Error::InvalidTargets));
+
+        let outside = BatchProof { targets: vec![0, 1, 2], proof: vec![] };
+        assert_eq!(stump.modify(&[], &[hashes[0], hashes[1], hashes[0]], &outside), Err(Error::InvalidTargets));
+        assert_eq!(stump, before);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_stump_serde() {
+        let hashes: Vec<_> = (0..4).map(hash_from_u64).collect();
+
+        let mut stump = super::Stump::new();
+        stump.modify(&hashes, &[], &BatchProof::default()).unwrap();
+
+        let json = serde_json::to_string(&stump).unwrap();
+        assert_eq!(json, format!("{{\"roots\":[\"{}\"],\"num_leaves\":4}}", stump.roots[0]));
+        assert_eq!(serde_json::from_str::<super::Stump>(&json).unwrap(), stump);
+
+        let proof = BatchProof { targets: vec![1], proof: vec![hashes[0], hashes[3]] };
+        let binary = serde_cbor::to_vec(&proof).unwrap();
+        assert_eq!(serde_cbor::from_slice::<BatchProof>(&binary).unwrap(), proof);
+
+        let json = serde_json::to_string(&proof).unwrap();
+        assert_eq!(json, format!("{{\"targets\":[1],\"proof\":[\"{}\",\"{}\"]}}", hashes[0], hashes[3]));
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_stump_matches_pollard() {
+        use super::super::pollard::Pollard;
+        use super::super::util::Rng;
+
+        let mut rng = Rng::new(0x7c3a9e15d2b46f08);
+
+        let mut pollard = Pollard::new();
+        let mut stump = super::Stump::new();
+
+        // Everything is remembered so the Pollard can serve the proofs
+        let mut live = Vec::new();
+        let mut next_leaf = 0;
+        for _ in 0..50 {
+            let mut del_hashes = Vec::new();
+            live.retain(|hash| {
+                let spent = rng.next() % 4 == 0;
+                if spent {
+                    del_hashes.push(*hash);
+                }
+                !spent
+            });
+            let proof = pollard.prove(&del_hashes).unwrap();
+
+            let num_adds = rng.next() % 40;
+            let adds: Vec<_> = (next_leaf..next_leaf + num_adds).map(hash_from_u64).collect();
+            next_leaf += num_adds;
+            live.extend(adds.iter().copied());
+
+            stump.modify(&adds, &del_hashes, &proof).unwrap();
+
+            let leaves = adds.iter().map(|hash| types::Leaf { hash: *hash, remember: true }).collect();
+            pollard.modify(leaves, proof.targets).unwrap();
+
+            assert_eq!(stump.roots, pollard.roots());
+            assert_eq!(stump.num_leaves, pollard.num_leaves);
+        }
+    }
+}
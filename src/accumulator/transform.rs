@@ -1,23 +1,30 @@
 // Rustreexo
 
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+
 use super::types;
 use super::util;
 
 /// transform is the function used for re-organzing Utreexo tree. Given a vector
-/// of positions to be deleted, it returns a
+/// of positions to be deleted, it returns a vector of arrows for each row.
+/// The arrows of a row move whole subtrees and are meant to be applied
+/// row by row, starting from the bottom.
 pub fn transform(mut dels: Vec<u64>, num_leaves: u64, forest_rows: u8) -> Vec<Vec<types::Arrow>> {
+    dels.sort_unstable();
+    dels.dedup();
+
     let next_n_leaves = num_leaves - dels.len() as u64;
 
     let mut swaps: Vec<Vec<types::Arrow>> = Vec::with_capacity(forest_rows as usize);
-    let mut collapses: Vec<Vec<types::Arrow>> = Vec::with_capacity(forest_rows as usize);
+    let mut collapses: Vec<Option<types::Arrow>> = Vec::with_capacity(forest_rows as usize);
 
     for row in 0..forest_rows {
         let mut root_present = num_leaves&(1<<row) != 0;
-        println!("Is root present: {}", root_present);
         let root_pos = util::root_position(num_leaves, row, forest_rows);
 
         // Does root exist. And is the last element in the root position
-        if root_present && dels.last().unwrap() == &root_pos {
+        if root_present && dels.last() == Some(&root_pos) {
             dels.pop();
 
             // this is the same as running num_leaves&(1<<row) != 0; again
@@ -27,149 +34,172 @@ pub fn transform(mut dels: Vec<u64>, num_leaves: u64, forest_rows: u8) -> Vec<Ve
 
         let del_remain = dels.len()%2 != 0;
 
-        // Twin
-        let mut twin_nextdels = util::extract_twins(dels.clone(), forest_rows);
-        dels = twin_nextdels.1;
+        // Twin. The parents of the twins are deleted on the next row and the
+        // twins themselves don't need to be swapped
+        let (mut next_dels, twins) = util::extract_twins(dels.clone(), forest_rows);
+        dels.retain(|del| twins.binary_search(del).is_err());
 
-        swaps[row as usize] = make_swaps(dels.clone(), del_remain, root_present, root_pos);
+        swaps.push(make_swaps(&dels, del_remain, root_present, root_pos));
 
-        collapses[row as usize] = vec!(make_collapse(dels.clone(), del_remain, root_present, next_n_leaves, num_leaves, row, forest_rows).unwrap());
+        collapses.push(make_collapse(&dels, del_remain, root_present, next_n_leaves, num_leaves, row, forest_rows));
 
-        let mut swap_nextdels = makeswap_nextdels(dels.clone(), del_remain, root_present, forest_rows);
-twin_nextdels.0.append(&mut swap_nextdels);
-        twin_nextdels.0.dedup();
-        twin_nextdels.0.sort();
+        let mut swap_nextdels = makeswap_nextdels(&dels, del_remain, root_present, forest_rows);
+        next_dels.append(&mut swap_nextdels);
+        next_dels.sort_unstable();
+        next_dels.dedup();
 
-        dels = twin_nextdels.0;
+        dels = next_dels;
     }
 
-    swap_collapses(&mut swaps, &mut collapses, forest_rows);
+    swap_collapses(&swaps, &mut collapses, forest_rows);
 
-    let collapse_iter = collapses.into_iter().enumerate();
-    for collapse in collapse_iter {
-        if collapse.0 == 1 && collapse.1[0].from != collapse.1[0].to {
-            swaps[collapse.0 as usize].append(&mut vec!(collapse.1[0]));
+    // The collapses are placed at the end of the row they happen on
+    for (row, collapse) in collapses.into_iter().enumerate() {
+        if let Some(collapse) = collapse {
+            if collapse.from != collapse.to {
+                swaps[row].push(collapse);
+            }
         }
     }
 
-    return swaps;
+    swaps
 }
 
-fn make_swaps(mut dels: Vec<u64>, del_remain: bool, root_present: bool, root_pos: u64) -> Vec<types::Arrow> {
-    let n_swaps;
-if del_remain && root_present {
-        n_swaps = (dels.len() >> 1) + 1;
-    } else {
-        n_swaps = dels.len() >> 1;
+/// transform_positions returns where each of the given positions ends up
+/// after the dels are removed from the forest. The positions can be on any
+/// row. A position comes back as None if the node is gone: either it was
+/// deleted, a deleted leaf was under it, or a part of its subtree was moved
+/// away so that the node no longer exists as it was.
+pub fn transform_positions(positions: &[u64], dels: &[u64], num_leaves: u64, forest_rows: u8) -> Vec<Option<u64>> {
+    let swap_rows = transform(dels.to_vec(), num_leaves, forest_rows);
+
+    // The deleted leaves and everything above them
+    let mut gone = BTreeSet::new();
+    for del in dels {
+        for rise in 0..=forest_rows {
+            gone.insert(util::n_grandparent(*del, rise, forest_rows).unwrap());
+        }
     }
 
-    let mut row_swaps: Vec<types::Arrow> = Vec::with_capacity(n_swaps);
+    positions.iter().map(|pos| {
+        if gone.contains(pos) {
+            return None
+        }
 
-    let mut i = 0;
-    while dels.len() > 1 {
-        row_swaps[i] = types::Arrow{from: dels[1] ^ 1, to: dels[0]};
+        let row = util::detect_row(*pos, forest_rows);
+        let mut pos = *pos;
+
+        for (swap_row, swaps) in swap_rows.iter().enumerate() {
+            let swap_row = swap_row as u8;
+
+            for swap in swaps {
+                if swap_row < row {
+                    // Something under the node got swapped in or out
+                    let rise = row - swap_row;
+                    if util::n_grandparent(swap.from, rise, forest_rows) == Ok(pos) ||
+                        util::n_grandparent(swap.to, rise, forest_rows) == Ok(pos) {
+                        return None
+                    }
+                } else {
+                    pos ^= swap_if_descendant(swap, pos, swap_row, row, forest_rows);
+                }
+            }
+        }
 
-        let to_keep = dels.len() - 2;
-        dels.truncate(to_keep);
-        i += 1;
+        Some(pos)
+    }).collect()
+}
+
+fn make_swaps(dels: &[u64], del_remain: bool, root_present: bool, root_pos: u64) -> Vec<types::Arrow> {
+    let mut row_swaps: Vec<types::Arrow> = Vec::with_capacity((dels.len() >> 1) + 1);
+
+    // the sibling of the second del in the pair is moved into the first del
+    for pair in dels.chunks_exact(2) {
+        row_swaps.push(types::Arrow{from: pair[1] ^ 1, to: pair[0]});
     }
 
-    // last swap
+    // last swap. The root fills in the remaining del
     if del_remain && root_present {
-        row_swaps[i] = types::Arrow{from: root_pos, to: dels[0]}
+        row_swaps.push(types::Arrow{from: root_pos, to: dels[dels.len() - 1]});
     }
 
-    return row_swaps;
+    row_swaps
 }
 
-fn make_collapse(mut dels: Vec<u64>, del_remain: bool, root_present: bool, next_n_leaves: u64, n_leaves: u64, row: u8, forest_rows: u8) -> Option<types::Arrow> {
+fn make_collapse(dels: &[u64], del_remain: bool, root_present: bool, next_n_leaves: u64, n_leaves: u64, row: u8, forest_rows: u8) -> Option<types::Arrow> {
     let root_dest = util::root_position(next_n_leaves, row, forest_rows);
 
     if !del_remain && root_present {
+        // the root stays a root but may need to move over
         let root_src = util::root_position(n_leaves, row, forest_rows);
-        return Some(types::Arrow{from: root_src, to: root_dest});
+        Some(types::Arrow{from: root_src, to: root_dest})
 
     } else if del_remain && !root_present {
-        let root_src = dels.pop().unwrap() ^ 1;
-        return Some(types::Arrow{from: root_src, to: root_dest});
+        // sibling of the remaining del becomes the root of this row
+        let root_src = dels[dels.len() - 1] ^ 1;
+        Some(types::Arrow{from: root_src, to: root_dest})
 
     } else {
         None
     }
 }
 
-fn makeswap_nextdels(mut dels: Vec<u64>, del_remain: bool, root_present: bool, forest_rows: u8) -> Vec<u64> {
-    let n_swaps;
-
-    if del_remain && root_present {
-        n_swaps = (dels.len() >> 1) + 1;
-    } else {
-        n_swaps = dels.len() >> 1;
-    }
-
-    let mut swap_nextdels: Vec<u64> = Vec::with_capacity(n_swaps);
+fn makeswap_nextdels(dels: &[u64], del_remain: bool, root_present: bool, forest_rows: u8) -> Vec<u64> {
+    let mut swap_nextdels: Vec<u64> = Vec::with_capacity((dels.len() >> 1) + 1);
 
-    let mut i = 0;
-    while dels.len() > 1 {
-        swap_nextdels[i] = util::parent(dels[1], forest_rows);
-
-        let to_keep = dels.len() - 2;
-        dels.truncate(to_keep);
-        i += 1;
+    // the parent of the second del is left empty after the swap
+    for pair in dels.chunks_exact(2) {
+        swap_nextdels.push(util::parent(pair[1], forest_rows));
     }
 
-    // last swap
-    if del_remain && root_present {
-        swap_nextdels[i] = util::parent(dels[0], forest_rows);
+    // the sibling of the remaining del turned into a root, so the
+    // deletion promotes to the next row
+    if del_remain && !root_present {
+        swap_nextdels.push(util::parent(dels[dels.len() - 1], forest_rows));
     }
 
-    return swap_nextdels;
+    swap_nextdels
 }
 
-fn swap_collapses(swaps: &mut Vec<Vec<types::Arrow>>, collapses: &mut Vec<Vec<types::Arrow>>, forest_rows: u8) {
-    if collapses.len() == 0 {
+// swap_collapses adjusts the destinations of the collapses so that they
+// end up in the right place after all the arrows in the rows above them are
+// applied. Goes from the top row down so that the collapses that are used
+// to adjust the lower ones are already adjusted themselves.
+fn swap_collapses(swaps: &[Vec<types::Arrow>], collapses: &mut [Option<types::Arrow>], forest_rows: u8) {
+    if collapses.is_empty() {
         return
     }
 
-    let mut rows = collapses.len();
-
-    // For all the collapses, go through all of them except for the root
-    while rows != 0 {
-        for swap in &swaps[rows] {
-            swap_inrow(swap, collapses, rows as u8, forest_rows);
+    for row in (1..collapses.len()).rev() {
+        // Arrows on a row are applied swaps first, then the collapse. Undo
+        // them in the opposite order
+        if let Some(rowcol) = collapses[row] {
+            swap_inrow(&rowcol, collapses, row as u8, forest_rows);
         }
 
-        if collapses.len() == 0 {
-            continue
+        for swap in &swaps[row] {
+            swap_inrow(swap, collapses, row as u8, forest_rows);
         }
-
-        let rowcol = collapses[rows][0].clone();
-        swap_inrow(&rowcol, collapses, rows as u8, forest_rows);
-
-        rows -= 1;
     }
 }
 
-fn swap_inrow(s: &types::Arrow, collapses: &mut Vec<Vec<types::Arrow>>, row: u8, forest_rows: u8) {
-    let mut cr = 0;
-
-    while cr < row {
-        if collapses[cr as usize].len() == 0 {
-            continue
+fn swap_inrow(s: &types::Arrow, collapses: &mut [Option<types::Arrow>], row: u8, forest_rows: u8) {
+    for cr in 0..row {
+        if let Some(collapse) = &mut collapses[cr as usize] {
+            let mask = swap_if_descendant(s, collapse.to, row, cr, forest_rows);
+            collapse.to ^= mask;
         }
-
-        let mask = swap_if_descendant(s, &collapses[cr as usize][0], row, cr, forest_rows);
-        collapses[cr as usize][0].to ^= mask;
-
-        cr += 1;
     }
 }
 
-fn swap_if_descendant(a: &types::Arrow, b: &types::Arrow, ar: u8, br: u8, forest_rows: u8) -> u64 {
+/// swap_if_descendant returns the mask to xor the position b with if b is
+/// under either end of the arrow a. Returns 0 if the arrow doesn't move b.
+/// ar is the row of the arrow and br is the row of b.
+pub fn swap_if_descendant(a: &types::Arrow, b: u64, ar: u8, br: u8, forest_rows: u8) -> u64 {
     // ar=row of a, br=row of b, fr=forest_row
     let hdiff = ar - br;
 
-    let b_up = util::n_grandparent(b.to, hdiff, forest_rows).unwrap();
+    let b_up = util::n_grandparent(b, hdiff, forest_rows).unwrap();
 
     let mut sub_mask = 0;
     if (b_up == a.from) != (b_up == a.to) {
@@ -177,6 +207,134 @@ fn swap_if_descendant(a: &types::Arrow, b: &types::Arrow, ar: u8, br: u8, forest
         sub_mask = root_mask << hdiff;
     }
 
-    return sub_mask;
+    sub_mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{types, util};
+    use super::super::util::Rng;
+
+    // apply_arrows moves the leaves around as if each arrow was a swap of the
+    // two subtrees it points to.
+    fn apply_arrows(leaves: &mut [Option<u64>], swap_rows: &[Vec<types::Arrow>], forest_rows: u8) {
+        for (row, swaps) in swap_rows.iter().enumerate() {
+            let row = row as u8;
+            for swap in swaps {
+                let from = (swap.from - util::row_offset(row, forest_rows)) << row;
+                let to = (swap.to - util::row_offset(row, forest_rows)) << row;
+
+                for i in 0..1 << row {
+                    leaves.swap((from + i) as usize, (to + i) as usize);
+                }
+            }
+        }
+    }
 
+    // check_transform deletes the given leaves and checks that whatever is
+    // left over is packed at the start of the forest.
+    fn check_transform(num_leaves: u64, dels: Vec<u64>) {
+        let forest_rows = util::tree_rows(num_leaves);
+        let swap_rows = super::transform(dels.clone(), num_leaves, forest_rows);
+
+        let mut leaves: Vec<Option<u64>> = (0..1u64 << forest_rows).map(|i| {
+            if i < num_leaves && !dels.contains(&i) {
+                Some(i)
+            } else {
+                None
+            }
+        }).collect();
+
+        apply_arrows(&mut leaves, &swap_rows, forest_rows);
+
+        let next_n_leaves = (num_leaves - dels.len() as u64) as usize;
+        for (i, leaf) in leaves.iter().enumerate() {
+            assert_eq!(leaf.is_some(), i < next_n_leaves,
+                "leaves {} dels {:?} ended up as {:?}", num_leaves, dels, leaves);
+        }
+    }
+
+    #[test]
+    fn test_transform_single() {
+        for num_leaves in 1..40 {
+            for del in 0..num_leaves {
+                check_transform(num_leaves, vec![del]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_transform_pairs() {
+        for num_leaves in 2..40 {
+            for a in 0..num_leaves {
+                for b in a + 1..num_leaves {
+                    check_transform(num_leaves, vec![a, b]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_transform_many() {
+        let mut rng = Rng::new(0x2545f4914f6cdd1d);
+        for num_leaves in 1..200u64 {
+            for _ in 0..20 {
+                check_transform(num_leaves, rng.dels(num_leaves, 3));
+            }
+        }
+    }
+
+    #[test]
+    fn test_transform_positions() {
+        let mut rng = Rng::new(0x853c49e6748fea9b);
+        for num_leaves in 1..100u64 {
+            let forest_rows = util::tree_rows(num_leaves);
+            let dels = rng.dels(num_leaves, 4);
+
+            let mut leaves: Vec<Option<u64>> = (0..1u64 << forest_rows).map(|i| {
+                if i < num_leaves && !dels.contains(&i) {
+                    Some(i)
+                } else {
+                    None
+                }
+            }).collect();
+
+            let swap_rows = super::transform(dels.clone(), num_leaves, forest_rows);
+            apply_arrows(&mut leaves, &swap_rows, forest_rows);
+
+            // every node in the forest, all the rows
+            let positions: Vec<u64> = (0..(2 << forest_rows) - 1)
+                .filter(|pos| util::in_forest(*pos, num_leaves, forest_rows))
+                .collect();
+
+            let moved = super::transform_positions(&positions, &dels, num_leaves, forest_rows);
+
+            for (pos, new_pos) in positions.iter().zip(moved) {
+                let row = util::detect_row(*pos, forest_rows);
+                let start = (pos - util::row_offset(row, forest_rows)) << row;
+
+                match new_pos {
+                    // deleted leaves are always gone
+                    None => assert!(row != 0 || dels.contains(pos)),
+
+                    // the leaves under the node are the same ones, in the
+                    // same order
+                    Some(new_pos) => {
+                        assert_eq!(util::detect_row(new_pos, forest_rows), row);
+                        let new_start = (new_pos - util::row_offset(row, forest_rows)) << row;
+                        for i in 0..1 << row {
+                            assert_eq!(leaves[(new_start + i) as usize], Some(start + i));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_transform_all() {
+        for num_leaves in 1..70 {
+            check_transform(num_leaves, (0..num_leaves).collect());
+        }
+    }
 }
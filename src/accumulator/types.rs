@@ -2,13 +2,26 @@
 
 //use sha2::{Digest, Sha256};
 
+use alloc::vec::Vec;
+
+#[cfg(feature = "std")]
 use bitcoin::blockdata::transaction;
-use bitcoin::hashes::{sha256, Hash, HashEngine};
+#[cfg(feature = "std")]
+use bitcoin::consensus::encode::{self, Decodable, Encodable};
+#[cfg(feature = "std")]
+use std::io;
+use bitcoin_hashes::{sha256, Hash, HashEngine};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use super::hash;
 
+#[cfg(feature = "std")]
 type HASH = [u8; 32];
 
 /// Leaf represents a utxo in the utreexo tree. These are the bottommost
 /// nodes in the tree.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Leaf {
     ///  The hash representation of the Leaf
     pub hash: sha256::Hash,
@@ -21,8 +34,13 @@ pub struct Leaf {
 /// LeafData is all the data that goes into the hashing the leaf.
 /// The data included is needed for transaction script validation.
 /// The rest of the data is for hardening against hash collisions.
+#[cfg(feature = "std")]
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct LeafData {
+    #[cfg_attr(feature = "serde", serde(with = "serde_block_hash"))]
     block_header: HASH,
+    #[cfg_attr(feature = "serde", serde(with = "OutPointDef"))]
     outpoint: transaction::OutPoint,
     height: i32,
     is_coinbase: bool,
@@ -30,10 +48,88 @@ pub struct LeafData {
     pk_script: Vec<u8>,
 }
 
+#[cfg(feature = "std")]
+impl LeafData {
+    /// Returns the leaf data for the output at outpoint, created in the block
+    /// with the given hash at the given height.
+    pub fn new(block_header: [u8; 32], outpoint: transaction::OutPoint, height: i32, is_coinbase: bool,
+               amt: i64, pk_script: Vec<u8>) -> LeafData {
+        LeafData { block_header, outpoint, height, is_coinbase, amt, pk_script }
+    }
+
+    /// outpoint returns the output this is the leaf data of.
+    pub fn outpoint(&self) -> transaction::OutPoint {
+        self.outpoint
+    }
+
+    /// leaf_hash returns the hash of the leaf that goes into the accumulator,
+    /// which is the hash of its consensus encoding.
+    pub fn leaf_hash(&self) -> sha256::Hash {
+        let mut engine = sha256::Hash::engine();
+        self.consensus_encode(&mut engine).expect("engines don't error");
+        sha256::Hash::from_engine(engine)
+    }
+}
+
+// LeafData is encoded the same way as in the Go implementation, with the
+// height and whether it's a coinbase packed into one int32.
+#[cfg(feature = "std")]
+impl Encodable for LeafData {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let mut len = 0;
+        len += self.block_header.consensus_encode(&mut w)?;
+        len += self.outpoint.consensus_encode(&mut w)?;
+        len += (self.height << 1 | self.is_coinbase as i32).consensus_encode(&mut w)?;
+        len += self.amt.consensus_encode(&mut w)?;
+        len += self.pk_script.consensus_encode(&mut w)?;
+        Ok(len)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Decodable for LeafData {
+    fn consensus_decode<R: io::Read>(mut r: R) -> Result<LeafData, encode::Error> {
+        let block_header = Decodable::consensus_decode(&mut r)?;
+        let outpoint = Decodable::consensus_decode(&mut r)?;
+        let code = i32::consensus_decode(&mut r)?;
+        let amt = Decodable::consensus_decode(&mut r)?;
+        let pk_script = Decodable::consensus_decode(&mut r)?;
+
+        Ok(LeafData { block_header, outpoint, height: code >> 1, is_coinbase: code & 1 == 1, amt, pk_script })
+    }
+}
+
+// OutPointDef lets serde derive for the OutPoint in LeafData as bitcoin only
+// has it with its own serde feature.
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "transaction::OutPoint")]
+struct OutPointDef {
+    txid: bitcoin::Txid,
+    vout: u32,
+}
+
+// serde_block_hash (de)serializes the block hash in LeafData the same way as
+// the other hashes.
+#[cfg(feature = "serde")]
+mod serde_block_hash {
+    use bitcoin_hashes::{sha256d, Hash};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(hash: &[u8; 32], s: S) -> Result<S::Ok, S::Error> {
+        sha256d::Hash::from_inner(*hash).serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<[u8; 32], D::Error> {
+        sha256d::Hash::deserialize(d).map(|hash| hash.into_inner())
+    }
+}
+
 /// Arrow is used to describe the movement of a leaf to a different
 /// position. This is used for batch deletions during removal
 #[derive(Clone, Copy)]
 #[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Arrow {
     pub from: u64,
     pub to: u64,
@@ -48,3 +144,59 @@ pub fn parent_hash(left: &sha256::Hash, right: &sha256::Hash) -> sha256::Hash {
 
     return sha256::Hash::from_engine(engine);
 }
+
+// parent_hash_batch returns the merkle parents of each of the pairs, in the
+// same order. Faster than calling parent_hash for each of them.
+pub fn parent_hash_batch(pairs: &[(sha256::Hash, sha256::Hash)]) -> Vec<sha256::Hash> {
+    let inputs: Vec<[u8; 64]> = pairs.iter()
+        .map(|(left, right)| {
+            let mut input = [0u8; 64];
+            input[..32].copy_from_slice(&left[..]);
+            input[32..].copy_from_slice(&right[..]);
+            input
+        })
+        .collect();
+
+    hash::hash64_batch(&inputs).into_iter()
+        .map(sha256::Hash::from_inner)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_serde() {
+        use bitcoin::hashes::hex::FromHex;
+        use bitcoin_hashes::{sha256, Hash};
+
+        let leaf = super::Leaf { hash: sha256::Hash::hash(b"leaf"), remember: true };
+        let json = serde_json::to_string(&leaf).unwrap();
+        assert_eq!(json, format!("{{\"hash\":\"{}\",\"remember\":true}}", leaf.hash));
+
+        // Raw bytes in binary formats
+        let binary = serde_cbor::to_vec(&leaf).unwrap();
+        assert!(binary.windows(32).any(|window| window == &leaf.hash[..]));
+        let decoded: super::Leaf = serde_cbor::from_slice(&binary).unwrap();
+        assert_eq!(decoded.hash, leaf.hash);
+
+        let arrow: super::Arrow = serde_json::from_str("{\"from\":5,\"to\":1}").unwrap();
+        assert_eq!((arrow.from, arrow.to), (5, 1));
+
+        let txid = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+        let data = super::LeafData {
+            block_header: [7; 32],
+            outpoint: bitcoin::OutPoint { txid: bitcoin::Txid::from_hex(txid).unwrap(), vout: 3 },
+            height: 100,
+            is_coinbase: false,
+            amt: 5000,
+            pk_script: vec![0x51],
+        };
+        let json = serde_json::to_string(&data).unwrap();
+        assert!(json.contains(&format!("\"outpoint\":{{\"txid\":\"{}\",\"vout\":3}}", txid)));
+        assert!(json.contains(&format!("\"block_header\":\"{}\"", "07".repeat(32))));
+
+        let decoded: super::LeafData = serde_json::from_str(&json).unwrap();
+        assert_eq!(serde_json::to_string(&decoded).unwrap(), json);
+    }
+}
@@ -1,6 +1,6 @@
 // Rustreexo
 
-use std::vec::Vec;
+use alloc::vec::Vec;
 
 // extractTwins is a optimization for batched deletions. It checks if the nodes
 // being deleted also have their sibling being deleted. It returns the parents
@@ -68,11 +68,11 @@ pub fn detect_row(pos: u64, forest_rows: u8) -> u8 {
 //
 // 8 = getRowOffset(1, 3)
 // 12 = getRowOffset(2, 3)
-fn row_offset(row: u8, forest_rows: u8) -> u64 {
+pub fn row_offset(row: u8, forest_rows: u8) -> u64 {
     // 2 << forestRows is 2 more than the max poisition
     // to get the correct offset for a given row,
     // subtract (2 << `row complement of forestRows`) from (2 << forestRows)
-    (2 << forest_rows) - (2 << (forest_rows - row))
+    forest_mask(forest_rows) - forest_mask(forest_rows - row)
 }
 
 pub fn detect_offset(pos: u64, num_leaves: u64) -> (u8, u8, u64) {
@@ -108,7 +108,7 @@ pub fn detect_offset(pos: u64, num_leaves: u64) -> (u8, u8, u64) {
     // covered by that tree from the position, and proceed to the next tree,
     // skipping trees that don't exist.
 
-    while (marker << nr) & ((2 << tr) - 1) >= (1 << tr) & num_leaves {
+    while (marker << nr) & forest_mask(tr) >= (1 << tr) & num_leaves {
         let tree_size = (1 << tr) & num_leaves;
         if tree_size != 0 {
             marker -= tree_size;
@@ -120,9 +120,16 @@ pub fn detect_offset(pos: u64, num_leaves: u64) -> (u8, u8, u64) {
     return (bigger_trees, tr - nr, !marker);
 }
 
+// forest_mask returns a mask of the bits that the positions of a forest with
+// the given rows can have. This is (2 << forest_rows) - 1, without the
+// overflow when there are 63 rows.
+fn forest_mask(forest_rows: u8) -> u64 {
+    u64::MAX >> (63 - forest_rows)
+}
+
 // child gives you the left child (LSB will be 0)
-fn child(pos: u64, forest_rows: u8) -> u64 {
-    let mask = (2 << forest_rows) - 1;
+pub fn child(pos: u64, forest_rows: u8) -> u64 {
+    let mask = forest_mask(forest_rows);
     return (pos << 1) & mask;
 }
 
@@ -136,7 +143,7 @@ fn n_grandchild(pos: u64, drop: u8, forest_rows: u8) -> Result<u64, u8> {
     if drop > forest_rows {
         return Err(1);
     }
-    let mask = (2 << forest_rows) - 1;
+    let mask = forest_mask(forest_rows);
     return Ok((pos << drop) & mask);
 }
 
@@ -155,7 +162,7 @@ pub fn n_grandparent(pos: u64, rise: u8, forest_rows: u8) -> Result<u64, u8> {
     if rise > forest_rows {
         return Err(1);
     }
-    let mask = (2 << forest_rows) - 1;
+    let mask = forest_mask(forest_rows);
     Ok((pos >> rise | (mask << (forest_rows - (rise - 1)))) & mask)
 }
 
@@ -174,7 +181,7 @@ pub fn in_forest(mut pos: u64, num_leaves: u64, forest_rows: u8) -> bool {
     }
 
     let marker = 1 << forest_rows;
-    let mask = (marker << 1) - 1;
+    let mask = forest_mask(forest_rows);
 
     if pos >= mask {
         return false;
@@ -220,14 +227,14 @@ fn num_roots(num_leaves: u64) -> u8 {
 // root_position returns the position of the root at a given row
 // TODO undefined behavior if the given row doesn't have a root
 pub fn root_position(num_leaves: u64, row: u8, forest_rows: u8) -> u64 {
-    let mask = (2 << forest_rows) - 1;
-    let before = num_leaves & (mask << (row + 1));
+    let mask = forest_mask(forest_rows);
+    let before = num_leaves & mask.checked_shl(row as u32 + 1).unwrap_or(0);
 
     // the go code relies on an overflow on row.
     // (forest_rows - (row - 1)) when row is 0 is equivalent to
-    // (forest_rows + 1)
+    // (forest_rows + 1). Shifting by 64 is 0 in go
     if row == 0 {
-        let shifted = (before >> row) | mask << (forest_rows + 1);
+        let shifted = (before >> row) | mask.checked_shl(forest_rows as u32 + 1).unwrap_or(0);
         return shifted & mask;
     }
 
@@ -237,15 +244,77 @@ pub fn root_position(num_leaves: u64, row: u8, forest_rows: u8) -> u64 {
 }
 
 // get_roots_reverse gives you the positions of the tree roots, given a number of leaves.
-fn get_roots_reverse(num_leaves: u64, forest_rows: u8) {
-    //let pos: u64;
+// The lowest root comes first. This is the reverse of the order the roots are
+// kept in the Pollard.
+pub fn get_roots_reverse(num_leaves: u64, forest_rows: u8) -> Vec<u64> {
+    let mut roots = Vec::with_capacity(num_roots(num_leaves) as usize);
+
+    for row in 0..=forest_rows {
+        if num_leaves & (1 << row) != 0 {
+            roots.push(root_position(num_leaves, row, forest_rows));
+        }
+    }
+
+    roots
+}
+
+// is_root_position returns whether the given position is a root
+pub fn is_root_position(pos: u64, num_leaves: u64, forest_rows: u8) -> bool {
+    let row = detect_row(pos, forest_rows);
+    num_leaves & (1 << row) != 0 && pos == root_position(num_leaves, row, forest_rows)
+}
+
+// proof_positions returns the positions of all the nodes needed to hash the
+// given targets up to their roots. Positions that can be calculated from the
+// targets themselves are not included. Returned positions are sorted.
+pub fn proof_positions(targets: &[u64], num_leaves: u64, forest_rows: u8) -> Vec<u64> {
+    let mut proof_positions = Vec::new();
+
+    let mut computed = targets.to_vec();
+    computed.sort_unstable();
+    computed.dedup();
+
+    for row in 0..=forest_rows {
+        let mut next_computed = Vec::with_capacity(computed.len());
+
+        let mut i = 0;
+        while i < computed.len() {
+            let pos = computed[i];
+
+            // roots don't need a sibling
+            if num_leaves & (1 << row) != 0 && pos == root_position(num_leaves, row, forest_rows) {
+                i += 1;
+                continue
+            }
+
+            // if the sibling is also being computed, it doesn't need to be
+            // in the proof
+            if i + 1 < computed.len() && computed[i + 1] == pos ^ 1 {
+                i += 2;
+            } else {
+                proof_positions.push(pos ^ 1);
+                i += 1;
+            }
+
+            next_computed.push(parent(pos, forest_rows));
+        }
+
+        computed = next_computed;
+    }
 
-    //for
+    proof_positions
 }
 
 fn subtree_positions() {}
 
-fn subtree_leafrange() {}
+// subtree_leafrange returns the position of the leftmost leaf under the
+// given position and how many leaves there are under it.
+pub fn subtree_leafrange(pos: u64, forest_rows: u8) -> (u64, u64) {
+    let row = detect_row(pos, forest_rows);
+    let left = n_grandchild(pos, row, forest_rows).unwrap();
+
+    (left, 1 << row)
+}
 
 fn to_leaves() {}
 
@@ -263,15 +332,51 @@ fn previous_pow2(n: u64) -> u64 {
 
 // next_pow2 returns the next power of 2
 // ex: n = 9 will return 16. n = 33 will return 64
+// n = 0 wraps around and returns 0
 fn next_pow2(n: u64) -> u64 {
-    let mut t = n - 1;
+    let mut t = n.wrapping_sub(1);
     t |= t >> 1;
     t |= t >> 2;
     t |= t >> 4;
     t |= t >> 8;
     t |= t >> 16;
     t |= t >> 32;
-    t + 1
+    t.wrapping_add(1)
+}
+
+// Rng is a xorshift generator so that the tests that need random numbers are
+// deterministic.
+#[cfg(test)]
+pub(crate) struct Rng(u64);
+
+#[cfg(test)]
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Rng {
+        Rng(seed)
+    }
+
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    // dels returns the positions below num_leaves, each of them picked with
+    // a chance of one in one_in.
+    pub(crate) fn dels(&mut self, num_leaves: u64, one_in: u64) -> Vec<u64> {
+        (0..num_leaves).filter(|_| self.next() % one_in == 0).collect()
+    }
+}
+
+// hash_from_u64 returns the hash the tests use for the leaf with the number.
+#[cfg(test)]
+pub(crate) fn hash_from_u64(num: u64) -> bitcoin_hashes::sha256::Hash {
+    use bitcoin_hashes::{sha256, Hash, HashEngine};
+
+    let mut engine = sha256::Hash::engine();
+    engine.input(&num.to_le_bytes());
+    sha256::Hash::from_engine(engine)
 }
 
 #[cfg(test)]
@@ -284,6 +389,15 @@ mod tests {
 
         let pos = super::root_position(5, 0, 3);
         assert_eq!(pos, 4);
+
+        // The biggest forest there can be
+        let num_leaves = u64::MAX >> 1;
+        assert_eq!(super::root_position(1 << 63, 63, 63), u64::MAX - 1);
+        assert_eq!(super::root_position(num_leaves, 62, 63), u64::MAX - 3);
+        assert_eq!(super::root_position(num_leaves, 0, 63), num_leaves - 1);
+        assert_eq!(super::row_offset(62, 63), u64::MAX - 3);
+        assert_eq!(super::parent(u64::MAX - 3, 63), u64::MAX - 1);
+        assert!(super::in_forest(u64::MAX - 1, 1 << 63, 63));
     }
     #[test]
     fn pow_tests() {
@@ -341,4 +455,13 @@ mod tests {
         let h = super::detect_sub_tree_rows(0, 8, 3);
         assert_eq!(h, 3);
     }
+
+    #[test]
+    fn test_subtree_leafrange() {
+        // forest with 8 leaves
+        assert_eq!(super::subtree_leafrange(5, 3), (5, 1));
+        assert_eq!(super::subtree_leafrange(9, 3), (2, 2));
+        assert_eq!(super::subtree_leafrange(13, 3), (4, 4));
+        assert_eq!(super::subtree_leafrange(14, 3), (0, 8));
+    }
 }
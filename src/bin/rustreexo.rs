@@ -0,0 +1,308 @@
+// Rustreexo
+
+//! rustreexo works with a Pollard kept in a file. The Pollard and proofs
+//! are read and written as CBOR if the file name ends in .cbor and as JSON
+//! otherwise.
+//!
+//! The file given to modify has one change on each line, either
+//! "add <hash>", "add <hash> remember" or "del <hash>", where the hashes are
+//! 64 hex characters. Blank lines and lines starting with # are skipped.
+//! The dels are deleted before the adds are added, like in a block.
+
+use std::fs;
+use std::process;
+
+use bitcoin_hashes::hex::FromHex;
+use bitcoin_hashes::sha256;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use serde::{de::DeserializeOwned, Serialize};
+
+use rustreexo::accumulator::{pollard::Pollard, proof::BatchProof, render, types::Leaf};
+
+// positions and dump draw a line for each row, as wide as the forest, so
+// they only take counts that fit on a screen.
+const MAX_POSITIONS_LEAVES: u64 = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Format {
+    Json,
+    Cbor,
+}
+
+impl Format {
+    fn of(path: &str) -> Format {
+        if path.ends_with(".cbor") { Format::Cbor } else { Format::Json }
+    }
+}
+
+fn read<T: DeserializeOwned>(path: &str) -> Result<T, String> {
+    let data = fs::read(path).map_err(|err| format!("can't read {}: {}", path, err))?;
+    match Format::of(path) {
+        Format::Json => serde_json::from_slice(&data).map_err(|err| format!("can't decode {}: {}", path, err)),
+        Format::Cbor => serde_cbor::from_slice(&data).map_err(|err| format!("can't decode {}: {}", path, err)),
+    }
+}
+
+fn write<T: Serialize>(path: &str, value: &T) -> Result<(), String> {
+    let data = match Format::of(path) {
+        Format::Json => serde_json::to_vec(value).map_err(|err| err.to_string())?,
+        Format::Cbor => serde_cbor::to_vec(value).map_err(|err| err.to_string())?,
+    };
+    fs::write(path, data).map_err(|err| format!("can't write {}: {}", path, err))
+}
+
+fn parse_hash(hash: &str) -> Result<sha256::Hash, String> {
+    sha256::Hash::from_hex(hash).map_err(|_| format!("{} isn't a hash", hash))
+}
+
+fn hashes(matches: &ArgMatches) -> Result<Vec<sha256::Hash>, String> {
+    matches.values_of("HASH").into_iter().flatten().map(parse_hash).collect()
+}
+
+// parse_changes returns the adds and the dels in the file of changes.
+fn parse_changes(changes: &str) -> Result<(Vec<Leaf>, Vec<sha256::Hash>), String> {
+    let mut adds = Vec::new();
+    let mut dels = Vec::new();
+
+    for (number, line) in changes.lines().enumerate() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let bad_line = || format!("line {}: expected add <hash> [remember] or del <hash>", number + 1);
+
+        match words.as_slice() {
+            [] => {}
+            [word, ..] if word.starts_with('#') => {}
+            ["add", hash] => adds.push(Leaf { hash: parse_hash(hash)?, remember: false }),
+            ["add", hash, "remember"] => adds.push(Leaf { hash: parse_hash(hash)?, remember: true }),
+            ["del", hash] => dels.push(parse_hash(hash)?),
+            _ => return Err(bad_line()),
+        }
+    }
+
+    Ok((adds, dels))
+}
+
+fn app() -> App<'static, 'static> {
+    let state = || Arg::with_name("STATE").help("File the Pollard is kept in").required(true);
+    let hash = || Arg::with_name("HASH").help("Hashes of the leaves").multiple(true);
+
+    App::new("rustreexo")
+        .about("Inspects and changes utreexo accumulators kept in files")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        .subcommand(SubCommand::with_name("new")
+            .about("Writes an empty Pollard")
+            .arg(state()))
+        .subcommand(SubCommand::with_name("modify")
+            .about("Deletes and adds the leaves in a file of changes")
+            .arg(state())
+            .arg(Arg::with_name("CHANGES").help("File with the changes").required(true)))
+        .subcommand(SubCommand::with_name("roots")
+            .about("Prints the number of leaves and the roots")
+            .arg(state()))
+        .subcommand(SubCommand::with_name("prove")
+            .about("Proves remembered leaves")
+            .arg(state())
+            .arg(hash().required(true))
+            .arg(Arg::with_name("out").long("out").short("o").takes_value(true)
+                .help("Writes the proof to the file instead of printing it")))
+        .subcommand(SubCommand::with_name("verify")
+            .about("Checks a proof of leaves against the Pollard")
+            .arg(state())
+            .arg(Arg::with_name("PROOF").help("File with the proof").required(true))
+            .arg(hash()))
+        .subcommand(SubCommand::with_name("dump")
+            .about("Draws the Pollard")
+            .arg(state())
+            .arg(Arg::with_name("dot").long("dot").help("Writes a Graphviz digraph instead")))
+        .subcommand(SubCommand::with_name("positions")
+            .about("Draws the positions of a forest")
+            .arg(Arg::with_name("LEAVES").help("Number of leaves, at most 1024").required(true)))
+        .subcommand(SubCommand::with_name("convert")
+            .about("Writes a Pollard or a proof in the format of another file")
+            .arg(Arg::with_name("IN").required(true))
+            .arg(Arg::with_name("OUT").required(true))
+            .arg(Arg::with_name("proof").long("proof").help("The file is a proof and not a Pollard")))
+}
+
+// run runs the subcommand and returns what it prints.
+fn run(matches: &ArgMatches) -> Result<String, String> {
+    let (name, args) = matches.subcommand();
+    let args = args.ok_or("no subcommand")?;
+    let state = args.value_of("STATE").unwrap_or_default();
+
+    match name {
+        "new" => {
+            write(state, &Pollard::with_leaf_index())?;
+            Ok(String::new())
+        }
+        "modify" => {
+            let mut pollard: Pollard = read(state)?;
+            let path = args.value_of("CHANGES").unwrap();
+            let changes = fs::read_to_string(path).map_err(|err| format!("can't read {}: {}", path, err))?;
+            let (adds, dels) = parse_changes(&changes)?;
+
+            pollard.modify_by_hash(adds, &dels).map_err(|err| err.to_string())?;
+            write(state, &pollard)?;
+            Ok(format!("{} leaves\n", pollard.num_leaves))
+        }
+        "roots" => {
+            let pollard: Pollard = read(state)?;
+            let mut out = format!("{} leaves\n", pollard.num_leaves);
+            for root in pollard.roots() {
+                out.push_str(&format!("{}\n", root));
+            }
+            Ok(out)
+        }
+        "prove" => {
+            let pollard: Pollard = read(state)?;
+            let proof = pollard.prove(&hashes(args)?).map_err(|err| err.to_string())?;
+            match args.value_of("out") {
+                Some(out) => write(out, &proof).map(|_| String::new()),
+                None => Ok(serde_json::to_string_pretty(&proof).unwrap() + "\n"),
+            }
+        }
+        "verify" => {
+            let pollard: Pollard = read(state)?;
+            let proof: BatchProof = read(args.value_of("PROOF").unwrap())?;
+            if !proof.verify(&hashes(args)?, &pollard.roots(), pollard.num_leaves) {
+                return Err("proof is not valid".to_string());
+            }
+            Ok("proof is valid\n".to_string())
+        }
+        "dump" => {
+            let pollard: Pollard = read(state)?;
+            if args.is_present("dot") {
+                return Ok(pollard.to_dot());
+            }
+            if pollard.num_leaves > MAX_POSITIONS_LEAVES {
+                return Err(format!("can't draw more than {} leaves, use --dot", MAX_POSITIONS_LEAVES));
+            }
+            Ok(pollard.to_string())
+        }
+        "positions" => {
+            let leaves = args.value_of("LEAVES").unwrap();
+            let num_leaves = leaves.parse().map_err(|_| format!("{} isn't a number", leaves))?;
+            if num_leaves > MAX_POSITIONS_LEAVES {
+                return Err(format!("can't draw more than {} leaves", MAX_POSITIONS_LEAVES));
+            }
+            Ok(render::draw_positions(num_leaves))
+        }
+        "convert" => {
+            let (input, output) = (args.value_of("IN").unwrap(), args.value_of("OUT").unwrap());
+            if args.is_present("proof") {
+                write(output, &read::<BatchProof>(input)?)?;
+            } else {
+                write(output, &read::<Pollard>(input)?)?;
+            }
+            Ok(String::new())
+        }
+        _ => Err(format!("unknown subcommand {}", name)),
+    }
+}
+
+fn main() {
+    match run(&app().get_matches()) {
+        Ok(out) => print!("{}", out),
+        Err(err) => {
+            eprintln!("error: {}", err);
+            process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use bitcoin_hashes::{sha256, Hash};
+
+    fn run(args: &[&str]) -> Result<String, String> {
+        let matches = super::app().get_matches_from_safe(std::iter::once("rustreexo").chain(args.iter().copied()))
+            .map_err(|err| err.to_string())?;
+        super::run(&matches)
+    }
+
+    // temp_dir returns an empty directory for the test with the name.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rustreexo-cli-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn hash(num: u8) -> String {
+        sha256::Hash::hash(&[num]).to_string()
+    }
+
+    #[test]
+    fn test_cli() {
+        let dir = temp_dir("cli");
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        let state = path("state.json");
+
+        run(&["new", &state]).unwrap();
+        assert_eq!(run(&["roots", &state]).unwrap(), "0 leaves\n");
+
+        let changes = (0..4).map(|num| format!("add {} remember\n", hash(num))).collect::<String>();
+        fs::write(path("adds"), format!("# four leaves\n\n{}", changes)).unwrap();
+        assert_eq!(run(&["modify", &state, &path("adds")]).unwrap(), "4 leaves\n");
+
+        fs::write(path("dels"), format!("del {}\nadd {}\n", hash(1), hash(9))).unwrap();
+        assert_eq!(run(&["modify", &state, &path("dels")]).unwrap(), "4 leaves\n");
+
+        let roots = run(&["roots", &state]).unwrap();
+        assert_eq!(roots.lines().count(), 2);
+
+        // A proof goes to a file and is checked from it, in either format
+        run(&["prove", &state, &hash(2), &hash(3), "-o", &path("proof.cbor")]).unwrap();
+        assert_eq!(run(&["verify", &state, &path("proof.cbor"), &hash(2), &hash(3)]).unwrap(), "proof is valid\n");
+        assert!(run(&["verify", &state, &path("proof.cbor"), &hash(3), &hash(2)]).is_err());
+        assert!(run(&["prove", &state, &hash(9)]).unwrap_err().contains("not remembered"));
+
+        run(&["convert", "--proof", &path("proof.cbor"), &path("proof.json")]).unwrap();
+        let printed: serde_json::Value = serde_json::from_str(&run(&["prove", &state, &hash(2), &hash(3)]).unwrap()).unwrap();
+        let converted: serde_json::Value = serde_json::from_str(&fs::read_to_string(path("proof.json")).unwrap()).unwrap();
+        assert_eq!(printed, converted);
+
+        // The state is the same after going through CBOR
+        run(&["convert", &state, &path("state.cbor")]).unwrap();
+        assert_eq!(run(&["roots", &path("state.cbor")]).unwrap(), roots);
+        assert_eq!(run(&["dump", &path("state.cbor")]).unwrap(), run(&["dump", &state]).unwrap());
+        assert!(run(&["dump", "--dot", &state]).unwrap().starts_with("digraph forest {\n"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_bad_input() {
+        let dir = temp_dir("bad");
+        let state = dir.join("state.json").to_str().unwrap().to_string();
+        let changes = dir.join("changes").to_str().unwrap().to_string();
+        run(&["new", &state]).unwrap();
+
+        fs::write(&changes, "add 1234\n").unwrap();
+        assert!(run(&["modify", &state, &changes]).unwrap_err().contains("isn't a hash"));
+
+        fs::write(&changes, format!("add {}\nremove {}\n", hash(0), hash(0))).unwrap();
+        assert_eq!(run(&["modify", &state, &changes]).unwrap_err(),
+                   "line 2: expected add <hash> [remember] or del <hash>");
+
+        // Nothing was written for the changes that failed
+        assert_eq!(run(&["roots", &state]).unwrap(), "0 leaves\n");
+        assert!(run(&["roots", dir.join("missing").to_str().unwrap()]).unwrap_err().starts_with("can't read"));
+        assert_eq!(run(&["positions", "2"]).unwrap(), "2\n|--\\\n0  1\n");
+        assert!(run(&["positions", "1024"]).is_ok());
+        assert_eq!(run(&["positions", "1025"]).unwrap_err(), "can't draw more than 1024 leaves");
+        assert_eq!(run(&["positions", "18446744073709551615"]).unwrap_err(), "can't draw more than 1024 leaves");
+
+        // Too many leaves to draw, but not for a digraph
+        let adds = (0..1025u32).map(|num| format!("add {}\n", sha256::Hash::hash(&num.to_le_bytes()))).collect::<String>();
+        fs::write(&changes, adds).unwrap();
+        run(&["modify", &state, &changes]).unwrap();
+        assert_eq!(run(&["dump", &state]).unwrap_err(), "can't draw more than 1024 leaves, use --dot");
+        assert!(run(&["dump", "--dot", &state]).unwrap().starts_with("digraph forest {\n"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
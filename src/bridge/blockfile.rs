@@ -0,0 +1,393 @@
+// Rustreexo
+
+//! Reads the blocks Bitcoin Core keeps in the blk*.dat files of its blocks
+//! directory, so a bridge can be built without a node to ask for them.
+//!
+//! Each block in a file is the network magic, the size of the block as a
+//! little endian u32 and then the block. The files are allocated ahead of
+//! time so they can end in zeros. Blocks are written in the order they
+//! arrive, which isn't the order of the chain, and blocks that aren't in
+//! the best chain are there too. Since 28.0 Bitcoin Core XORs the files
+//! with the 8 byte key in xor.dat.
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use bitcoin::consensus::encode::{self, deserialize};
+use bitcoin::util::uint::Uint256;
+use bitcoin::{BitcoinHash, Block, BlockHash, BlockHeader, Network};
+
+use super::Bridge;
+
+/// Error is returned when the block files can't be read or don't fit the
+/// bridge.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+
+    /// A block or a header in the files can't be decoded.
+    Decode(encode::Error),
+
+    /// Something that isn't the network magic is where a block should start.
+    BadMagic { file: PathBuf, offset: u64, magic: u32 },
+
+    /// None of the blocks builds on nothing, so there's no chain to follow.
+    NoGenesis,
+
+    /// The bridge couldn't process a block.
+    Bridge(super::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "{}", err),
+            Error::Decode(err) => write!(f, "can't decode block: {}", err),
+            Error::BadMagic { file, offset, magic } => {
+                write!(f, "{:08x} at {} in {} isn't the network magic", magic, offset, file.display())
+            }
+            Error::NoGenesis => write!(f, "there's no genesis block"),
+            Error::Bridge(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<encode::Error> for Error {
+    fn from(err: encode::Error) -> Error {
+        Error::Decode(err)
+    }
+}
+
+impl From<super::Error> for Error {
+    fn from(err: super::Error) -> Error {
+        Error::Bridge(err)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// BlockPos is where a block is in the block files.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockPos {
+    /// Index of the file in BlockFiles::files
+    pub file: usize,
+
+    /// Where the block starts in the file, after the magic and the size
+    pub offset: u64,
+
+    pub len: u32,
+    pub hash: BlockHash,
+}
+
+/// BlockFiles are the block files in a blocks directory.
+pub struct BlockFiles {
+    files: Vec<PathBuf>,
+    key: [u8; 8],
+    magic: u32,
+}
+
+impl BlockFiles {
+    /// open finds the block files and the XOR key in the blocks directory of
+    /// a Bitcoin Core data directory for the network. The files aren't read
+    /// until they're needed.
+    pub fn open(blocks_dir: impl AsRef<Path>, network: Network) -> Result<BlockFiles> {
+        let blocks_dir = blocks_dir.as_ref();
+
+        let mut files = Vec::new();
+        for entry in fs::read_dir(blocks_dir)? {
+            let name = entry?.file_name();
+            let number = name.to_str()
+                .and_then(|name| name.strip_prefix("blk")?.strip_suffix(".dat")?.parse::<u32>().ok());
+            if let Some(number) = number {
+                files.push((number, blocks_dir.join(&name)));
+            }
+        }
+        files.sort();
+
+        // Files written before 28.0 aren't XORed, which is the same as a key
+        // of all zeros
+        let key = match fs::read(blocks_dir.join("xor.dat")) {
+            Ok(key) => {
+                <[u8; 8]>::try_from(key.as_slice())
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "xor.dat isn't 8 bytes"))?
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => [0; 8],
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(BlockFiles { files: files.into_iter().map(|(_, path)| path).collect(), key, magic: network.magic() })
+    }
+
+    /// files returns the paths of the block files, in the order of their
+    /// numbers.
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    /// headers returns the header of every block in the files along with
+    /// where the block is, in the order they are in the files.
+    pub fn headers(&self) -> Result<Vec<(BlockPos, BlockHeader)>> {
+        let mut headers = Vec::new();
+
+        for (index, path) in self.files.iter().enumerate() {
+            let file_len = fs::metadata(path)?.len();
+            let mut file = self.open_file(index)?;
+
+            let mut offset = 0;
+            while offset + 8 + 80 <= file_len {
+                let mut prefix = [0u8; 8];
+                file.read_exact(&mut prefix)?;
+
+                let magic = u32::from_le_bytes([prefix[0], prefix[1], prefix[2], prefix[3]]);
+                let len = u32::from_le_bytes([prefix[4], prefix[5], prefix[6], prefix[7]]);
+
+                // The rest of the file hasn't been written to yet
+                if magic == 0 {
+                    break
+                }
+                if magic != self.magic {
+                    return Err(Error::BadMagic { file: path.clone(), offset, magic });
+                }
+
+                // A block that's cut off is still being written
+                if offset + 8 + len as u64 > file_len {
+                    break
+                }
+
+                let mut header = [0u8; 80];
+                file.read_exact(&mut header)?;
+                let header: BlockHeader = deserialize(&header)?;
+
+                headers.push((BlockPos { file: index, offset: offset + 8, len, hash: header.bitcoin_hash() }, header));
+
+                offset += 8 + len as u64;
+                file.seek(offset)?;
+            }
+        }
+
+        Ok(headers)
+    }
+
+    /// best_chain returns where the blocks of the chain with the most work
+    /// are, from the genesis block to the tip. The genesis block is the one
+    /// that builds on nothing.
+    pub fn best_chain(&self) -> Result<Vec<BlockPos>> {
+        let mut blocks: Vec<(BlockPos, BlockHeader)> = Vec::new();
+        let mut children: HashMap<BlockHash, Vec<usize>> = HashMap::new();
+        let mut seen = HashSet::new();
+
+        for (pos, header) in self.headers()? {
+            // The same block can be in the files more than once
+            if !seen.insert(pos.hash) {
+                continue
+            }
+
+            children.entry(header.prev_blockhash).or_default().push(blocks.len());
+            blocks.push((pos, header));
+        }
+
+        let genesis = *children.get(&BlockHash::default()).and_then(|genesis| genesis.first()).ok_or(Error::NoGenesis)?;
+
+        // Total work of the chain up to each block and the block it builds
+        // on, for the blocks that connect to the genesis block
+        let mut work: HashMap<usize, Uint256> = HashMap::new();
+        let mut parent: HashMap<usize, usize> = HashMap::new();
+        work.insert(genesis, blocks[genesis].1.work());
+
+        let mut best = genesis;
+        let mut queue = vec![genesis];
+        while let Some(block) = queue.pop() {
+            for &child in children.get(&blocks[block].0.hash).into_iter().flatten() {
+                let total = work[&block] + blocks[child].1.work();
+                if total > work[&best] || (total == work[&best] && child < best) {
+                    best = child;
+                }
+
+                work.insert(child, total);
+                parent.insert(child, block);
+                queue.push(child);
+            }
+        }
+
+        let mut chain = vec![blocks[best].0.clone()];
+        let mut block = best;
+        while let Some(&prev) = parent.get(&block) {
+            chain.push(blocks[prev].0.clone());
+            block = prev;
+        }
+        chain.reverse();
+
+        Ok(chain)
+    }
+
+    /// read_block reads the block at the position.
+    pub fn read_block(&self, pos: &BlockPos) -> Result<Block> {
+        let mut file = self.open_file(pos.file)?;
+        file.seek(pos.offset)?;
+
+        let mut data = vec![0; pos.len as usize];
+        file.read_exact(&mut data)?;
+        Ok(deserialize(&data)?)
+    }
+
+    /// blocks returns the blocks of the best chain, from the genesis block to
+    /// the tip. They're read as they're needed.
+    pub fn blocks(&self) -> Result<impl Iterator<Item = Result<Block>> + '_> {
+        Ok(self.best_chain()?.into_iter().map(move |pos| self.read_block(&pos)))
+    }
+
+    fn open_file(&self, index: usize) -> io::Result<XorFile> {
+        Ok(XorFile { file: BufReader::new(File::open(&self.files[index])?), key: self.key, pos: 0 })
+    }
+}
+
+/// load processes the blocks of the best chain in the files that the bridge
+/// doesn't have yet, so it can be built up over more than one run. Returns
+/// how many blocks were processed.
+pub fn load(bridge: &mut Bridge, files: &BlockFiles) -> Result<u32> {
+    let start = bridge.height().map_or(0, |height| height as usize + 1);
+
+    let mut processed = 0;
+    for pos in files.best_chain()?.iter().skip(start) {
+        bridge.process_block(&files.read_block(pos)?)?;
+        processed += 1;
+    }
+
+    Ok(processed)
+}
+
+// XorFile reads a block file, undoing the XOR with the key. Which byte of the
+// key goes with a byte of the file depends on where it is in the file.
+struct XorFile {
+    file: BufReader<File>,
+    key: [u8; 8],
+    pos: u64,
+}
+
+impl XorFile {
+    fn seek(&mut self, pos: u64) -> io::Result<()> {
+        self.pos = self.file.seek(SeekFrom::Start(pos))?;
+        Ok(())
+    }
+}
+
+impl Read for XorFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.file.read(buf)?;
+        for (i, byte) in buf[..read].iter_mut().enumerate() {
+            *byte ^= self.key[(self.pos as usize + i) % 8];
+        }
+
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use bitcoin::consensus::serialize;
+    use bitcoin::{BitcoinHash, Block, Network};
+
+    use super::super::{tests, Bridge};
+    use super::{load, BlockFiles, Error};
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rustreexo-blockfile-{}-{}", name, std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // write_file writes the blocks the way Bitcoin Core does, with some
+    // zeros at the end for the space that's not used yet.
+    fn write_file(path: &Path, blocks: &[&Block], key: [u8; 8]) {
+        let mut data = Vec::new();
+        for block in blocks {
+            let block = serialize(*block);
+            data.extend(&Network::Regtest.magic().to_le_bytes());
+            data.extend(&(block.len() as u32).to_le_bytes());
+            data.extend(block);
+        }
+        data.extend(&[0; 100]);
+
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte ^= key[i % 8];
+        }
+        fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn test_best_chain() {
+        let dir = temp_dir("chain");
+        let key = [1, 2, 3, 4, 5, 6, 7, 8];
+        fs::write(dir.join("xor.dat"), key).unwrap();
+
+        // A stale block on top of the genesis block that loses to the chain
+        let chain = tests::chain();
+        let stale = tests::block(chain[0].bitcoin_hash(), 99, vec![]);
+
+        // Out of order, spread over two files, with one block twice
+        write_file(&dir.join("blk00000.dat"), &[&chain[1], &stale, &chain[0]], key);
+        write_file(&dir.join("blk00001.dat"), &[&chain[2], &chain[1]], key);
+        fs::write(dir.join("rev00000.dat"), [0xff; 10]).unwrap();
+
+        let files = BlockFiles::open(&dir, Network::Regtest).unwrap();
+        assert_eq!(files.files().len(), 2);
+        assert_eq!(files.headers().unwrap().len(), 5);
+
+        let best: Vec<_> = files.best_chain().unwrap().into_iter().map(|pos| pos.hash).collect();
+        assert_eq!(best, chain.iter().map(|block| block.bitcoin_hash()).collect::<Vec<_>>());
+
+        let blocks = files.blocks().unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(blocks, chain);
+
+        // The bridge ends up the same as when it's given the blocks directly
+        let mut expected = Bridge::new();
+        for block in &chain {
+            expected.process_block(block).unwrap();
+        }
+
+        let mut bridge = Bridge::new();
+        bridge.process_block(&chain[0]).unwrap();
+        assert_eq!(load(&mut bridge, &files).unwrap(), 2);
+        assert_eq!(load(&mut bridge, &files).unwrap(), 0);
+        assert_eq!(bridge.roots(2).unwrap(), expected.roots(2).unwrap());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_bad_files() {
+        let dir = temp_dir("bad");
+        let chain = tests::chain();
+
+        // Without xor.dat the files are read as they are
+        write_file(&dir.join("blk00000.dat"), &[&chain[1]], [0; 8]);
+        let files = BlockFiles::open(&dir, Network::Regtest).unwrap();
+        assert!(matches!(files.best_chain(), Err(Error::NoGenesis)));
+
+        // Blocks for another network
+        let files = BlockFiles::open(&dir, Network::Bitcoin).unwrap();
+        assert!(matches!(files.headers(), Err(Error::BadMagic { offset: 0, .. })));
+
+        fs::write(dir.join("xor.dat"), [1, 2, 3]).unwrap();
+        assert!(matches!(BlockFiles::open(&dir, Network::Regtest), Err(Error::Io(_))));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,424 @@
+// Rustreexo
+
+//! The bridge keeps the whole accumulator and the leaf data of every unspent
+//! output, so it can make the proofs that nodes with only the roots need.
+
+pub mod blockfile;
+#[cfg(feature = "rpc")]
+pub mod rpc;
+pub mod server;
+pub mod snapshot;
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use bitcoin::{Block, BlockHash, BitcoinHash, OutPoint, Txid};
+use bitcoin_hashes::{sha256, Hash};
+
+use crate::accumulator::{self, pollard::Pollard, types};
+use crate::network::message::{UData, UtreexoRoot};
+
+/// Error is returned by the bridge when a block or a request doesn't fit
+/// its state.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    /// The accumulator couldn't do what was asked of it.
+    Accumulator(accumulator::error::Error),
+
+    /// The block doesn't build on the last block the bridge has.
+    NotOnTip { prev_blockhash: BlockHash, tip: Option<BlockHash> },
+
+    /// The output is spent or was never created.
+    UnknownOutPoint(OutPoint),
+
+    /// There's no block with the hash.
+    UnknownBlock(BlockHash),
+
+    /// There's no block at the height.
+    UnknownHeight(u32),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Accumulator(err) => write!(f, "{}", err),
+            Error::NotOnTip { prev_blockhash, tip: Some(tip) } => {
+                write!(f, "block builds on {} but the tip is {}", prev_blockhash, tip)
+            }
+            Error::NotOnTip { prev_blockhash, tip: None } => {
+                write!(f, "block builds on {} but there are no blocks", prev_blockhash)
+            }
+            Error::UnknownOutPoint(outpoint) => write!(f, "output {} is not unspent", outpoint),
+            Error::UnknownBlock(hash) => write!(f, "block {} is not known", hash),
+            Error::UnknownHeight(height) => write!(f, "there's no block at height {}", height),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<accumulator::error::Error> for Error {
+    fn from(err: accumulator::error::Error) -> Error {
+        Error::Accumulator(err)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// BlockState is what the bridge keeps for each block it has processed.
+struct BlockState {
+    hash: BlockHash,
+
+    // Roots and number of leaves after the block
+    roots: Vec<sha256::Hash>,
+    num_leaves: u64,
+
+    // Proof and leaf data of what the block spends
+    udata: UData,
+}
+
+/// Bridge builds the accumulator block by block, starting from the genesis
+/// block at height 0 or from a snapshot of the unspent outputs. Every leaf is
+/// remembered so any unspent output can be proven.
+pub struct Bridge {
+    pollard: Pollard,
+    utxos: HashMap<OutPoint, types::LeafData>,
+    blocks: Vec<BlockState>,
+    heights: HashMap<BlockHash, u32>,
+
+    // Height of the first block in blocks
+    start: u32,
+}
+
+impl Default for Bridge {
+    fn default() -> Bridge {
+        Bridge::new()
+    }
+}
+
+impl Bridge {
+    /// Returns a bridge without any blocks
+    pub fn new() -> Bridge {
+        Bridge {
+            pollard: Pollard::with_leaf_index(),
+            utxos: HashMap::new(),
+            blocks: Vec::new(),
+            heights: HashMap::new(),
+            start: 0,
+        }
+    }
+
+    /// from_utxos returns a bridge whose last block is the one with the hash
+    /// at the height, with the unspent outputs there were after it. The leaves
+    /// are added in the order the outputs are given. Nothing is known about
+    /// the blocks before it, so the roots and proofs start from there.
+    pub fn from_utxos(block_hash: BlockHash, height: u32, utxos: Vec<types::LeafData>) -> Result<Bridge> {
+        let mut bridge = Bridge::starting_at(block_hash, height);
+        bridge.add_utxos(utxos)?;
+        Ok(bridge)
+    }
+
+    // starting_at returns a bridge whose last block is the one with the hash
+    // at the height, without any unspent outputs yet.
+    fn starting_at(block_hash: BlockHash, height: u32) -> Bridge {
+        let mut bridge = Bridge { start: height, ..Bridge::new() };
+        bridge.heights.insert(block_hash, height);
+        bridge.blocks.push(BlockState {
+            hash: block_hash,
+            roots: Vec::new(),
+            num_leaves: 0,
+            udata: UData { proof: Default::default(), leaf_data: Vec::new() },
+        });
+        bridge
+    }
+
+    // add_utxos adds the unspent outputs after the leaves there are and
+    // makes the roots of the last block the new ones. It's only for building
+    // a bridge from its first block, since the roots of that block change.
+    fn add_utxos(&mut self, utxos: Vec<types::LeafData>) -> Result<()> {
+        let leaves = utxos.iter().map(|data| types::Leaf { hash: data.leaf_hash(), remember: true }).collect();
+        self.pollard.modify_by_hash(leaves, &[])?;
+        self.utxos.extend(utxos.into_iter().map(|data| (data.outpoint(), data)));
+
+        let state = self.blocks.last_mut().expect("the bridge has a first block");
+        state.roots = self.pollard.roots();
+        state.num_leaves = self.pollard.num_leaves;
+        Ok(())
+    }
+
+    /// process_block deletes what the block spends from the accumulator and
+    /// adds what it creates. Outputs that are spent in the same block and
+    /// ones that can't be spent are never added, and neither is the output
+    /// of the genesis block since Bitcoin Core never puts it in its unspent
+    /// outputs. The block has to build on the last one. Errors without
+    /// changing anything if the block doesn't fit.
+    pub fn process_block(&mut self, block: &Block) -> Result<()> {
+        let tip = self.blocks.last().map(|state| state.hash);
+        if tip.map_or(false, |tip| tip != block.header.prev_blockhash) ||
+            (tip.is_none() && block.header.prev_blockhash != BlockHash::default()) {
+            return Err(Error::NotOnTip { prev_blockhash: block.header.prev_blockhash, tip });
+        }
+
+        let height = self.start + self.blocks.len() as u32;
+        let block_hash = block.bitcoin_hash();
+
+        // The txids are only hashed once, and the outputs of the block are
+        // looked up by them
+        let txids: Vec<Txid> = block.txdata.iter().map(|tx| tx.txid()).collect();
+        let created: HashMap<Txid, usize> = txids.iter().zip(&block.txdata)
+            .map(|(txid, tx)| (*txid, tx.output.len()))
+            .collect();
+
+        let spent: HashSet<OutPoint> = block.txdata.iter()
+            .filter(|tx| !tx.is_coin_base())
+            .flat_map(|tx| tx.input.iter().map(|input| input.previous_output))
+            .collect();
+
+        let mut adds = Vec::new();
+        for (tx, txid) in block.txdata.iter().zip(&txids).filter(|_| height > 0) {
+            let txid = *txid;
+            for (vout, output) in tx.output.iter().enumerate() {
+                let outpoint = OutPoint { txid, vout: vout as u32 };
+                if spent.contains(&outpoint) || output.script_pubkey.is_provably_unspendable() {
+                    continue
+                }
+
+                adds.push(types::LeafData::new(block_hash.into_inner(), outpoint, height as i32, tx.is_coin_base(),
+                                               output.value as i64, output.script_pubkey.to_bytes()));
+            }
+        }
+
+        // What the block spends from earlier blocks, in the order it's spent
+        let mut leaf_data = Vec::new();
+        for tx in block.txdata.iter().filter(|tx| !tx.is_coin_base()) {
+            for input in &tx.input {
+                match self.utxos.get(&input.previous_output) {
+                    Some(data) => leaf_data.push(data.clone()),
+                    // Spending an output of the block doesn't touch the accumulator
+                    None if created.get(&input.previous_output.txid)
+                        .map_or(false, |outputs| (input.previous_output.vout as usize) < *outputs) => {}
+                    None => return Err(Error::UnknownOutPoint(input.previous_output)),
+                }
+            }
+        }
+
+        let del_hashes: Vec<sha256::Hash> = leaf_data.iter().map(|data| data.leaf_hash()).collect();
+        let proof = self.pollard.prove(&del_hashes)?;
+
+        let leaves = adds.iter().map(|data| types::Leaf { hash: data.leaf_hash(), remember: true }).collect();
+        self.pollard.modify_by_hash(leaves, &del_hashes)?;
+
+        for data in &leaf_data {
+            self.utxos.remove(&data.outpoint());
+        }
+        self.utxos.extend(adds.into_iter().map(|data| (data.outpoint(), data)));
+
+        self.heights.insert(block_hash, height);
+        self.blocks.push(BlockState {
+            hash: block_hash,
+            roots: self.pollard.roots(),
+            num_leaves: self.pollard.num_leaves,
+            udata: UData { proof, leaf_data },
+        });
+
+        Ok(())
+    }
+
+    /// height returns the height of the last block, or None if there are no
+    /// blocks.
+    pub fn height(&self) -> Option<u32> {
+        self.blocks.len().checked_sub(1).map(|index| self.start + index as u32)
+    }
+
+    /// block_hash returns the hash of the block at the height.
+    pub fn block_hash(&self, height: u32) -> Result<BlockHash> {
+        self.state(height).map(|state| state.hash)
+    }
+
+    /// roots returns the state of the accumulator after the block at the
+    /// height.
+    pub fn roots(&self, height: u32) -> Result<UtreexoRoot> {
+        let state = self.state(height)?;
+        Ok(UtreexoRoot { block_hash: state.hash, num_leaves: state.num_leaves, roots: state.roots.clone() })
+    }
+
+    /// block_udata returns the proof and the leaf data of what the block with
+    /// the hash spends. The proof is for the accumulator before the block.
+    pub fn block_udata(&self, block_hash: &BlockHash) -> Result<UData> {
+        let height = self.heights.get(block_hash).ok_or(Error::UnknownBlock(*block_hash))?;
+        Ok(self.blocks[(*height - self.start) as usize].udata.clone())
+    }
+
+    /// prove returns the proof and the leaf data of the unspent outputs for
+    /// the accumulator as it is now.
+    pub fn prove(&self, outpoints: &[OutPoint]) -> Result<UData> {
+        let leaf_data = outpoints.iter()
+            .map(|outpoint| self.utxos.get(outpoint).cloned().ok_or(Error::UnknownOutPoint(*outpoint)))
+            .collect::<Result<Vec<types::LeafData>>>()?;
+
+        let hashes: Vec<sha256::Hash> = leaf_data.iter().map(|data| data.leaf_hash()).collect();
+        Ok(UData { proof: self.pollard.prove(&hashes)?, leaf_data })
+    }
+
+    /// leaf_hash returns the hash of the leaf of the unspent output.
+    pub fn leaf_hash(&self, outpoint: &OutPoint) -> Result<sha256::Hash> {
+        self.utxos.get(outpoint).map(|data| data.leaf_hash()).ok_or(Error::UnknownOutPoint(*outpoint))
+    }
+
+    /// num_utxos returns how many unspent outputs there are.
+    pub fn num_utxos(&self) -> usize {
+        self.utxos.len()
+    }
+
+    /// pollard returns the accumulator as it is now.
+    pub fn pollard(&self) -> &Pollard {
+        &self.pollard
+    }
+
+    fn state(&self, height: u32) -> Result<&BlockState> {
+        height.checked_sub(self.start)
+            .and_then(|index| self.blocks.get(index as usize))
+            .ok_or(Error::UnknownHeight(height))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin::blockdata::opcodes;
+    use bitcoin::{Block, BlockHeader, BlockHash, BitcoinHash, OutPoint, Script, Transaction, TxIn, TxOut};
+
+    use super::{Bridge, Error};
+
+    fn tx(inputs: &[OutPoint], values: &[u64]) -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input: inputs.iter()
+                .map(|outpoint| TxIn { previous_output: *outpoint, script_sig: Script::new(), sequence: 0, witness: vec![] })
+                .collect(),
+            output: values.iter()
+                .map(|value| TxOut { value: *value, script_pubkey: Builder::new().push_int(*value as i64).into_script() })
+                .collect(),
+        }
+    }
+
+    // block returns a block after prev with a coinbase that pays to value
+    // and the given transactions.
+    pub(crate) fn block(prev: BlockHash, value: u64, txs: Vec<Transaction>) -> Block {
+        let mut coinbase = tx(&[OutPoint::null()], &[value]);
+        coinbase.lock_time = value as u32;
+
+        let mut txdata = vec![coinbase];
+        txdata.extend(txs);
+
+        let header = BlockHeader {
+            version: 1,
+            prev_blockhash: prev,
+            merkle_root: Default::default(),
+            time: 0,
+            bits: 0x207fffff,
+            nonce: 0,
+        };
+        Block { header, txdata }
+    }
+
+    // chain returns a few blocks that spend each other's outputs, including
+    // an output spent in the block that creates it and one that can't be
+    // spent.
+    pub(crate) fn chain() -> Vec<Block> {
+        let genesis = block(BlockHash::default(), 50, vec![]);
+
+        let spend = tx(&[], &[20, 30]);
+        let spend_again = tx(&[OutPoint { txid: spend.txid(), vout: 1 }], &[29]);
+        let mut burn = tx(&[], &[]);
+        burn.output.push(TxOut { value: 0, script_pubkey: Builder::new().push_opcode(opcodes::all::OP_RETURN).into_script() });
+        let first = block(genesis.bitcoin_hash(), 51, vec![spend.clone(), spend_again, burn]);
+
+        let second = block(first.bitcoin_hash(), 52, vec![tx(&[OutPoint { txid: spend.txid(), vout: 0 }], &[19])]);
+
+        vec![genesis, first, second]
+    }
+
+    #[test]
+    fn test_process_block() {
+        let blocks = chain();
+        let mut bridge = Bridge::new();
+        for block in &blocks {
+            bridge.process_block(block).unwrap();
+        }
+        assert_eq!(bridge.height(), Some(2));
+
+        // The genesis block adds nothing. The first block adds its coinbase,
+        // the first output of spend and the one of spend_again. The second
+        // spends the output of spend and adds two.
+        assert_eq!(bridge.roots(0).unwrap().num_leaves, 0);
+        assert_eq!(bridge.roots(1).unwrap().num_leaves, 3);
+        assert_eq!(bridge.roots(2).unwrap().num_leaves, 4);
+        assert_eq!(bridge.utxos.len(), 4);
+
+        // Every block's proof checks out against the roots before it
+        for height in 1..3 {
+            let before = bridge.roots(height - 1).unwrap();
+            let udata = bridge.block_udata(&blocks[height as usize].bitcoin_hash()).unwrap();
+            let hashes: Vec<_> = udata.leaf_data.iter().map(|data| data.leaf_hash()).collect();
+            assert_eq!(hashes.len(), height as usize - 1);
+            assert!(udata.proof.verify(&hashes, &before.roots, before.num_leaves));
+        }
+
+        let coinbase = OutPoint { txid: blocks[2].txdata[0].txid(), vout: 0 };
+        let udata = bridge.prove(&[coinbase]).unwrap();
+        let now = bridge.roots(2).unwrap();
+        assert!(udata.proof.verify(&[udata.leaf_data[0].leaf_hash()], &now.roots, now.num_leaves));
+
+        let spent = OutPoint { txid: blocks[2].txdata[1].input[0].previous_output.txid, vout: 0 };
+        assert_eq!(bridge.prove(&[spent]).unwrap_err(), Error::UnknownOutPoint(spent));
+    }
+
+    #[test]
+    fn test_from_utxos() {
+        let blocks = chain();
+        let mut bridge = Bridge::new();
+        for block in &blocks {
+            bridge.process_block(block).unwrap();
+        }
+        let tip = blocks[2].bitcoin_hash();
+        let mut utxos: Vec<_> = bridge.utxos.values().cloned().collect();
+        utxos.sort_by_key(|data| data.outpoint());
+
+        // Adding the outputs a few at a time gives the same roots
+        let all = Bridge::from_utxos(tip, 2, utxos.clone()).unwrap();
+        let mut batched = Bridge::starting_at(tip, 2);
+        for batch in utxos.chunks(3) {
+            batched.add_utxos(batch.to_vec()).unwrap();
+        }
+        assert_eq!(all.roots(2).unwrap(), batched.roots(2).unwrap());
+        assert_eq!(batched.roots(2).unwrap().num_leaves, 4);
+        assert_eq!(batched.num_utxos(), 4);
+    }
+
+    #[test]
+    fn test_bad_blocks() {
+        let blocks = chain();
+        let mut bridge = Bridge::new();
+        assert!(matches!(bridge.process_block(&blocks[1]), Err(Error::NotOnTip { tip: None, .. })));
+
+        bridge.process_block(&blocks[0]).unwrap();
+
+        // Spends something that doesn't exist
+        let missing = OutPoint { txid: Default::default(), vout: 7 };
+        let bad = block(blocks[0].bitcoin_hash(), 1, vec![tx(&[missing], &[1])]);
+        assert_eq!(bridge.process_block(&bad), Err(Error::UnknownOutPoint(missing)));
+
+        // The output of the genesis block can't be spent
+        let genesis = OutPoint { txid: blocks[0].txdata[0].txid(), vout: 0 };
+        let bad = block(blocks[0].bitcoin_hash(), 1, vec![tx(&[genesis], &[1])]);
+        assert_eq!(bridge.process_block(&bad), Err(Error::UnknownOutPoint(genesis)));
+        assert_eq!(bridge.roots(0).unwrap().num_leaves, 0);
+
+        // Nothing changed, so the real block still fits
+        assert_eq!(bridge.height(), Some(0));
+        bridge.process_block(&blocks[1]).unwrap();
+        assert!(matches!(bridge.process_block(&blocks[1]), Err(Error::NotOnTip { .. })));
+    }
+}
@@ -0,0 +1,404 @@
+// Rustreexo
+
+//! A JSON-RPC server for looking at the state of a running bridge.
+//!
+//! Requests are JSON-RPC 2.0 calls POSTed over HTTP, one per connection, the
+//! same way bitcoind takes them. The methods are:
+//!
+//! - getroots [height]: the roots and the number of leaves after the block at
+//!   the height, or the last block
+//! - getleafcount [height]: the number of leaves after the block
+//! - getproof [items]: the proof for the unspent outputs, each given as
+//!   "txid:vout" or as the hex hash of its leaf
+//! - verifyproof proof hashes [height]: whether the proof from getproof is
+//!   good for the hashes against the roots after the block
+//! - getforeststats: the size of the forest and of what the bridge keeps
+
+use std::convert::TryFrom;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use bitcoin::OutPoint;
+use bitcoin_hashes::hex::FromHex;
+use bitcoin_hashes::sha256;
+use serde_json::{json, Value};
+
+use super::Bridge;
+use crate::accumulator::{proof::BatchProof, util};
+
+/// The request isn't JSON.
+pub const PARSE_ERROR: i64 = -32700;
+
+/// The request is JSON but not a JSON-RPC request.
+pub const INVALID_REQUEST: i64 = -32600;
+
+/// There's no method with the name.
+pub const METHOD_NOT_FOUND: i64 = -32601;
+
+/// The params don't fit the method.
+pub const INVALID_PARAMS: i64 = -32602;
+
+/// The bridge can't answer the call, like for an output that isn't unspent.
+pub const BRIDGE_ERROR: i64 = -1;
+
+/// Bodies bigger than this are refused.
+pub const MAX_BODY_SIZE: usize = 4 * 1024 * 1024;
+
+/// Request and header lines longer than this are refused.
+pub const MAX_LINE_SIZE: usize = 8 * 1024;
+
+/// Connections that send nothing for this long are dropped.
+pub const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl ToString) -> RpcError {
+        RpcError { code, message: message.to_string() }
+    }
+
+    fn params(message: impl ToString) -> RpcError {
+        RpcError::new(INVALID_PARAMS, message)
+    }
+}
+
+impl From<super::Error> for RpcError {
+    fn from(err: super::Error) -> RpcError {
+        RpcError::new(BRIDGE_ERROR, err)
+    }
+}
+
+/// handle answers a JSON-RPC request with what the bridge has. Both the
+/// request and the response are JSON text.
+pub fn handle(bridge: &Bridge, request: &str) -> String {
+    let (id, result) = match serde_json::from_str::<Value>(request) {
+        Ok(request) => {
+            let id = request.get("id").cloned().unwrap_or(Value::Null);
+            (id, call_request(bridge, &request))
+        }
+        Err(err) => (Value::Null, Err(RpcError::new(PARSE_ERROR, err))),
+    };
+
+    let response = match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(err) => json!({ "jsonrpc": "2.0", "error": { "code": err.code, "message": err.message }, "id": id }),
+    };
+    response.to_string()
+}
+
+fn call_request(bridge: &Bridge, request: &Value) -> Result<Value, RpcError> {
+    let method = request.get("method").and_then(Value::as_str)
+        .ok_or_else(|| RpcError::new(INVALID_REQUEST, "method is missing"))?;
+    let params = match request.get("params") {
+        None | Some(Value::Null) => Vec::new(),
+        Some(Value::Array(params)) => params.clone(),
+        Some(_) => return Err(RpcError::new(INVALID_REQUEST, "params have to be a list")),
+    };
+
+    match method {
+        "getroots" => get_roots(bridge, &params),
+        "getleafcount" => {
+            let height = height_param(bridge, &params, 0)?;
+            Ok(json!(bridge.roots(height)?.num_leaves))
+        }
+        "getproof" => get_proof(bridge, &params),
+        "verifyproof" => verify_proof(bridge, &params),
+        "getforeststats" => get_forest_stats(bridge),
+        _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("method {} not found", method))),
+    }
+}
+
+fn get_roots(bridge: &Bridge, params: &[Value]) -> Result<Value, RpcError> {
+    let height = height_param(bridge, params, 0)?;
+    let roots = bridge.roots(height)?;
+
+    Ok(json!({
+        "height": height,
+        "block_hash": roots.block_hash.to_string(),
+        "num_leaves": roots.num_leaves,
+        "roots": roots.roots,
+    }))
+}
+
+fn get_proof(bridge: &Bridge, params: &[Value]) -> Result<Value, RpcError> {
+    let items = params.first().and_then(Value::as_array)
+        .ok_or_else(|| RpcError::params("expected a list of outpoints or leaf hashes"))?;
+
+    let hashes = items.iter()
+        .map(|item| {
+            let item = item.as_str().ok_or_else(|| RpcError::params("items have to be strings"))?;
+            if item.contains(':') {
+                let outpoint: OutPoint = item.parse().map_err(|_| RpcError::params(format!("bad outpoint {}", item)))?;
+                Ok(bridge.leaf_hash(&outpoint)?)
+            } else {
+                sha256::Hash::from_hex(item).map_err(|_| RpcError::params(format!("bad leaf hash {}", item)))
+            }
+        })
+        .collect::<Result<Vec<sha256::Hash>, RpcError>>()?;
+
+    let proof = bridge.pollard().prove(&hashes).map_err(super::Error::from)?;
+    Ok(json!({ "height": bridge.height(), "proof": proof, "hashes": hashes }))
+}
+
+fn verify_proof(bridge: &Bridge, params: &[Value]) -> Result<Value, RpcError> {
+    let proof: BatchProof = params.first().cloned().map(serde_json::from_value)
+        .ok_or_else(|| RpcError::params("proof is missing"))?
+        .map_err(|err| RpcError::params(format!("bad proof: {}", err)))?;
+    let hashes: Vec<sha256::Hash> = params.get(1).cloned().map(serde_json::from_value)
+        .ok_or_else(|| RpcError::params("hashes are missing"))?
+        .map_err(|err| RpcError::params(format!("bad hashes: {}", err)))?;
+
+    let roots = bridge.roots(height_param(bridge, params, 2)?)?;
+    Ok(json!(proof.verify(&hashes, &roots.roots, roots.num_leaves)))
+}
+
+fn get_forest_stats(bridge: &Bridge) -> Result<Value, RpcError> {
+    let pollard = bridge.pollard();
+
+    Ok(json!({
+        "height": bridge.height(),
+        "num_leaves": pollard.num_leaves,
+        "forest_rows": util::tree_rows(pollard.num_leaves),
+        "num_roots": pollard.roots().len(),
+        "num_nodes": pollard.num_nodes(),
+        "num_utxos": bridge.num_utxos(),
+    }))
+}
+
+// height_param returns the height at the index of the params, or the height
+// of the last block if it's not there.
+fn height_param(bridge: &Bridge, params: &[Value], index: usize) -> Result<u32, RpcError> {
+    match params.get(index) {
+        None | Some(Value::Null) => bridge.height().ok_or_else(|| RpcError::new(BRIDGE_ERROR, "there are no blocks")),
+        Some(height) => height.as_u64().and_then(|height| u32::try_from(height).ok())
+            .ok_or_else(|| RpcError::params("height has to be a number")),
+    }
+}
+
+/// RpcServer answers JSON-RPC requests about the bridge over HTTP.
+pub struct RpcServer {
+    listener: TcpListener,
+    bridge: Arc<RwLock<Bridge>>,
+}
+
+impl RpcServer {
+    /// bind returns a server listening on the address. It doesn't answer
+    /// anything until it's run.
+    pub fn bind(addr: impl ToSocketAddrs, bridge: Arc<RwLock<Bridge>>) -> io::Result<RpcServer> {
+        Ok(RpcServer { listener: TcpListener::bind(addr)?, bridge })
+    }
+
+    /// local_addr returns the address the server is listening on.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// run accepts connections and answers each of them on its own thread.
+    /// Only returns if accepting a connection fails.
+    pub fn run(&self) -> io::Result<()> {
+        loop {
+            let (stream, _) = self.listener.accept()?;
+            let bridge = Arc::clone(&self.bridge);
+
+            thread::spawn(move || serve(stream, &bridge).ok());
+        }
+    }
+}
+
+// serve reads one HTTP request from the connection and answers it.
+fn serve(mut stream: TcpStream, bridge: &RwLock<Bridge>) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let (status, body) = match read_head(&mut reader) {
+        Err(err) if err.kind() == io::ErrorKind::InvalidData => ("400 Bad Request", String::new()),
+        Err(err) => return Err(err),
+        Ok((false, _)) => ("405 Method Not Allowed", String::new()),
+        Ok((true, None)) => ("411 Length Required", String::new()),
+        Ok((true, Some(len))) if len > MAX_BODY_SIZE => ("413 Payload Too Large", String::new()),
+        Ok((true, Some(len))) => {
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body)?;
+            ("200 OK", handle(&bridge.read().unwrap(), &String::from_utf8_lossy(&body)))
+        }
+    };
+
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+           status, body.len(), body)?;
+    stream.flush()
+}
+
+// read_head reads the request line and the headers. It returns whether the
+// request is a POST and the content length, if there's one.
+fn read_head(reader: &mut impl BufRead) -> io::Result<(bool, Option<usize>)> {
+    let mut line = String::new();
+    read_line(reader, &mut line)?;
+    let is_post = line.starts_with("POST ");
+
+    let mut content_length = None;
+    loop {
+        line.clear();
+        if read_line(reader, &mut line)? == 0 || line.trim().is_empty() {
+            return Ok((is_post, content_length))
+        }
+
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse::<usize>().ok();
+            }
+        }
+    }
+}
+
+// read_line is BufRead::read_line, but errors with InvalidData instead of
+// reading more than MAX_LINE_SIZE bytes.
+fn read_line(reader: &mut impl BufRead, line: &mut String) -> io::Result<usize> {
+    let read = reader.take(MAX_LINE_SIZE as u64).read_line(line)?;
+    if read == MAX_LINE_SIZE && !line.ends_with('\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor, Read, Write};
+    use std::net::TcpStream;
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+
+    use bitcoin::{BitcoinHash, OutPoint};
+    use serde_json::{json, Value};
+
+    use super::super::{tests::chain, Bridge};
+    use super::{handle, read_head, RpcServer, MAX_LINE_SIZE};
+
+    fn bridge() -> Bridge {
+        let mut bridge = Bridge::new();
+        for block in chain() {
+            bridge.process_block(&block).unwrap();
+        }
+        bridge
+    }
+
+    fn call(bridge: &Bridge, method: &str, params: Value) -> Value {
+        let request = json!({ "jsonrpc": "2.0", "id": 7, "method": method, "params": params });
+        let response: Value = serde_json::from_str(&handle(bridge, &request.to_string())).unwrap();
+        assert_eq!(response["id"], 7);
+        response
+    }
+
+    #[test]
+    fn test_get_roots() {
+        let bridge = bridge();
+
+        let tip = bridge.roots(2).unwrap();
+        let result = &call(&bridge, "getroots", json!([]))["result"];
+        assert_eq!(result["height"], 2);
+        assert_eq!(result["block_hash"], tip.block_hash.to_string());
+        assert_eq!(result["num_leaves"], tip.num_leaves);
+        assert_eq!(result["roots"], json!(tip.roots.iter().map(|root| root.to_string()).collect::<Vec<_>>()));
+
+        assert_eq!(call(&bridge, "getleafcount", json!([1]))["result"], 3);
+        assert_eq!(call(&bridge, "getleafcount", json!([]))["result"], 4);
+        assert_eq!(call(&bridge, "getleafcount", json!([9]))["error"]["code"], super::BRIDGE_ERROR);
+        assert_eq!(call(&bridge, "getleafcount", json!(["x"]))["error"]["code"], super::INVALID_PARAMS);
+    }
+
+    #[test]
+    fn test_get_proof() {
+        let blocks = chain();
+        let bridge = bridge();
+
+        let outpoint = OutPoint { txid: blocks[2].txdata[0].txid(), vout: 0 };
+        let other = bridge.leaf_hash(&OutPoint { txid: blocks[1].txdata[0].txid(), vout: 0 }).unwrap();
+        let result = call(&bridge, "getproof", json!([[outpoint.to_string(), other.to_string()]]))["result"].clone();
+        assert_eq!(result["hashes"], json!([bridge.leaf_hash(&outpoint).unwrap().to_string(), other.to_string()]));
+
+        // What getproof returns is what verifyproof takes
+        assert_eq!(call(&bridge, "verifyproof", json!([result["proof"], result["hashes"]]))["result"], true);
+        assert_eq!(call(&bridge, "verifyproof", json!([result["proof"], result["hashes"], 1]))["result"], false);
+        assert_eq!(call(&bridge, "verifyproof", json!([result["proof"], [other.to_string()]]))["result"], false);
+        assert_eq!(call(&bridge, "verifyproof", json!([{ "targets": 1 }, []]))["error"]["code"], super::INVALID_PARAMS);
+
+        let spent = OutPoint { txid: blocks[0].txdata[0].txid(), vout: 0 };
+        let response = call(&bridge, "getproof", json!([[spent.to_string()]]));
+        assert_eq!(response["error"]["code"], super::BRIDGE_ERROR);
+        assert_eq!(call(&bridge, "getproof", json!([["zz"]]))["error"]["code"], super::INVALID_PARAMS);
+    }
+
+    #[test]
+    fn test_get_forest_stats() {
+        let bridge = bridge();
+        let result = &call(&bridge, "getforeststats", json!([]))["result"];
+        assert_eq!(result["height"], 2);
+        assert_eq!(result["num_leaves"], 4);
+        assert_eq!(result["forest_rows"], 2);
+        assert_eq!(result["num_roots"], 1);
+        assert_eq!(result["num_nodes"], 7);
+        assert_eq!(result["num_utxos"], 4);
+    }
+
+    #[test]
+    fn test_bad_requests() {
+        let bridge = bridge();
+
+        let response: Value = serde_json::from_str(&handle(&bridge, "{")).unwrap();
+        assert_eq!(response["error"]["code"], super::PARSE_ERROR);
+        assert_eq!(response["id"], Value::Null);
+
+        assert_eq!(call(&bridge, "getblock", json!([]))["error"]["code"], super::METHOD_NOT_FOUND);
+        assert_eq!(call(&bridge, "getroots", json!({}))["error"]["code"], super::INVALID_REQUEST);
+    }
+
+    #[test]
+    fn test_http() {
+        let blocks = chain();
+        let bridge = Arc::new(RwLock::new(bridge()));
+
+        let server = RpcServer::bind("127.0.0.1:0", bridge).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        let post = |body: &str| {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            write!(stream, "POST / HTTP/1.1\r\nHost: localhost\r\ncontent-length: {}\r\n\r\n{}", body.len(), body).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let response = post(r#"{"jsonrpc":"2.0","id":"a","method":"getroots","params":[0]}"#);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let body: Value = serde_json::from_str(response.split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(body["id"], "a");
+        assert_eq!(body["result"]["block_hash"], blocks[0].bitcoin_hash().to_string());
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 405"));
+    }
+
+    #[test]
+    fn test_long_lines() {
+        let head = |head: String| read_head(&mut Cursor::new(head.into_bytes()));
+
+        let header = format!("X-Pad: {}\r\n", "a".repeat(MAX_LINE_SIZE - 9));
+        assert_eq!(head(format!("POST / HTTP/1.1\r\n{}Content-Length: 2\r\n\r\n", header)).unwrap(), (true, Some(2)));
+
+        let header = format!("X-Pad: {}\r\n", "a".repeat(MAX_LINE_SIZE - 8));
+        let err = head(format!("POST / HTTP/1.1\r\n{}Content-Length: 2\r\n\r\n", header)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let err = head(format!("POST /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_LINE_SIZE))).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,374 @@
+// Rustreexo
+
+//! A proof server for nodes on the same machine as the bridge.
+//!
+//! Requests and responses are sent as frames: the length of the payload as
+//! a little endian u32 and then the payload. The payload is a byte for the
+//! kind of message and then its fields, consensus encoded. A connection can
+//! send any number of requests and gets one response for each of them, in
+//! order, until it's idle for READ_TIMEOUT.
+
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use bitcoin::consensus::encode::{self, Decodable, Encodable};
+use bitcoin::consensus::serialize;
+use bitcoin::{BlockHash, OutPoint};
+
+use super::Bridge;
+use crate::network::message::{decode_vec, encode_vec, UData, UtreexoRoot};
+
+/// Frames bigger than this are refused so a peer can't make the other side
+/// allocate whatever it wants.
+pub const MAX_FRAME_SIZE: u32 = 32 * 1024 * 1024;
+
+/// Connections past this many are closed as soon as they're accepted, as
+/// each one has a thread.
+pub const MAX_CONNECTIONS: usize = 64;
+
+/// Connections that send nothing for this long are closed. Nodes can
+/// connect again when they need something.
+pub const READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Request is what a node asks the bridge for.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Request {
+    /// The roots after the block at the height
+    GetRoots(u32),
+
+    /// The proof and the leaf data of what the block with the hash spends
+    GetBlockUData(BlockHash),
+
+    /// The proof and the leaf data of the unspent outputs
+    GetProof(Vec<OutPoint>),
+}
+
+/// Response is what the bridge answers a request with.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Response {
+    /// The request couldn't be answered, and why
+    Error(String),
+
+    Roots(UtreexoRoot),
+    UData(UData),
+}
+
+impl Encodable for Request {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let len = match self {
+            Request::GetRoots(height) => 0u8.consensus_encode(&mut w)? + height.consensus_encode(&mut w)?,
+            Request::GetBlockUData(hash) => 1u8.consensus_encode(&mut w)? + hash.consensus_encode(&mut w)?,
+            Request::GetProof(outpoints) => 2u8.consensus_encode(&mut w)? + encode_vec(outpoints, &mut w)?,
+        };
+        Ok(len)
+    }
+}
+
+impl Decodable for Request {
+    fn consensus_decode<R: io::Read>(mut r: R) -> Result<Request, encode::Error> {
+        let request = match u8::consensus_decode(&mut r)? {
+            0 => Request::GetRoots(Decodable::consensus_decode(&mut r)?),
+            1 => Request::GetBlockUData(Decodable::consensus_decode(&mut r)?),
+            2 => Request::GetProof(decode_vec(&mut r)?),
+            _ => return Err(encode::Error::ParseFailed("unknown request")),
+        };
+        Ok(request)
+    }
+}
+
+impl Encodable for Response {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let len = match self {
+            Response::Error(msg) => 0u8.consensus_encode(&mut w)? + msg.consensus_encode(&mut w)?,
+            Response::Roots(roots) => 1u8.consensus_encode(&mut w)? + roots.consensus_encode(&mut w)?,
+            Response::UData(udata) => 2u8.consensus_encode(&mut w)? + udata.consensus_encode(&mut w)?,
+        };
+        Ok(len)
+    }
+}
+
+impl Decodable for Response {
+    fn consensus_decode<R: io::Read>(mut r: R) -> Result<Response, encode::Error> {
+        let response = match u8::consensus_decode(&mut r)? {
+            0 => Response::Error(Decodable::consensus_decode(&mut r)?),
+            1 => Response::Roots(Decodable::consensus_decode(&mut r)?),
+            2 => Response::UData(Decodable::consensus_decode(&mut r)?),
+            _ => return Err(encode::Error::ParseFailed("unknown response")),
+        };
+        Ok(response)
+    }
+}
+
+/// Server answers the requests of the nodes that connect to it with what
+/// the bridge has. The bridge can keep processing blocks while it runs.
+pub struct Server {
+    listener: TcpListener,
+    bridge: Arc<RwLock<Bridge>>,
+}
+
+impl Server {
+    /// bind returns a server listening on the address. It doesn't answer
+    /// anything until it's run.
+    pub fn bind(addr: impl ToSocketAddrs, bridge: Arc<RwLock<Bridge>>) -> io::Result<Server> {
+        Ok(Server { listener: TcpListener::bind(addr)?, bridge })
+    }
+
+    /// local_addr returns the address the server is listening on.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// run accepts connections and serves each of them on its own thread,
+    /// up to MAX_CONNECTIONS at a time. Only returns if accepting a
+    /// connection fails.
+    pub fn run(&self) -> io::Result<()> {
+        let connections = Arc::new(AtomicUsize::new(0));
+        loop {
+            let (stream, _) = self.listener.accept()?;
+
+            // Only this thread adds connections, so the count can't go over
+            // between the check and the add
+            if connections.load(Ordering::SeqCst) >= MAX_CONNECTIONS {
+                continue
+            }
+            let slot = Slot::take(&connections);
+            let bridge = Arc::clone(&self.bridge);
+
+            // A connection that breaks only matters to that node
+            thread::spawn(move || {
+                let _slot = slot;
+                serve(stream, &bridge).ok()
+            });
+        }
+    }
+}
+
+// Slot counts a connection for as long as it's kept, even if serving it
+// panics.
+struct Slot(Arc<AtomicUsize>);
+
+impl Slot {
+    fn take(connections: &Arc<AtomicUsize>) -> Slot {
+        connections.fetch_add(1, Ordering::SeqCst);
+        Slot(Arc::clone(connections))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// serve answers the requests on the connection until it's closed. A request
+// that can't be decoded is answered with an error, but a frame that's too
+// big closes the connection since what follows it can't be trusted.
+fn serve(stream: TcpStream, bridge: &RwLock<Bridge>) -> io::Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    while let Some(payload) = read_frame(&mut reader)? {
+        let response = match encode::deserialize::<Request>(&payload) {
+            Ok(request) => answer(&bridge.read().unwrap(), &request),
+            Err(err) => Response::Error(format!("bad request: {}", err)),
+        };
+
+        write_frame(&mut writer, &serialize(&response))?;
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+fn answer(bridge: &Bridge, request: &Request) -> Response {
+    let response = match request {
+        Request::GetRoots(height) => bridge.roots(*height).map(Response::Roots),
+        Request::GetBlockUData(hash) => bridge.block_udata(hash).map(Response::UData),
+        Request::GetProof(outpoints) => bridge.prove(outpoints).map(Response::UData),
+    };
+
+    response.unwrap_or_else(|err| Response::Error(err.to_string()))
+}
+
+/// Client asks a Server for roots and proofs.
+pub struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Client {
+    /// connect returns a client connected to the server at the address.
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<Client> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(Client { reader: BufReader::new(stream.try_clone()?), writer: stream })
+    }
+
+    /// request sends the request and returns the response to it.
+    pub fn request(&mut self, request: &Request) -> io::Result<Response> {
+        write_frame(&mut self.writer, &serialize(request))?;
+
+        let payload = read_frame(&mut self.reader)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "server closed the connection"))?;
+        encode::deserialize(&payload).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// roots returns the roots after the block at the height.
+    pub fn roots(&mut self, height: u32) -> io::Result<UtreexoRoot> {
+        match self.request(&Request::GetRoots(height))? {
+            Response::Roots(roots) => Ok(roots),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// block_udata returns the proof and the leaf data of what the block with
+    /// the hash spends.
+    pub fn block_udata(&mut self, block_hash: BlockHash) -> io::Result<UData> {
+        match self.request(&Request::GetBlockUData(block_hash))? {
+            Response::UData(udata) => Ok(udata),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// prove returns the proof and the leaf data of the unspent outputs.
+    pub fn prove(&mut self, outpoints: Vec<OutPoint>) -> io::Result<UData> {
+        match self.request(&Request::GetProof(outpoints))? {
+            Response::UData(udata) => Ok(udata),
+            response => Err(unexpected(response)),
+        }
+    }
+}
+
+// unexpected returns the error for a response that isn't the one that was
+// asked for.
+fn unexpected(response: Response) -> io::Error {
+    match response {
+        Response::Error(msg) => io::Error::new(io::ErrorKind::Other, msg),
+        _ => io::Error::new(io::ErrorKind::InvalidData, "got the wrong kind of response"),
+    }
+}
+
+fn write_frame(w: &mut impl Write, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_FRAME_SIZE as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame is too big"));
+    }
+
+    w.write_all(&(payload.len() as u32).to_le_bytes())?;
+    w.write_all(payload)
+}
+
+// read_frame returns the payload of the next frame, or None if the other
+// side closed the connection before starting one.
+fn read_frame(r: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match r.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+
+    let len = u32::from_le_bytes(len);
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame is too big"));
+    }
+
+    let mut payload = Vec::new();
+    r.take(len as u64).read_to_end(&mut payload)?;
+    if payload.len() != len as usize {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "frame was cut off"));
+    }
+
+    Ok(Some(payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+    use std::time::Duration;
+
+    use bitcoin::{BitcoinHash, OutPoint};
+
+    use super::super::{tests::chain, Bridge};
+    use super::{Client, Request, Response, Server, MAX_CONNECTIONS};
+
+    #[test]
+    fn test_server() {
+        let blocks = chain();
+        let bridge = Arc::new(RwLock::new(Bridge::new()));
+        bridge.write().unwrap().process_block(&blocks[0]).unwrap();
+
+        let server = Server::bind("127.0.0.1:0", Arc::clone(&bridge)).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        let mut client = Client::connect(addr).unwrap();
+        assert_eq!(client.roots(0).unwrap(), bridge.read().unwrap().roots(0).unwrap());
+
+        // Blocks processed while serving are seen right away
+        for block in &blocks[1..] {
+            bridge.write().unwrap().process_block(block).unwrap();
+        }
+
+        let before = client.roots(1).unwrap();
+        let udata = client.block_udata(blocks[2].bitcoin_hash()).unwrap();
+        let hashes: Vec<_> = udata.leaf_data.iter().map(|data| data.leaf_hash()).collect();
+        assert!(udata.proof.verify(&hashes, &before.roots, before.num_leaves));
+
+        let now = client.roots(2).unwrap();
+        let outpoints = vec![
+            OutPoint { txid: blocks[1].txdata[0].txid(), vout: 0 },
+            OutPoint { txid: blocks[2].txdata[1].txid(), vout: 0 },
+        ];
+        let udata = client.prove(outpoints.clone()).unwrap();
+        let hashes: Vec<_> = udata.leaf_data.iter().map(|data| data.leaf_hash()).collect();
+        assert_eq!(udata.leaf_data.iter().map(|data| data.outpoint()).collect::<Vec<_>>(), outpoints);
+        assert!(udata.proof.verify(&hashes, &now.roots, now.num_leaves));
+
+        // Errors come back as responses and the connection keeps working
+        let err = client.roots(3).unwrap_err();
+        assert_eq!(err.to_string(), "there's no block at height 3");
+        assert!(client.block_udata(Default::default()).is_err());
+        assert_eq!(client.request(&Request::GetRoots(2)).unwrap(), Response::Roots(now));
+
+        // So does a request that can't be decoded
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(&[1, 0, 0, 0, 9]).unwrap();
+        let mut len = [0; 4];
+        stream.read_exact(&mut len).unwrap();
+        let mut payload = vec![0; u32::from_le_bytes(len) as usize];
+        stream.read_exact(&mut payload).unwrap();
+        assert!(matches!(bitcoin::consensus::deserialize::<Response>(&payload), Ok(Response::Error(_))));
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let bridge = Arc::new(RwLock::new(Bridge::new()));
+        bridge.write().unwrap().process_block(&chain()[0]).unwrap();
+
+        let server = Server::bind("127.0.0.1:0", bridge).unwrap();
+        let addr = server.local_addr().unwrap();
+        thread::spawn(move || server.run());
+
+        // Once a client is answered it's counted
+        let mut clients: Vec<Client> = (0..MAX_CONNECTIONS).map(|_| Client::connect(addr).unwrap()).collect();
+        for client in &mut clients {
+            client.roots(0).unwrap();
+        }
+        assert!(Client::connect(addr).unwrap().roots(0).is_err());
+
+        // There's room again after a client leaves
+        clients.pop();
+        let answered = (0..100).any(|_| {
+            thread::sleep(Duration::from_millis(10));
+            Client::connect(addr).and_then(|mut client| client.roots(0)).is_ok()
+        });
+        assert!(answered);
+    }
+}
@@ -0,0 +1,644 @@
+// Rustreexo
+
+//! Reads the snapshots of the unspent outputs that Bitcoin Core writes with
+//! dumptxoutset, so a bridge can start at the block of the snapshot instead
+//! of processing every block before it.
+//!
+//! A snapshot starts with the metadata: the bytes "utxo\xff", the version as
+//! a u16, the network magic, the hash of the block the snapshot is after and
+//! the number of outputs as a u64. The outputs follow, grouped by txid: the
+//! txid, the number of outputs in the group as a compact size and then each
+//! output as its vout as a compact size and a coin. Coins are stored the way
+//! Bitcoin Core keeps them in its database, with its own varints and the
+//! amount and script compressed. Bitcoin Core writes the outputs in the
+//! order of the keys of its database, which are the txid followed by the
+//! vout as one of its varints, compared as bytes.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Read};
+
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::consensus::encode::{self, Decodable, VarInt};
+use bitcoin::hashes::Hash;
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::{BlockHash, Network, OutPoint, Script, TxOut, Txid};
+
+use super::Bridge;
+use crate::accumulator::types::LeafData;
+
+/// The bytes every snapshot starts with.
+pub const MAGIC: [u8; 5] = *b"utxo\xff";
+
+/// The version of snapshots that can be read.
+pub const VERSION: u16 = 2;
+
+// Scripts longer than this can't be spent, so Bitcoin Core doesn't keep them.
+const MAX_SCRIPT_SIZE: u64 = 10_000;
+
+// No output can have more satoshis than there will ever be.
+const MAX_MONEY: u64 = 21_000_000 * 100_000_000;
+
+// load adds this many coins to the bridge at a time.
+const LOAD_BATCH_SIZE: usize = 1 << 16;
+
+/// Error is returned when a snapshot can't be read or doesn't fit the chain
+/// it's loaded for.
+#[derive(Debug)]
+pub enum Error {
+    /// The snapshot isn't well formed.
+    Decode(encode::Error),
+
+    /// The file doesn't start with the snapshot magic.
+    NotSnapshot,
+
+    UnsupportedVersion(u16),
+
+    /// The snapshot is for another network.
+    WrongNetwork(u32),
+
+    /// The block the snapshot is after isn't in the chain it's loaded for.
+    UnknownBlock(BlockHash),
+
+    /// A coin was created after the block the snapshot is after.
+    CoinAfterBase { outpoint: OutPoint, height: u32 },
+
+    /// The coin isn't after the one before it, so the coins aren't sorted by
+    /// outpoint or it's there twice.
+    UnsortedCoin(OutPoint),
+
+    /// The bridge couldn't be built from the coins.
+    Bridge(super::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Decode(err) => write!(f, "can't decode snapshot: {}", err),
+            Error::NotSnapshot => write!(f, "not a snapshot of the unspent outputs"),
+            Error::UnsupportedVersion(version) => write!(f, "snapshot version {} is not supported", version),
+            Error::WrongNetwork(magic) => write!(f, "snapshot is for the network with magic {:08x}", magic),
+            Error::UnknownBlock(hash) => write!(f, "snapshot is after block {} which is not in the chain", hash),
+            Error::CoinAfterBase { outpoint, height } => {
+                write!(f, "output {} is created at height {} which is after the snapshot", outpoint, height)
+            }
+            Error::UnsortedCoin(outpoint) => write!(f, "output {} is out of order or repeated in the snapshot", outpoint),
+            Error::Bridge(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<encode::Error> for Error {
+    fn from(err: encode::Error) -> Error {
+        Error::Decode(err)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Decode(encode::Error::Io(err))
+    }
+}
+
+impl From<super::Error> for Error {
+    fn from(err: super::Error) -> Error {
+        Error::Bridge(err)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Metadata is what a snapshot says about itself.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Metadata {
+    pub network_magic: u32,
+
+    /// The snapshot is of the unspent outputs after this block
+    pub base_blockhash: BlockHash,
+
+    pub coins_count: u64,
+}
+
+/// Coin is an unspent output in a snapshot.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Coin {
+    pub outpoint: OutPoint,
+    pub output: TxOut,
+
+    /// Height of the block that created the output
+    pub height: u32,
+
+    pub is_coinbase: bool,
+}
+
+impl Coin {
+    /// leaf_data returns the leaf data of the coin, given the hash of the
+    /// block that created it.
+    pub fn leaf_data(&self, block_hash: BlockHash) -> LeafData {
+        LeafData::new(block_hash.into_inner(), self.outpoint, self.height as i32, self.is_coinbase,
+                      self.output.value as i64, self.output.script_pubkey.to_bytes())
+    }
+}
+
+/// Snapshot reads the coins of a snapshot one by one, in the order they are
+/// in it.
+pub struct Snapshot<R> {
+    reader: R,
+    metadata: Metadata,
+
+    // Coins left to read, in all and in the group of the current txid
+    left: u64,
+    txid: Txid,
+    left_in_tx: u64,
+}
+
+impl<R: Read> Snapshot<R> {
+    /// new reads the metadata of the snapshot.
+    pub fn new(mut reader: R) -> Result<Snapshot<R>> {
+        let mut magic = [0u8; 5];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(Error::NotSnapshot);
+        }
+
+        let version = u16::consensus_decode(&mut reader)?;
+        if version != VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        let metadata = Metadata {
+            network_magic: Decodable::consensus_decode(&mut reader)?,
+            base_blockhash: Decodable::consensus_decode(&mut reader)?,
+            coins_count: Decodable::consensus_decode(&mut reader)?,
+        };
+
+        Ok(Snapshot { reader, left: metadata.coins_count, metadata, txid: Default::default(), left_in_tx: 0 })
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// next_coin returns the next coin, or None after the last one.
+    pub fn next_coin(&mut self) -> Result<Option<Coin>> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+
+        while self.left_in_tx == 0 {
+            self.txid = Decodable::consensus_decode(&mut self.reader)?;
+            self.left_in_tx = VarInt::consensus_decode(&mut self.reader)?.0;
+        }
+
+        let vout = VarInt::consensus_decode(&mut self.reader)?.0;
+        let vout = u32::try_from(vout).map_err(|_| encode::Error::ParseFailed("vout doesn't fit in a u32"))?;
+
+        let code = read_varint(&mut self.reader)?;
+        let height = u32::try_from(code >> 1).map_err(|_| encode::Error::ParseFailed("height doesn't fit in a u32"))?;
+        let value = decompress_amount(read_varint(&mut self.reader)?).filter(|value| *value <= MAX_MONEY)
+            .ok_or(encode::Error::ParseFailed("amount is more than there can be"))?;
+        let script_pubkey = read_script(&mut self.reader)?;
+
+        self.left -= 1;
+        self.left_in_tx -= 1;
+
+        Ok(Some(Coin {
+            outpoint: OutPoint { txid: self.txid, vout },
+            output: TxOut { value, script_pubkey },
+            height,
+            is_coinbase: code & 1 == 1,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for Snapshot<R> {
+    type Item = Result<Coin>;
+
+    fn next(&mut self) -> Option<Result<Coin>> {
+        self.next_coin().transpose()
+    }
+}
+
+/// load returns a bridge for the chain that starts at the block of the
+/// snapshot, with every coin in it. block_hashes are the hashes of the
+/// blocks of the chain by height, from the genesis block to at least the
+/// block of the snapshot; they're needed since the leaf data of a coin has
+/// the hash of the block that created it but snapshots only have the height.
+///
+/// The coins have to be in the order Bitcoin Core writes them, so the roots
+/// only depend on what the unspent outputs are. They're added in batches as
+/// they're read, in that order. Coins that can't be spent aren't added, like
+/// when processing blocks.
+pub fn load(reader: impl Read, network: Network, block_hashes: &[BlockHash]) -> Result<Bridge> {
+    let mut snapshot = Snapshot::new(reader)?;
+
+    let metadata = snapshot.metadata().clone();
+    if metadata.network_magic != network.magic() {
+        return Err(Error::WrongNetwork(metadata.network_magic));
+    }
+
+    let height = block_hashes.iter().position(|hash| *hash == metadata.base_blockhash)
+        .ok_or(Error::UnknownBlock(metadata.base_blockhash))?;
+
+    let mut bridge = Bridge::starting_at(metadata.base_blockhash, height as u32);
+    let mut utxos = Vec::new();
+
+    // The database keys of this coin and the one before it
+    let mut key = Vec::new();
+    let mut last_key = Vec::new();
+
+    while let Some(coin) = snapshot.next_coin()? {
+        key.clear();
+        key.extend(&coin.outpoint.txid[..]);
+        write_varint(coin.outpoint.vout as u64, &mut key);
+        if !last_key.is_empty() && key <= last_key {
+            return Err(Error::UnsortedCoin(coin.outpoint));
+        }
+        std::mem::swap(&mut key, &mut last_key);
+
+        if coin.height as usize > height {
+            return Err(Error::CoinAfterBase { outpoint: coin.outpoint, height: coin.height });
+        }
+        if !coin.output.script_pubkey.is_provably_unspendable() {
+            utxos.push(coin.leaf_data(block_hashes[coin.height as usize]));
+        }
+        if utxos.len() == LOAD_BATCH_SIZE {
+            bridge.add_utxos(std::mem::take(&mut utxos))?;
+        }
+    }
+    bridge.add_utxos(utxos)?;
+
+    Ok(bridge)
+}
+
+// read_varint reads the varints Bitcoin Core uses in its database, which are
+// big endian groups of 7 bits where every group but the last has the high bit
+// set and is one less than what it stands for. This way every number has only
+// one encoding.
+fn read_varint(r: &mut impl Read) -> Result<u64> {
+    let mut n: u64 = 0;
+    loop {
+        let byte = u8::consensus_decode(&mut *r)?;
+        if n > u64::MAX >> 7 {
+            return Err(encode::Error::ParseFailed("varint is too big").into());
+        }
+
+        n = (n << 7) | (byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+        n = n.checked_add(1).ok_or(encode::Error::ParseFailed("varint is too big"))?;
+    }
+}
+
+// write_varint appends n as the varints read_varint reads.
+fn write_varint(mut n: u64, out: &mut Vec<u8>) {
+    let mut bytes = [0u8; 10];
+    let mut len = 0;
+    loop {
+        bytes[len] = (n & 0x7f) as u8 | if len > 0 { 0x80 } else { 0 };
+        len += 1;
+        if n <= 0x7f {
+            break;
+        }
+        n = (n >> 7) - 1;
+    }
+    out.extend(bytes[..len].iter().rev());
+}
+
+// decompress_amount undoes the compression of amounts in Bitcoin Core, which
+// takes out trailing zeros so round amounts are small. It returns None if
+// the amount doesn't fit in a u64.
+fn decompress_amount(x: u64) -> Option<u64> {
+    if x == 0 {
+        return Some(0);
+    }
+
+    let mut x = x - 1;
+    let mut e = x % 10;
+    x /= 10;
+
+    let mut n = if e < 9 {
+        let d = x % 9 + 1;
+        x /= 9;
+        x.checked_mul(10)?.checked_add(d)?
+    } else {
+        x + 1
+    };
+    while e > 0 {
+        n = n.checked_mul(10)?;
+        e -= 1;
+    }
+    Some(n)
+}
+
+// read_script reads a compressed script. The common kinds of scripts are
+// stored as their kind and the hash or the key in them, and the rest as the
+// script with its size plus the number of kinds.
+fn read_script(r: &mut impl Read) -> Result<Script> {
+    let size = read_varint(r)?;
+
+    let script = match size {
+        0 | 1 => {
+            let mut hash = [0u8; 20];
+            r.read_exact(&mut hash)?;
+            if size == 0 {
+                Builder::new()
+                    .push_opcode(opcodes::all::OP_DUP)
+                    .push_opcode(opcodes::all::OP_HASH160)
+                    .push_slice(&hash)
+                    .push_opcode(opcodes::all::OP_EQUALVERIFY)
+                    .push_opcode(opcodes::all::OP_CHECKSIG)
+                    .into_script()
+            } else {
+                Builder::new()
+                    .push_opcode(opcodes::all::OP_HASH160)
+                    .push_slice(&hash)
+                    .push_opcode(opcodes::all::OP_EQUAL)
+                    .into_script()
+            }
+        }
+        2..=5 => {
+            let mut key = [0u8; 33];
+            key[0] = size as u8;
+            r.read_exact(&mut key[1..])?;
+
+            // Uncompressed keys are stored compressed
+            let key = if size < 4 {
+                key.to_vec()
+            } else {
+                key[0] -= 2;
+                let key = PublicKey::from_slice(&key).map_err(|_| encode::Error::ParseFailed("invalid public key"))?;
+                key.serialize_uncompressed().to_vec()
+            };
+
+            Builder::new().push_slice(&key).push_opcode(opcodes::all::OP_CHECKSIG).into_script()
+        }
+        size if size - 6 > MAX_SCRIPT_SIZE => {
+            // The script is skipped and one that can't be spent stands in for
+            // it, like in Bitcoin Core
+            io::copy(&mut r.take(size - 6), &mut io::sink())?;
+            Builder::new().push_opcode(opcodes::all::OP_RETURN).into_script()
+        }
+        size => {
+            let mut script = vec![0u8; (size - 6) as usize];
+            r.read_exact(&mut script)?;
+            Script::from(script)
+        }
+    };
+
+    Ok(script)
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::blockdata::opcodes;
+    use bitcoin::blockdata::script::Builder;
+    use bitcoin::consensus::encode::{self, Encodable, VarInt};
+    use bitcoin::hashes::{hash160, Hash};
+    use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+    use bitcoin::{BitcoinHash, Block, BlockHash, Network, OutPoint, Script, TxOut};
+
+    use super::super::{tests::chain, Bridge};
+    use super::{decompress_amount, load, read_script, read_varint, write_varint, Coin, Error, Snapshot, MAGIC, VERSION};
+
+    fn compress_amount(mut n: u64) -> u64 {
+        if n == 0 {
+            return 0;
+        }
+
+        let mut e = 0;
+        while n % 10 == 0 && e < 9 {
+            n /= 10;
+            e += 1;
+        }
+        if e < 9 {
+            let d = n % 10;
+            n /= 10;
+            1 + (n * 9 + d - 1) * 10 + e
+        } else {
+            1 + (n - 1) * 10 + 9
+        }
+    }
+
+    // snapshot writes the coins as Bitcoin Core would, grouping the ones
+    // that are next to each other and have the same txid. Scripts are
+    // written as they are, and the coins in the order they're given.
+    fn snapshot(network: Network, base: BlockHash, coins: &[Coin]) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        VERSION.consensus_encode(&mut out).unwrap();
+        network.magic().consensus_encode(&mut out).unwrap();
+        base.consensus_encode(&mut out).unwrap();
+        (coins.len() as u64).consensus_encode(&mut out).unwrap();
+
+        let mut rest = coins;
+        while !rest.is_empty() {
+            let len = rest.iter().take_while(|coin| coin.outpoint.txid == rest[0].outpoint.txid).count();
+            let (group, next) = rest.split_at(len);
+            rest = next;
+
+            group[0].outpoint.txid.consensus_encode(&mut out).unwrap();
+            VarInt(group.len() as u64).consensus_encode(&mut out).unwrap();
+            for coin in group {
+                VarInt(coin.outpoint.vout as u64).consensus_encode(&mut out).unwrap();
+                write_varint((coin.height as u64) << 1 | coin.is_coinbase as u64, &mut out);
+                write_varint(compress_amount(coin.output.value), &mut out);
+                write_varint(coin.output.script_pubkey.len() as u64 + 6, &mut out);
+                out.extend(coin.output.script_pubkey.as_bytes());
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn test_encodings() {
+        for n in &[0, 1, 127, 128, 255, 16511, 16512, 1 << 40, u64::MAX] {
+            let mut out = Vec::new();
+            write_varint(*n, &mut out);
+            assert_eq!(read_varint(&mut out.as_slice()).unwrap(), *n);
+        }
+        assert_eq!(read_varint(&mut [0x80, 0x00].as_ref()).unwrap(), 128);
+        assert!(read_varint(&mut [0xff; 11].as_ref()).is_err());
+
+        for n in &[0, 1, 10, 546, 5000, 5_000_000_000, 2_100_000_000_000_000, 1_000_000_000_000] {
+            assert_eq!(decompress_amount(compress_amount(*n)), Some(*n));
+        }
+        assert_eq!(decompress_amount(u64::MAX), None);
+        assert_eq!(compress_amount(5_000_000_000), 0x32);
+
+        // The special kinds of scripts
+        let hash = hash160::Hash::hash(&[1]);
+        let mut data = vec![0];
+        data.extend(&hash[..]);
+        let p2pkh = read_script(&mut data.as_slice()).unwrap();
+        assert!(p2pkh.is_p2pkh() && p2pkh[3..23] == hash[..]);
+        data[0] = 1;
+        let p2sh = read_script(&mut data.as_slice()).unwrap();
+        assert!(p2sh.is_p2sh() && p2sh[2..22] == hash[..]);
+
+        let key = PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[7; 32]).unwrap());
+        let compressed = key.serialize();
+        let p2pk = |key: &[u8]| Builder::new().push_slice(key).push_opcode(opcodes::all::OP_CHECKSIG).into_script();
+        assert_eq!(read_script(&mut compressed.as_ref()).unwrap(), p2pk(&compressed));
+
+        let mut data = compressed.to_vec();
+        data[0] += 2;
+        assert_eq!(read_script(&mut data.as_slice()).unwrap(), p2pk(&key.serialize_uncompressed()));
+    }
+
+    // coins returns the unspent outputs of the bridge that were created in
+    // the blocks, sorted by outpoint.
+    fn coins(blocks: &[Block], bridge: &Bridge) -> Vec<Coin> {
+        let mut coins = Vec::new();
+        for (height, block) in blocks.iter().enumerate() {
+            for tx in &block.txdata {
+                for (vout, output) in tx.output.iter().enumerate() {
+                    let outpoint = OutPoint { txid: tx.txid(), vout: vout as u32 };
+                    if bridge.leaf_hash(&outpoint).is_ok() {
+                        coins.push(Coin { outpoint, output: output.clone(), height: height as u32, is_coinbase: tx.is_coin_base() });
+                    }
+                }
+            }
+        }
+        sort(&mut coins);
+        coins
+    }
+
+    // sort sorts the coins the way Bitcoin Core writes them.
+    fn sort(coins: &mut [Coin]) {
+        coins.sort_by_key(|coin| {
+            let mut key = coin.outpoint.txid.to_vec();
+            write_varint(coin.outpoint.vout as u64, &mut key);
+            key
+        });
+    }
+
+    #[test]
+    fn test_load() {
+        let blocks = chain();
+        let hashes: Vec<BlockHash> = blocks.iter().map(|block| block.bitcoin_hash()).collect();
+
+        let mut bridge = Bridge::new();
+        bridge.process_block(&blocks[0]).unwrap();
+        bridge.process_block(&blocks[1]).unwrap();
+        let first = coins(&blocks, &bridge);
+        bridge.process_block(&blocks[2]).unwrap();
+
+        let coins = coins(&blocks, &bridge);
+        assert_eq!(coins.len(), 4);
+
+        let data = snapshot(Network::Regtest, hashes[2], &coins);
+        let read: Vec<Coin> = Snapshot::new(data.as_slice()).unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(read, coins);
+
+        // An output that can't be spent isn't added
+        let mut unspendable = coins.clone();
+        unspendable.push(Coin {
+            outpoint: OutPoint { txid: coins[0].outpoint.txid, vout: 9 },
+            output: TxOut { value: 0, script_pubkey: Builder::new().push_opcode(opcodes::all::OP_RETURN).into_script() },
+            height: 2,
+            is_coinbase: false,
+        });
+        sort(&mut unspendable);
+
+        let from_snapshot = load(data.as_slice(), Network::Regtest, &hashes).unwrap();
+        let unspendable = load(snapshot(Network::Regtest, hashes[2], &unspendable).as_slice(), Network::Regtest, &hashes).unwrap();
+        assert_eq!(from_snapshot.height(), Some(2));
+        assert_eq!(from_snapshot.roots(2).unwrap(), unspendable.roots(2).unwrap());
+        assert_eq!(from_snapshot.roots(2).unwrap().num_leaves, 4);
+        assert!(from_snapshot.roots(1).is_err());
+
+        // Every output can be proven against the roots at the snapshot
+        let outpoints: Vec<OutPoint> = coins.iter().map(|coin| coin.outpoint).collect();
+        let udata = from_snapshot.prove(&outpoints).unwrap();
+        let roots = from_snapshot.roots(2).unwrap();
+        let leaf_hashes: Vec<_> = udata.leaf_data.iter().map(|data| data.leaf_hash()).collect();
+        assert!(udata.proof.verify(&leaf_hashes, &roots.roots, roots.num_leaves));
+        for outpoint in &outpoints {
+            assert_eq!(from_snapshot.leaf_hash(outpoint).unwrap(), bridge.leaf_hash(outpoint).unwrap());
+        }
+
+        // A snapshot after the first block can keep going with the second
+        let mut bridge = load(snapshot(Network::Regtest, hashes[1], &first).as_slice(), Network::Regtest, &hashes).unwrap();
+        bridge.process_block(&blocks[2]).unwrap();
+        assert_eq!(bridge.height(), Some(2));
+        assert_eq!(bridge.roots(2).unwrap().num_leaves, 4);
+        assert!(bridge.block_udata(&hashes[2]).is_ok());
+
+        // Coins that aren't sorted or are there twice are refused
+        let mut reversed = coins.clone();
+        reversed.reverse();
+        let data = snapshot(Network::Regtest, hashes[2], &reversed);
+        assert!(matches!(load(data.as_slice(), Network::Regtest, &hashes), Err(Error::UnsortedCoin(outpoint)) if outpoint == coins[2].outpoint));
+
+        let mut repeated = coins.clone();
+        repeated.insert(1, coins[1].clone());
+        let data = snapshot(Network::Regtest, hashes[2], &repeated);
+        assert!(matches!(load(data.as_slice(), Network::Regtest, &hashes), Err(Error::UnsortedCoin(outpoint)) if outpoint == coins[1].outpoint));
+    }
+
+    #[test]
+    fn test_load_order() {
+        let hashes: Vec<BlockHash> = chain().iter().map(|block| block.bitcoin_hash()).collect();
+        let coin = |vout: u32| Coin {
+            outpoint: OutPoint { txid: Default::default(), vout },
+            output: TxOut { value: 1, script_pubkey: Builder::new().push_int(vout as i64).into_script() },
+            height: 1,
+            is_coinbase: false,
+        };
+
+        // The vouts are in the order of their varints, which isn't the order
+        // of the numbers once they take three bytes
+        let mut coins: Vec<Coin> = [0, 255, 256, 16511, 16512, 16513, 1 << 20].iter().map(|vout| coin(*vout)).collect();
+        sort(&mut coins);
+        let vouts: Vec<u32> = coins.iter().map(|coin| coin.outpoint.vout).collect();
+        assert_eq!(vouts, [0, 255, 16512, 16513, 256, 1 << 20, 16511]);
+
+        let bridge = load(snapshot(Network::Regtest, hashes[1], &coins).as_slice(), Network::Regtest, &hashes).unwrap();
+        assert_eq!(bridge.num_utxos(), 7);
+
+        coins.sort_by_key(|coin| coin.outpoint.vout);
+        let data = snapshot(Network::Regtest, hashes[1], &coins);
+        assert!(matches!(load(data.as_slice(), Network::Regtest, &hashes), Err(Error::UnsortedCoin(outpoint)) if outpoint.vout == 16512));
+    }
+
+    #[test]
+    fn test_bad_snapshots() {
+        let hashes: Vec<BlockHash> = chain().iter().map(|block| block.bitcoin_hash()).collect();
+        let coin = Coin {
+            outpoint: OutPoint::default(),
+            output: TxOut { value: 1, script_pubkey: Script::new() },
+            height: 2,
+            is_coinbase: false,
+        };
+
+        let data = snapshot(Network::Regtest, hashes[1], std::slice::from_ref(&coin));
+        assert!(matches!(load(data.as_slice(), Network::Bitcoin, &hashes), Err(Error::WrongNetwork(_))));
+        assert!(matches!(load(data.as_slice(), Network::Regtest, &hashes[..1]), Err(Error::UnknownBlock(_))));
+        assert!(matches!(load(data.as_slice(), Network::Regtest, &hashes), Err(Error::CoinAfterBase { height: 2, .. })));
+        assert!(matches!(load(&data[..data.len() - 1], Network::Regtest, &hashes), Err(Error::Decode(_))));
+
+        // Amounts that overflow or are more than there can be
+        let mut big = coin.clone();
+        big.output.value = super::MAX_MONEY + 1;
+        let data = snapshot(Network::Regtest, hashes[2], &[big]);
+        let amount_err = |data: &[u8]| matches!(load(data, Network::Regtest, &hashes),
+            Err(Error::Decode(encode::Error::ParseFailed("amount is more than there can be"))));
+        assert!(amount_err(&data));
+        let mut data = snapshot(Network::Regtest, hashes[2], std::slice::from_ref(&coin));
+        let mut amount = Vec::new();
+        write_varint(u64::MAX, &mut amount);
+        let end = data.len() - 1;
+        data.splice(end - 1..end, amount);
+        assert!(amount_err(&data));
+
+        let mut data = snapshot(Network::Regtest, hashes[2], &[coin]);
+        data[5] = 1;
+        assert!(matches!(Snapshot::new(data.as_slice()), Err(Error::UnsupportedVersion(1))));
+        data[0] = b'x';
+        assert!(matches!(Snapshot::new(data.as_slice()), Err(Error::NotSnapshot)));
+    }
+}
@@ -0,0 +1,424 @@
+// Rustreexo
+
+use std::io::{self, Cursor};
+use std::mem;
+
+use bitcoin::consensus::encode::{self, CheckedData, Decodable, Encodable, VarInt, MAX_VEC_SIZE};
+use bitcoin::consensus::serialize;
+use bitcoin::network::message::CommandString;
+use bitcoin::{Block, BlockHash, Transaction};
+use bitcoin_hashes::{sha256, Hash};
+
+use crate::accumulator::{proof::BatchProof, types::LeafData};
+
+/// UData is the utreexo data that goes with a block or a transaction: the
+/// proof for the outputs it spends and their leaf data, in the same order
+/// as the targets of the proof.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UData {
+    pub proof: BatchProof,
+    pub leaf_data: Vec<LeafData>,
+}
+
+/// BlockWithProof is a block along with the proof for everything it spends.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockWithProof {
+    pub block: Block,
+    pub udata: UData,
+}
+
+/// TxWithProof is a transaction along with the proof for what it spends.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TxWithProof {
+    pub tx: Transaction,
+    pub udata: UData,
+}
+
+/// GetUtreexoProof asks for the proof of what the block with the given hash
+/// spends. Only the leaves at the given indexes into the spent outputs of
+/// the block are asked for, or all of them if there are none.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GetUtreexoProof {
+    pub block_hash: BlockHash,
+    pub leaf_indexes: Vec<u32>,
+}
+
+/// UtreexoProof is the answer to GetUtreexoProof.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UtreexoProof {
+    pub block_hash: BlockHash,
+    pub udata: UData,
+}
+
+/// GetUtreexoRoot asks for the roots of the accumulator after the block with
+/// the given hash.
+#[derive(Clone, Debug, PartialEq)]
+pub struct GetUtreexoRoot {
+    pub block_hash: BlockHash,
+}
+
+/// UtreexoRoot is the state of the accumulator after the block with the
+/// given hash. The roots are biggest tree first.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UtreexoRoot {
+    pub block_hash: BlockHash,
+    pub num_leaves: u64,
+    pub roots: Vec<sha256::Hash>,
+}
+
+/// UtreexoSummary is what the block with the given hash does to the
+/// accumulator: how many leaves it adds and the positions it deletes. With
+/// it and the proof hashes a peer can follow the accumulator without the
+/// leaf data.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UtreexoSummary {
+    pub block_hash: BlockHash,
+    pub num_adds: u64,
+    pub targets: Vec<u64>,
+}
+
+/// UtreexoMessage is the payload of a utreexo message.
+#[derive(Clone, Debug, PartialEq)]
+pub enum UtreexoMessage {
+    BlockWithProof(BlockWithProof),
+    TxWithProof(TxWithProof),
+    GetUtreexoProof(GetUtreexoProof),
+    UtreexoProof(UtreexoProof),
+    GetUtreexoRoot(GetUtreexoRoot),
+    UtreexoRoot(UtreexoRoot),
+    UtreexoSummary(UtreexoSummary),
+}
+
+impl UtreexoMessage {
+    /// cmd returns the command of the message, which has to fit in the 12
+    /// bytes of the message header.
+    pub fn cmd(&self) -> &'static str {
+        match self {
+            UtreexoMessage::BlockWithProof(_) => "ublock",
+            UtreexoMessage::TxWithProof(_) => "utreexotx",
+            UtreexoMessage::GetUtreexoProof(_) => "getuproof",
+            UtreexoMessage::UtreexoProof(_) => "uproof",
+            UtreexoMessage::GetUtreexoRoot(_) => "geturoot",
+            UtreexoMessage::UtreexoRoot(_) => "uroot",
+            UtreexoMessage::UtreexoSummary(_) => "usummary",
+        }
+    }
+
+    /// payload returns the encoded message without the header.
+    pub fn payload(&self) -> Vec<u8> {
+        match self {
+            UtreexoMessage::BlockWithProof(msg) => serialize(msg),
+            UtreexoMessage::TxWithProof(msg) => serialize(msg),
+            UtreexoMessage::GetUtreexoProof(msg) => serialize(msg),
+            UtreexoMessage::UtreexoProof(msg) => serialize(msg),
+            UtreexoMessage::GetUtreexoRoot(msg) => serialize(msg),
+            UtreexoMessage::UtreexoRoot(msg) => serialize(msg),
+            UtreexoMessage::UtreexoSummary(msg) => serialize(msg),
+        }
+    }
+
+    /// from_payload decodes the payload of a message with the given command.
+    /// Like deserialize, it errors if anything is left after the message.
+    pub fn from_payload(cmd: &str, payload: &[u8]) -> Result<UtreexoMessage, encode::Error> {
+        let mut r = Cursor::new(payload);
+        let msg = match cmd {
+            "ublock" => UtreexoMessage::BlockWithProof(Decodable::consensus_decode(&mut r)?),
+            "utreexotx" => UtreexoMessage::TxWithProof(Decodable::consensus_decode(&mut r)?),
+            "getuproof" => UtreexoMessage::GetUtreexoProof(Decodable::consensus_decode(&mut r)?),
+            "uproof" => UtreexoMessage::UtreexoProof(Decodable::consensus_decode(&mut r)?),
+            "geturoot" => UtreexoMessage::GetUtreexoRoot(Decodable::consensus_decode(&mut r)?),
+            "uroot" => UtreexoMessage::UtreexoRoot(Decodable::consensus_decode(&mut r)?),
+            "usummary" => UtreexoMessage::UtreexoSummary(Decodable::consensus_decode(&mut r)?),
+            _ => return Err(encode::Error::UnrecognizedNetworkCommand(cmd.to_string())),
+        };
+
+        if r.position() != payload.len() as u64 {
+            return Err(encode::Error::ParseFailed("data not consumed entirely when explicitly deserializing"));
+        }
+
+        Ok(msg)
+    }
+}
+
+/// RawUtreexoMessage is a utreexo message with the same header as the
+/// messages in bitcoin::network::message: the network magic, the command
+/// and the checksummed payload.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RawUtreexoMessage {
+    pub magic: u32,
+    pub payload: UtreexoMessage,
+}
+
+impl Encodable for RawUtreexoMessage {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let mut len = 0;
+        len += self.magic.consensus_encode(&mut w)?;
+        len += CommandString::from(self.payload.cmd()).consensus_encode(&mut w)?;
+        len += CheckedData(self.payload.payload()).consensus_encode(&mut w)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for RawUtreexoMessage {
+    fn consensus_decode<R: io::Read>(mut r: R) -> Result<RawUtreexoMessage, encode::Error> {
+        let magic = Decodable::consensus_decode(&mut r)?;
+        let cmd = CommandString::consensus_decode(&mut r)?;
+        let payload = CheckedData::consensus_decode(&mut r)?.0;
+
+        Ok(RawUtreexoMessage { magic, payload: UtreexoMessage::from_payload(cmd.as_ref(), &payload)? })
+    }
+}
+
+// The targets are VarInts and the proof hashes are written as they are,
+// both after their count.
+impl Encodable for BatchProof {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let mut len = VarInt(self.targets.len() as u64).consensus_encode(&mut w)?;
+        for target in &self.targets {
+            len += VarInt(*target).consensus_encode(&mut w)?;
+        }
+
+        len += encode_hashes(&self.proof, &mut w)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for BatchProof {
+    fn consensus_decode<R: io::Read>(mut r: R) -> Result<BatchProof, encode::Error> {
+        let targets = decode_vec::<VarInt, _>(&mut r)?.into_iter().map(|target| target.0).collect();
+        let proof = decode_hashes(&mut r)?;
+
+        Ok(BatchProof { targets, proof })
+    }
+}
+
+impl Encodable for UData {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        Ok(self.proof.consensus_encode(&mut w)? + encode_vec(&self.leaf_data, &mut w)?)
+    }
+}
+
+impl Decodable for UData {
+    fn consensus_decode<R: io::Read>(mut r: R) -> Result<UData, encode::Error> {
+        Ok(UData { proof: Decodable::consensus_decode(&mut r)?, leaf_data: decode_vec(&mut r)? })
+    }
+}
+
+// impl_message implements Encodable and Decodable for a message as its
+// fields one after another.
+macro_rules! impl_message {
+    ($message:ident, $($field:ident),+) => {
+        impl Encodable for $message {
+            fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+                let mut len = 0;
+                $(len += self.$field.consensus_encode(&mut w)?;)+
+                Ok(len)
+            }
+        }
+
+        impl Decodable for $message {
+            fn consensus_decode<R: io::Read>(mut r: R) -> Result<$message, encode::Error> {
+                Ok($message { $($field: Decodable::consensus_decode(&mut r)?),+ })
+            }
+        }
+    }
+}
+
+impl_message!(BlockWithProof, block, udata);
+impl_message!(TxWithProof, tx, udata);
+impl_message!(UtreexoProof, block_hash, udata);
+impl_message!(GetUtreexoRoot, block_hash);
+
+impl Encodable for GetUtreexoProof {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        Ok(self.block_hash.consensus_encode(&mut w)? + encode_vec(&self.leaf_indexes, &mut w)?)
+    }
+}
+
+impl Decodable for GetUtreexoProof {
+    fn consensus_decode<R: io::Read>(mut r: R) -> Result<GetUtreexoProof, encode::Error> {
+        Ok(GetUtreexoProof { block_hash: Decodable::consensus_decode(&mut r)?, leaf_indexes: decode_vec(&mut r)? })
+    }
+}
+
+impl Encodable for UtreexoRoot {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let mut len = self.block_hash.consensus_encode(&mut w)?;
+        len += self.num_leaves.consensus_encode(&mut w)?;
+        len += encode_hashes(&self.roots, &mut w)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for UtreexoRoot {
+    fn consensus_decode<R: io::Read>(mut r: R) -> Result<UtreexoRoot, encode::Error> {
+        let block_hash = Decodable::consensus_decode(&mut r)?;
+        let num_leaves = Decodable::consensus_decode(&mut r)?;
+        let roots = decode_hashes(&mut r)?;
+
+        Ok(UtreexoRoot { block_hash, num_leaves, roots })
+    }
+}
+
+impl Encodable for UtreexoSummary {
+    fn consensus_encode<W: io::Write>(&self, mut w: W) -> Result<usize, encode::Error> {
+        let mut len = self.block_hash.consensus_encode(&mut w)?;
+        len += VarInt(self.num_adds).consensus_encode(&mut w)?;
+        len += VarInt(self.targets.len() as u64).consensus_encode(&mut w)?;
+        for target in &self.targets {
+            len += VarInt(*target).consensus_encode(&mut w)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for UtreexoSummary {
+    fn consensus_decode<R: io::Read>(mut r: R) -> Result<UtreexoSummary, encode::Error> {
+        let block_hash = Decodable::consensus_decode(&mut r)?;
+        let num_adds = VarInt::consensus_decode(&mut r)?.0;
+        let targets = decode_vec::<VarInt, _>(&mut r)?.into_iter().map(|target| target.0).collect();
+
+        Ok(UtreexoSummary { block_hash, num_adds, targets })
+    }
+}
+
+// encode_vec writes the number of items as a VarInt and then the items.
+pub(crate) fn encode_vec<T: Encodable, W: io::Write>(items: &[T], mut w: W) -> Result<usize, encode::Error> {
+    let mut len = VarInt(items.len() as u64).consensus_encode(&mut w)?;
+    for item in items {
+        len += item.consensus_encode(&mut w)?;
+    }
+    Ok(len)
+}
+
+// decode_vec reads what encode_vec writes. The count comes from the peer so
+// it's checked against MAX_VEC_SIZE before anything is allocated for it.
+pub(crate) fn decode_vec<T: Decodable, R: io::Read>(mut r: R) -> Result<Vec<T>, encode::Error> {
+    let count = VarInt::consensus_decode(&mut r)?.0;
+    let byte_size = (count as usize).checked_mul(mem::size_of::<T>())
+        .ok_or(encode::Error::ParseFailed("Invalid length"))?;
+    if byte_size > MAX_VEC_SIZE {
+        return Err(encode::Error::OversizedVectorAllocation { requested: byte_size, max: MAX_VEC_SIZE });
+    }
+
+    (0..count).map(|_| T::consensus_decode(&mut r)).collect()
+}
+
+fn encode_hashes<W: io::Write>(hashes: &[sha256::Hash], mut w: W) -> Result<usize, encode::Error> {
+    let mut len = VarInt(hashes.len() as u64).consensus_encode(&mut w)?;
+    for hash in hashes {
+        len += hash.into_inner().consensus_encode(&mut w)?;
+    }
+    Ok(len)
+}
+
+fn decode_hashes<R: io::Read>(r: R) -> Result<Vec<sha256::Hash>, encode::Error> {
+    Ok(decode_vec::<[u8; 32], _>(r)?.into_iter().map(sha256::Hash::from_inner).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::consensus::encode::{self, deserialize, serialize, VarInt};
+    use bitcoin::hashes::hex::FromHex;
+    use bitcoin::{BitcoinHash, Network, OutPoint, Txid};
+    use bitcoin_hashes::{sha256, Hash};
+
+    use super::{
+        BatchProof, BlockWithProof, GetUtreexoProof, GetUtreexoRoot, LeafData, RawUtreexoMessage, TxWithProof,
+        UData, UtreexoMessage, UtreexoProof, UtreexoRoot, UtreexoSummary,
+    };
+
+    fn udata() -> UData {
+        let txid = "4a5e1e4baab89f3a32518a88c31bc87f618f76673e2cc77ab2127b7afdeda33b";
+        let leaf = LeafData::new([7; 32], OutPoint { txid: Txid::from_hex(txid).unwrap(), vout: 3 },
+                                 100, true, 5000, vec![0x51]);
+
+        UData {
+            proof: BatchProof { targets: vec![3, 300, 70000], proof: vec![sha256::Hash::hash(b"proof")] },
+            leaf_data: vec![leaf.clone(), leaf],
+        }
+    }
+
+    #[test]
+    fn test_leaf_data() {
+        let leaf = &udata().leaf_data[0];
+        let encoded = serialize(leaf);
+
+        // 32 for the block hash, 36 for the outpoint, 4 + 8 for the rest and
+        // the script with its length
+        assert_eq!(encoded.len(), 32 + 36 + 4 + 8 + 2);
+        assert_eq!(&encoded[68..72], &(100i32 << 1 | 1).to_le_bytes());
+        assert_eq!(&deserialize::<LeafData>(&encoded).unwrap(), leaf);
+        assert_eq!(leaf.leaf_hash(), sha256::Hash::hash(&encoded));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let block = genesis_block(Network::Bitcoin);
+        let block_hash = block.bitcoin_hash();
+
+        let messages = vec![
+            UtreexoMessage::BlockWithProof(BlockWithProof { block: block.clone(), udata: udata() }),
+            UtreexoMessage::TxWithProof(TxWithProof { tx: block.txdata[0].clone(), udata: udata() }),
+            UtreexoMessage::GetUtreexoProof(GetUtreexoProof { block_hash, leaf_indexes: vec![0, 2] }),
+            UtreexoMessage::UtreexoProof(UtreexoProof { block_hash, udata: UData::default() }),
+            UtreexoMessage::GetUtreexoRoot(GetUtreexoRoot { block_hash }),
+            UtreexoMessage::UtreexoRoot(UtreexoRoot {
+                block_hash,
+                num_leaves: 5,
+                roots: vec![sha256::Hash::hash(b"a"), sha256::Hash::hash(b"b")],
+            }),
+            UtreexoMessage::UtreexoSummary(UtreexoSummary { block_hash, num_adds: 2, targets: vec![1, 4] }),
+        ];
+
+        for msg in messages {
+            let payload = msg.payload();
+            assert_eq!(UtreexoMessage::from_payload(msg.cmd(), &payload).unwrap(), msg);
+
+            let raw = RawUtreexoMessage { magic: Network::Bitcoin.magic(), payload: msg };
+            let encoded = serialize(&raw);
+            assert_eq!(&encoded[4..4 + raw.payload.cmd().len()], raw.payload.cmd().as_bytes());
+            assert_eq!(deserialize::<RawUtreexoMessage>(&encoded).unwrap(), raw);
+        }
+    }
+
+    #[test]
+    fn test_proof_encoding() {
+        let proof = udata().proof;
+        let encoded = serialize(&proof);
+
+        // The targets are VarInts
+        let mut expected = serialize(&VarInt(3));
+        for target in &proof.targets {
+            expected.extend(serialize(&VarInt(*target)));
+        }
+        expected.extend(serialize(&VarInt(1)));
+        expected.extend(&proof.proof[0][..]);
+        assert_eq!(encoded, expected);
+    }
+
+    #[test]
+    fn test_bad_messages() {
+        let msg = UtreexoMessage::GetUtreexoRoot(GetUtreexoRoot { block_hash: Default::default() });
+        let mut encoded = serialize(&RawUtreexoMessage { magic: 0, payload: msg.clone() });
+
+        // The checksum doesn't match anymore
+        *encoded.last_mut().unwrap() ^= 1;
+        assert!(matches!(deserialize::<RawUtreexoMessage>(&encoded), Err(encode::Error::InvalidChecksum { .. })));
+
+        assert!(matches!(UtreexoMessage::from_payload("block", &msg.payload()),
+                         Err(encode::Error::UnrecognizedNetworkCommand(_))));
+
+        // Nothing can come after the message
+        let mut payload = msg.payload();
+        payload.push(0);
+        assert!(matches!(UtreexoMessage::from_payload("geturoot", &payload), Err(encode::Error::ParseFailed(_))));
+
+        // A count that's too big is rejected before allocating for it
+        let mut payload = serialize(&VarInt(u32::MAX as u64));
+        payload.extend([0; 32].iter());
+        assert!(matches!(UtreexoMessage::from_payload("uroot", &[&[0; 40][..], &payload].concat()),
+                         Err(encode::Error::OversizedVectorAllocation { .. })));
+    }
+}
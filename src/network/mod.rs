@@ -0,0 +1,5 @@
+// Rustreexo
+
+//! Messages of the utreexo peer protocol.
+
+pub mod message;